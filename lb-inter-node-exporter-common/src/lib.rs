@@ -7,6 +7,7 @@ pub struct Ipv4Event {
     pub dst_addr: u32,
     pub src_port: u16,
     pub dst_port: u16,
+    pub ifindex: u32,
 }
 
 impl From<&[u8]> for Ipv4Event {
@@ -17,11 +18,13 @@ impl From<&[u8]> for Ipv4Event {
             ((v[7] as u32) << 24) + ((v[6] as u32) << 16) + ((v[5] as u32) << 8) + (v[4] as u32); // network byte order
         let c = ((v[8] as u16) << 8) + (v[9] as u16);
         let d = ((v[10] as u16) << 8) + (v[11] as u16);
+        let e = u32::from_ne_bytes([v[12], v[13], v[14], v[15]]); // host byte order
         Self {
             src_addr: a,
             dst_addr: b,
             src_port: c,
             dst_port: d,
+            ifindex: e,
         }
     }
 }
@@ -4,9 +4,12 @@
 use core::mem;
 
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{xdp_action, BPF_F_NO_PREALLOC},
     macros::{map, xdp},
-    maps::{HashMap, RingBuf},
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        HashMap, PerCpuArray, RingBuf,
+    },
     programs::XdpContext,
 };
 use aya_log_ebpf::info;
@@ -21,10 +24,17 @@ use network_types::{
 static IPV4VIP: HashMap<u32, u32> = HashMap::with_max_entries(1024, 0);
 #[map]
 static IPV6VIP: HashMap<u128, u32> = HashMap::with_max_entries(1024, 0);
+// VIP ranges like address pools of load balancers, keyed by addresses in network byte order
+#[map]
+static IPV4VIP_RANGE: LpmTrie<u32, u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
 #[map]
 static IPV4EVENT: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
 #[map]
 static IPV6EVENT: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
+// The count of events dropped because the ring buffer was full
+#[map]
+static EVENT_DROPS: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
 
 #[inline(always)]
 unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
@@ -52,18 +62,29 @@ fn is_ipv4vip(addr: u32) -> bool {
     unsafe { IPV4VIP.get(&addr).is_some() }
 }
 
+fn is_ipv4vip_range(addr_be: u32) -> bool {
+    IPV4VIP_RANGE.get(&Key::new(32, addr_be)).is_some()
+}
+
 fn is_ipv6vip(addr: u128) -> bool {
     unsafe { IPV6VIP.get(&addr).is_some() }
 }
 
+fn count_drop() {
+    if let Some(drops) = EVENT_DROPS.get_ptr_mut(0) {
+        unsafe { *drops += 1 };
+    }
+}
+
 fn try_lb_inter_node_exporter(ctx: XdpContext) -> Result<u32, ()> {
     // info!(&ctx, "received a packet");
     let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
     match unsafe { (*ethhdr).ether_type } {
         EtherType::Ipv4 => {
             let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-            let dst_addr = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
-            if !is_ipv4vip(dst_addr) {
+            let dst_addr_be = unsafe { (*ipv4hdr).dst_addr };
+            let dst_addr = u32::from_be(dst_addr_be);
+            if !is_ipv4vip(dst_addr) && !is_ipv4vip_range(dst_addr_be) {
                 return Ok(xdp_action::XDP_PASS);
             }
 
@@ -80,15 +101,20 @@ fn try_lb_inter_node_exporter(ctx: XdpContext) -> Result<u32, ()> {
             let src_port = unsafe { (*tcphdr).source };
             let dst_port = unsafe { (*tcphdr).dest };
             let src_addr = unsafe { (*ipv4hdr).src_addr };
+            let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
             let mut entry = match IPV4EVENT.reserve::<Ipv4Event>(0) {
                 Some(entry) => entry,
-                None => return Ok(xdp_action::XDP_PASS),
+                None => {
+                    count_drop();
+                    return Ok(xdp_action::XDP_PASS);
+                }
             };
             let event = Ipv4Event {
                 src_addr,
                 dst_addr,
                 src_port,
                 dst_port,
+                ifindex,
             };
             entry.write(event);
             entry.submit(0);
@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use actix_web::{
+    get,
+    web::{Bytes, Data, Payload, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use actix_ws::Message;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    event::event_drops,
+    feed::{ConnectionEvent, EventFilter, FilterExpr},
+    trace::CounterSample,
+    vip::ServicePortRef,
+    State,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachedInterface {
+    pub name: String,
+    pub index: u32,
+    pub mode: &'static str,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Vip {
+    pub addr: IpAddr,
+    // whether the VIP is in the eBPF map
+    pub in_map: bool,
+    pub ports: Vec<ServicePort>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServicePort {
+    pub namespace: String,
+    pub name: String,
+    pub port_name: Option<String>,
+    pub port: u16,
+    pub source: String,
+    pub traffic_policy: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapEntry {
+    pub key: String,
+    pub value: u64,
+}
+
+impl ServicePort {
+    fn new(port: u16, svc: ServicePortRef) -> Self {
+        ServicePort {
+            namespace: svc.service.namespace,
+            name: svc.service.name,
+            port_name: svc.port_name,
+            port,
+            source: svc.source.as_str().to_string(),
+            traffic_policy: svc.policy.as_str().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Limit {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CounterQuery {
+    filter: Option<String>,
+}
+
+/// List VIPs in the IPV4VIP map joined with Services tracked for them.
+#[get("/api/v1/vips")]
+pub async fn list_vips(c: Data<State>) -> impl Responder {
+    let mut vips: BTreeMap<IpAddr, Vip> = BTreeMap::new();
+    for (addr, ports) in c.vips.read().entries() {
+        vips.insert(
+            addr,
+            Vip {
+                addr,
+                in_map: false,
+                ports: ports
+                    .into_iter()
+                    .map(|(port, svc)| ServicePort::new(port, svc))
+                    .collect(),
+            },
+        );
+    }
+    if let Some(ipv4_vips) = c.ipv4_vips.as_ref() {
+        for key in ipv4_vips.lock().keys() {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::error!(error=?e, "Failed to read the IPV4VIP map");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+            let addr = IpAddr::V4(Ipv4Addr::from(key));
+            vips.entry(addr)
+                .or_insert_with(|| Vip {
+                    addr,
+                    in_map: false,
+                    ports: Vec::new(),
+                })
+                .in_map = true;
+        }
+    }
+    HttpResponse::Ok().json(vips.into_values().collect::<Vec<Vip>>())
+}
+
+/// List interfaces the XDP program is attached to.
+#[get("/api/v1/interfaces")]
+pub async fn list_interfaces(c: Data<State>) -> impl Responder {
+    HttpResponse::Ok().json(&c.interfaces)
+}
+
+/// Return recent events matching the query, newest first.
+#[get("/api/v1/events/recent")]
+pub async fn recent_events(
+    c: Data<State>,
+    filter: Query<EventFilter>,
+    limit: Query<Limit>,
+) -> impl Responder {
+    let recent = c.feed.recent();
+    let events: Vec<&ConnectionEvent> = recent
+        .iter()
+        .rev()
+        .map(|e| e.as_ref())
+        .filter(|e| filter.matches(e))
+        .take(limit.limit.unwrap_or(usize::MAX))
+        .collect();
+    HttpResponse::Ok().json(events)
+}
+
+/// Stream events matching the query as Server-Sent Events.
+#[get("/api/v1/events/stream")]
+pub async fn stream_events(c: Data<State>, filter: Query<EventFilter>) -> impl Responder {
+    let filter = filter.into_inner();
+    let events = futures::stream::unfold(c.feed.subscribe(), move |mut rx| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                let data = match rx.recv().await {
+                    Ok(event) if filter.matches(&event) => {
+                        let Ok(json) = serde_json::to_string(event.as_ref()) else {
+                            continue;
+                        };
+                        format!("data: {json}\n\n")
+                    }
+                    Ok(_) => continue,
+                    // tell the client that it missed events
+                    Err(RecvError::Lagged(n)) => format!("event: lagged\ndata: {n}\n\n"),
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok::<_, Infallible>(Bytes::from(data)), rx));
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// Stream events matching the query as WebSocket text messages.
+#[get("/api/v1/events/ws")]
+pub async fn watch_events(
+    req: HttpRequest,
+    body: Payload,
+    c: Data<State>,
+    filter: Query<EventFilter>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let filter = filter.into_inner();
+    let mut rx = c.feed.subscribe();
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) if filter.matches(&event) => {
+                        let Ok(json) = serde_json::to_string(event.as_ref()) else {
+                            continue;
+                        };
+                        if session.text(json).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!(skipped = n, "WebSocket subscriber lagged");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = messages.recv() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    Ok(response)
+}
+
+/// Return counters matching the filter expression.
+#[get("/api/v1/counters")]
+pub async fn list_counters(c: Data<State>, query: Query<CounterQuery>) -> impl Responder {
+    let filter = match FilterExpr::from_str(query.filter.as_deref().unwrap_or_default()) {
+        Ok(filter) => filter,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let counters: Vec<CounterSample> = c
+        .metrics
+        .counters()
+        .into_iter()
+        .filter(|c| filter.matches(|key| c.labels.get(key).cloned()))
+        .collect();
+    HttpResponse::Ok().json(counters)
+}
+
+/// Dump the contents of the eBPF maps.
+#[get("/api/v1/maps")]
+pub async fn dump_maps(c: Data<State>) -> impl Responder {
+    let mut maps: BTreeMap<&str, Vec<MapEntry>> = BTreeMap::new();
+    if let Some(ipv4_vips) = c.ipv4_vips.as_ref() {
+        let mut entries = Vec::new();
+        for entry in ipv4_vips.lock().iter() {
+            match entry {
+                Ok((key, value)) => entries.push(MapEntry {
+                    key: Ipv4Addr::from(key).to_string(),
+                    value: value as u64,
+                }),
+                Err(e) => {
+                    tracing::error!(error=?e, "Failed to read the IPV4VIP map");
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        maps.insert("IPV4VIP", entries);
+    }
+    if let Some(vip_ranges) = c.vip_ranges.as_ref() {
+        match vip_ranges.lock().map_entries() {
+            Ok(ranges) => {
+                maps.insert(
+                    "IPV4VIP_RANGE",
+                    ranges
+                        .into_iter()
+                        .map(|(range, value)| MapEntry {
+                            key: range.to_string(),
+                            value: value as u64,
+                        })
+                        .collect(),
+                );
+            }
+            Err(e) => {
+                tracing::error!(error=?e, "Failed to read the IPV4VIP_RANGE map");
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    if let Some(drops) = c.event_drops.as_ref() {
+        match event_drops(&drops.lock()) {
+            Ok(value) => {
+                // values of all CPUs are summed up
+                maps.insert(
+                    "EVENT_DROPS",
+                    vec![MapEntry {
+                        key: "0".to_string(),
+                        value,
+                    }],
+                );
+            }
+            Err(e) => {
+                tracing::error!(error=?e, "Failed to read the EVENT_DROPS map");
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    HttpResponse::Ok().json(maps)
+}
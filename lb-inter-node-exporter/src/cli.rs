@@ -0,0 +1,550 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use clap::{Args, Subcommand, ValueEnum};
+use futures::StreamExt;
+use ipnet::IpNet;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    api::{MapEntry, Vip},
+    feed::ConnectionEvent,
+    top,
+    trace::CounterSample,
+};
+
+// Command queries a running agent through its HTTP API.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List VIPs tracked by the agent
+    ListVips(ClientArgs),
+    /// Follow events received by the node
+    Tail {
+        #[clap(flatten)]
+        client: ClientArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Show live traffic of the node like top
+    Top {
+        #[clap(flatten)]
+        client: ClientArgs,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(
+            long = "prefix-v4",
+            default_value = "24",
+            value_parser = clap::value_parser!(u8).range(0..=32),
+            help = "Prefix length to aggregate IPv4 clients"
+        )]
+        prefix_v4: u8,
+        #[clap(
+            long = "prefix-v6",
+            default_value = "64",
+            value_parser = clap::value_parser!(u8).range(0..=128),
+            help = "Prefix length to aggregate IPv6 clients"
+        )]
+        prefix_v6: u8,
+    },
+    /// Show connection counters per Service
+    Counters {
+        #[clap(flatten)]
+        client: ClientArgs,
+        #[clap(
+            long,
+            help = "Filter expression on counter labels, e.g. namespace=test"
+        )]
+        filter: Option<String>,
+    },
+    /// Dump the contents of the eBPF maps
+    DumpMaps(ClientArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    #[clap(long, help = "Show events only to this VIP")]
+    vip: Option<IpAddr>,
+    #[clap(
+        long,
+        help = "Show events only to this Service (name or namespace/name)"
+    )]
+    service: Option<String>,
+    #[clap(long, help = "Show events only from this CIDR")]
+    src: Option<IpNet>,
+    #[clap(long, help = "Show events only to this port")]
+    port: Option<u16>,
+}
+
+impl FilterArgs {
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        if let Some(vip) = self.vip {
+            query.push(("vip", vip.to_string()));
+        }
+        if let Some(service) = self.service.as_ref() {
+            query.push(("service", service.clone()));
+        }
+        if let Some(src) = self.src {
+            query.push(("src", src.to_string()));
+        }
+        if let Some(port) = self.port {
+            query.push(("port", port.to_string()));
+        }
+        query
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ClientArgs {
+    #[clap(
+        long,
+        default_value = "http://localhost:8080",
+        help = "The address of the agent"
+    )]
+    agent: String,
+
+    #[clap(short, long, value_enum, default_value = "table")]
+    output: Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+pub async fn run(command: Command) -> Result<(), anyhow::Error> {
+    match command {
+        Command::ListVips(client) => list_vips(&client).await,
+        Command::Tail { client, filter } => tail(&client, &filter.query()).await,
+        Command::Top {
+            client,
+            filter,
+            prefix_v4,
+            prefix_v6,
+        } => top::run(client, filter.query(), prefix_v4, prefix_v6).await,
+        Command::Counters { client, filter } => counters(&client, filter).await,
+        Command::DumpMaps(client) => dump_maps(&client).await,
+    }
+}
+
+pub async fn get<T: DeserializeOwned>(
+    client: &ClientArgs,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<T, anyhow::Error> {
+    let res = reqwest::Client::new()
+        .get(format!("{}{}", client.agent.trim_end_matches('/'), path))
+        .query(query)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(res.json().await?)
+}
+
+async fn list_vips(client: &ClientArgs) -> Result<(), anyhow::Error> {
+    let vips: Vec<Vip> = get(client, "/api/v1/vips", &[]).await?;
+    if client.output == Output::Json {
+        println!("{}", serde_json::to_string_pretty(&vips)?);
+        return Ok(());
+    }
+    let mut rows = Vec::new();
+    for vip in vips.iter() {
+        if vip.ports.is_empty() {
+            rows.push(vec![
+                vip.addr.to_string(),
+                vip.in_map.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ]);
+        }
+        for port in vip.ports.iter() {
+            rows.push(vec![
+                vip.addr.to_string(),
+                vip.in_map.to_string(),
+                port.port.to_string(),
+                format!("{}/{}", port.namespace, port.name),
+                port.source.clone(),
+                port.traffic_policy.clone(),
+            ]);
+        }
+    }
+    print_table(
+        &["VIP", "IN MAP", "PORT", "SERVICE", "SOURCE", "POLICY"],
+        rows,
+    );
+    Ok(())
+}
+
+/// StreamMessage is a message of the event stream.
+#[derive(Debug)]
+pub enum StreamMessage {
+    Event(Box<ConnectionEvent>),
+    // the count of events the agent skipped for this client
+    Lagged(u64),
+}
+
+/// Follow the event stream until `f` returns false or the stream ends.
+pub async fn follow(
+    client: &ClientArgs,
+    query: &[(&str, String)],
+    mut f: impl FnMut(StreamMessage) -> bool,
+) -> Result<(), anyhow::Error> {
+    let res = reqwest::Client::new()
+        .get(format!(
+            "{}/api/v1/events/stream",
+            client.agent.trim_end_matches('/')
+        ))
+        .query(query)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut body = res.bytes_stream();
+    let mut parser = SseParser::default();
+    while let Some(chunk) = body.next().await {
+        for message in parser.push(&chunk?) {
+            if !f(message) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// SseParser decodes Server-Sent Events of the event stream from chunks of the body.
+#[derive(Debug, Default)]
+struct SseParser {
+    buf: Vec<u8>,
+}
+
+impl SseParser {
+    /// Return the messages completed by the chunk and keep the rest for the next chunk.
+    fn push(&mut self, chunk: &[u8]) -> Vec<StreamMessage> {
+        self.buf.extend_from_slice(chunk);
+        let mut messages = Vec::new();
+        // messages are separated by a blank line, a chunk may end in the middle of a character
+        while let Some(pos) = self.buf.windows(2).position(|w| w == b"\n\n") {
+            let message: Vec<u8> = self.buf.drain(..pos + 2).collect();
+            if let Some(message) = parse_message(&String::from_utf8_lossy(&message)) {
+                messages.push(message);
+            }
+        }
+        messages
+    }
+}
+
+fn parse_message(message: &str) -> Option<StreamMessage> {
+    let mut event = "message";
+    let mut data = Vec::new();
+    for line in message.lines() {
+        // lines starting with a colon are comments
+        if line.is_empty() || line.starts_with(':') {
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event = value,
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+    if data.is_empty() {
+        return None;
+    }
+    // data lines of a message are joined with newlines
+    let data = data.join("\n");
+    match event {
+        "lagged" => data.trim().parse().ok().map(StreamMessage::Lagged),
+        "message" => serde_json::from_str::<ConnectionEvent>(&data)
+            .ok()
+            .map(|event| StreamMessage::Event(Box::new(event))),
+        _ => None,
+    }
+}
+
+async fn tail(client: &ClientArgs, query: &[(&str, String)]) -> Result<(), anyhow::Error> {
+    let widths = TAIL_COLUMNS.map(|(_, w)| w);
+    if client.output == Output::Table {
+        print_row(&TAIL_COLUMNS.map(|(h, _)| h.to_string()), &widths);
+    }
+    follow(client, query, |message| {
+        match message {
+            StreamMessage::Event(event) => match client.output {
+                Output::Json => {
+                    if let Ok(json) = serde_json::to_string(&event) {
+                        println!("{json}");
+                    }
+                }
+                Output::Table => print_row(&event_row(&event), &widths),
+            },
+            StreamMessage::Lagged(n) => eprintln!("lagged: {n} events skipped"),
+        }
+        true
+    })
+    .await
+}
+
+const TAIL_COLUMNS: [(&str, usize); 6] = [
+    ("SOURCE", 21),
+    ("VIP", 21),
+    ("SERVICE", 30),
+    ("DELIVERY", 9),
+    ("BACKEND", 30),
+    ("NODE", 20),
+];
+
+fn event_row(event: &ConnectionEvent) -> [String; 6] {
+    let service = match (event.namespace.as_deref(), event.service.as_deref()) {
+        (Some(ns), Some(name)) => format!("{ns}/{name}"),
+        _ => String::new(),
+    };
+    [
+        format!("{}:{}", event.src_addr, event.src_port),
+        format!("{}:{}", event.dst_addr, event.dst_port),
+        service,
+        event.delivery.clone(),
+        event.backend_pod.clone().unwrap_or_default(),
+        event.backend_node.clone().unwrap_or_default(),
+    ]
+}
+
+async fn counters(client: &ClientArgs, filter: Option<String>) -> Result<(), anyhow::Error> {
+    let query: Vec<(&str, String)> = filter.into_iter().map(|f| ("filter", f)).collect();
+    let samples: Vec<CounterSample> = get(client, "/api/v1/counters", &query).await?;
+    let services = service_counters(&samples);
+    if client.output == Output::Json {
+        let services: Vec<serde_json::Value> = services
+            .into_iter()
+            .map(|((namespace, service), (picked, misrouted))| {
+                serde_json::json!({
+                    "namespace": namespace,
+                    "service": service,
+                    "picked": picked,
+                    "misrouted": misrouted,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&services)?);
+        return Ok(());
+    }
+    let rows = services
+        .into_iter()
+        .map(|((namespace, service), (picked, misrouted))| {
+            vec![
+                namespace,
+                service,
+                picked.to_string(),
+                misrouted.to_string(),
+            ]
+        })
+        .collect();
+    print_table(&["NAMESPACE", "SERVICE", "PICKED", "MISROUTED"], rows);
+    Ok(())
+}
+
+// Sum up counters per (namespace, service) into (picked, misrouted).
+fn service_counters(samples: &[CounterSample]) -> BTreeMap<(String, String), (u64, u64)> {
+    let mut services: BTreeMap<(String, String), (u64, u64)> = BTreeMap::new();
+    for sample in samples.iter() {
+        let key = (
+            sample.labels.get("namespace").cloned().unwrap_or_default(),
+            sample.labels.get("service").cloned().unwrap_or_default(),
+        );
+        let counts = services.entry(key).or_default();
+        if sample.name.ends_with("picked_total") {
+            counts.0 += sample.value;
+        } else if sample.name.ends_with("misrouted_total") {
+            counts.1 += sample.value;
+        }
+    }
+    services
+}
+
+async fn dump_maps(client: &ClientArgs) -> Result<(), anyhow::Error> {
+    let maps: BTreeMap<String, Vec<MapEntry>> = get(client, "/api/v1/maps", &[]).await?;
+    if client.output == Output::Json {
+        println!("{}", serde_json::to_string_pretty(&maps)?);
+        return Ok(());
+    }
+    let rows = maps
+        .iter()
+        .flat_map(|(name, entries)| {
+            entries
+                .iter()
+                .map(move |e| vec![name.clone(), e.key.clone(), e.value.to_string()])
+        })
+        .collect();
+    print_table(&["MAP", "KEY", "VALUE"], rows);
+    Ok(())
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows.iter() {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    print_row(&headers, &widths);
+    for row in rows.iter() {
+        print_row(row, &widths);
+    }
+}
+
+fn print_row(cells: &[String], widths: &[usize]) {
+    let line: Vec<String> = cells
+        .iter()
+        .zip(widths.iter())
+        .map(|(cell, w)| format!("{cell:<w$}"))
+        .collect();
+    println!("{}", line.join("  ").trim_end());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT: &str = r#"{"timestamp":1713684935.221,"src_addr":"192.168.0.2","src_port":60618,"dst_addr":"10.0.10.0","dst_port":80,"service":"app-svc-cluster","namespace":"test","port_name":"http","source":"ingress","delivery":"forwarded","backend_addr":null,"backend_port":null,"backend_pod":null,"backend_node":null,"misrouted":false,"ifindex":2,"ifname":"eth0"}"#;
+
+    fn parse(chunks: &[&[u8]]) -> Vec<StreamMessage> {
+        let mut parser = SseParser::default();
+        chunks.iter().flat_map(|chunk| parser.push(chunk)).collect()
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let stream = format!("data: {EVENT}\n\nevent: lagged\ndata: 5\n\n: keep-alive\n\n");
+        let messages = parse(&[stream.as_bytes()]);
+        assert_eq!(messages.len(), 2);
+        match &messages[0] {
+            StreamMessage::Event(event) => {
+                assert_eq!(event.src_addr, "192.168.0.2".parse::<IpAddr>().unwrap());
+                assert_eq!(event.service.as_deref(), Some("app-svc-cluster"));
+                assert_eq!(event.ifname.as_deref(), Some("eth0"));
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+        assert!(matches!(messages[1], StreamMessage::Lagged(5)));
+
+        // split at every byte
+        let bytes: Vec<&[u8]> = stream.as_bytes().chunks(1).collect();
+        let messages = parse(&bytes);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], StreamMessage::Event(_)));
+        assert!(matches!(messages[1], StreamMessage::Lagged(5)));
+
+        // an incomplete message waits for the rest
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"event: lagged\ndata: 7\n").is_empty());
+        assert!(matches!(parser.push(b"\n")[..], [StreamMessage::Lagged(7)]));
+    }
+
+    #[test]
+    fn test_sse_parser_fields() {
+        // data split into lines is joined with newlines, which JSON allows between tokens
+        let (head, tail) = EVENT.split_at(EVENT.find(",\"src_port\"").unwrap());
+        let stream = format!(": comment\ndata: {head}\ndata:{tail}\n\n");
+        let messages = parse(&[stream.as_bytes()]);
+        assert!(matches!(messages[..], [StreamMessage::Event(_)]));
+
+        // a multi-byte character split across chunks
+        let event = EVENT.replace("app-svc-cluster", "サービス");
+        let stream = format!("data: {event}\n\n");
+        let pos = stream.find("サ").unwrap() + 1;
+        let messages = parse(&[&stream.as_bytes()[..pos], &stream.as_bytes()[pos..]]);
+        match &messages[..] {
+            [StreamMessage::Event(event)] => {
+                assert_eq!(event.service.as_deref(), Some("サービス"))
+            }
+            messages => panic!("unexpected messages {messages:?}"),
+        }
+
+        // messages without data, of unknown events or invalid data are skipped
+        let messages = parse(&[
+            b"event: lagged\n\n",
+            b"event: unknown\ndata: 1\n\n",
+            b"event: lagged\ndata: many\n\n",
+            b"data: {\n\n",
+            b"id: 1\n\n",
+        ]);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_event_row() {
+        let mut event: ConnectionEvent = serde_json::from_str(EVENT).unwrap();
+        event.backend_pod = Some("app-0".to_string());
+        assert_eq!(
+            event_row(&event),
+            [
+                "192.168.0.2:60618",
+                "10.0.10.0:80",
+                "test/app-svc-cluster",
+                "forwarded",
+                "app-0",
+                "",
+            ]
+        );
+        event.namespace = None;
+        assert_eq!(event_row(&event)[2], "");
+    }
+
+    #[test]
+    fn test_service_counters() {
+        let sample = |name: &str, labels: &[(&str, &str)], value| CounterSample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+        };
+        let samples = [
+            sample(
+                "lb_inter_node_exporter_picked_total",
+                &[("namespace", "test"), ("service", "web"), ("src", "a")],
+                3,
+            ),
+            sample(
+                "lb_inter_node_exporter_picked_total",
+                &[("namespace", "test"), ("service", "web"), ("src", "b")],
+                4,
+            ),
+            sample(
+                "lb_inter_node_exporter_misrouted_total",
+                &[("namespace", "test"), ("service", "web")],
+                2,
+            ),
+            sample(
+                "lb_inter_node_exporter_picked_total",
+                &[("namespace", "other"), ("service", "web")],
+                1,
+            ),
+            // connections to unknown VIPs
+            sample(
+                "lb_inter_node_exporter_picked_total",
+                &[("dst", "10.0.0.1")],
+                5,
+            ),
+            // other counters are ignored
+            sample(
+                "lb_inter_node_exporter_events_processed_total",
+                &[("namespace", "test"), ("service", "web")],
+                100,
+            ),
+        ];
+        let services = service_counters(&samples);
+        let key = |ns: &str, svc: &str| (ns.to_string(), svc.to_string());
+        assert_eq!(
+            services,
+            BTreeMap::from([
+                (key("", ""), (5, 0)),
+                (key("other", "web"), (1, 0)),
+                (key("test", "web"), (7, 2)),
+            ])
+        );
+    }
+}
@@ -0,0 +1,722 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::watch;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{
+    error::Error,
+    kubernetes::ServiceFilter,
+    label::{SrcGroup, SrcLabelMode},
+    otlp::OtlpProtocol,
+    source::{StaticVip, VipSourceKind},
+    supervisor::Task,
+    trace::{AgentMetrics, LogLevelHandle},
+    Cmd,
+};
+
+/// Sections applied to the running agent on reloading the config file.
+/// Changes of the other keys take effect after a restart.
+const LIVE_KEYS: [&str; 3] = ["log-level", "filter", "sampling"];
+
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// ConfigFile is the content of the file given by --config in TOML or YAML.
+///
+/// Keys are named after the flags, and keys set in the file override the flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub log_level: Option<String>,
+    pub interfaces: Option<Vec<String>>,
+    pub xdp_mode: Option<String>,
+    pub node_name: Option<String>,
+    pub conntrack: Option<bool>,
+    pub vips: VipsConfig,
+    pub filter: FilterConfig,
+    pub labels: LabelsConfig,
+    pub exporters: ExportersConfig,
+    pub sampling: SamplingConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VipsConfig {
+    pub source: Option<VipSourceKind>,
+    #[serde(rename = "static")]
+    pub static_vips: Option<Vec<String>>,
+    pub file: Option<PathBuf>,
+    pub file_interval: Option<u64>,
+    pub ranges: Option<Vec<String>>,
+    pub metallb_pools: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FilterConfig {
+    pub namespaces: Option<Vec<String>>,
+    pub exclude_namespaces: Option<Vec<String>>,
+    pub selector: Option<String>,
+    pub opt_in: Option<bool>,
+    pub load_balancer_classes: Option<Vec<String>>,
+    pub track_local_policy: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LabelsConfig {
+    pub src: Option<SrcLabelMode>,
+    pub src_prefix_v4: Option<u8>,
+    pub src_prefix_v6: Option<u8>,
+    pub src_groups: Option<Vec<String>>,
+    pub src_top_k: Option<usize>,
+    pub src_idle_timeout: Option<u64>,
+    pub series_ttl: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExportersConfig {
+    pub port: Option<u32>,
+    pub grpc_port: Option<u16>,
+    #[serde(alias = "metrics-endpoint")]
+    pub otlp_traces_endpoint: Option<String>,
+    pub otlp_traces_protocol: Option<OtlpProtocol>,
+    pub otlp_metrics_endpoint: Option<String>,
+    pub otlp_metrics_protocol: Option<OtlpProtocol>,
+    pub otlp_metrics_interval: Option<u64>,
+    pub otlp_logs_endpoint: Option<String>,
+    pub otlp_logs_protocol: Option<OtlpProtocol>,
+    pub ipfix_collector: Option<String>,
+    pub ipfix_observation_domain: Option<u32>,
+    pub ipfix_template_refresh: Option<u64>,
+    pub program_stats_interval: Option<u64>,
+    pub recent_events: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SamplingConfig {
+    // the fraction of events to process
+    pub rate: Option<f64>,
+}
+
+/// LiveConfig is the part of the configuration applied without a restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveConfig {
+    pub log_level: String,
+    pub filter: ServiceFilter,
+    pub sample_rate: f64,
+}
+
+impl LiveConfig {
+    pub fn from_cmd(cmd: &Cmd) -> Self {
+        LiveConfig {
+            log_level: cmd.log_level.clone(),
+            filter: ServiceFilter {
+                namespaces: cmd.namespaces.clone(),
+                exclude_namespaces: cmd.exclude_namespaces.clone(),
+                label_selector: cmd.label_selector.clone(),
+                opt_in: cmd.opt_in,
+                load_balancer_classes: cmd.load_balancer_classes.clone(),
+                track_local_policy: cmd.track_local_policy,
+            },
+            sample_rate: cmd.sample_rate,
+        }
+    }
+}
+
+/// FixedConfig is the part of the configuration the live part is validated against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedConfig {
+    pub vip_source: VipSourceKind,
+    pub node_name: String,
+}
+
+impl FixedConfig {
+    pub fn from_cmd(cmd: &Cmd) -> Self {
+        FixedConfig {
+            vip_source: cmd.vip_source,
+            node_name: cmd.node_name.clone(),
+        }
+    }
+
+    /// Return an error if the live configuration can't work with the fixed one.
+    pub fn validate(&self, live: &LiveConfig) -> Result<(), Error> {
+        if self.vip_source == VipSourceKind::Kubernetes
+            && self.node_name.is_empty()
+            && live.filter.track_local_policy
+        {
+            return Err(Error::Config(
+                "--node-name or NODE_NAME is required to track externalTrafficPolicy=Local Services"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Config is a loaded config file with its raw content to find changed keys.
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    file: ConfigFile,
+    raw: Value,
+    content: String,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::StdIo)?;
+        Config::parse(path, &content)
+    }
+
+    fn parse(path: &Path, content: &str) -> Result<Self, Error> {
+        let raw: Value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(content).map_err(|e| Error::Config(e.to_string()))?,
+            _ => serde_yaml::from_str(content).map_err(|e| Error::Config(e.to_string()))?,
+        };
+        // an empty YAML file is null
+        let raw = if raw.is_null() {
+            Value::Object(Default::default())
+        } else {
+            raw
+        };
+        let file: ConfigFile =
+            serde_json::from_value(raw.clone()).map_err(|e| Error::Config(e.to_string()))?;
+        if let Some(level) = file.log_level.as_ref() {
+            LevelFilter::from_str(level).map_err(|e| Error::Config(format!("log-level: {e}")))?;
+        }
+        if let Some(rate) = file.sampling.rate {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(Error::Config(format!(
+                    "sampling.rate must be between 0 and 1: {rate}"
+                )));
+            }
+        }
+        Ok(Config {
+            path: path.to_path_buf(),
+            file,
+            raw,
+            content: content.to_string(),
+        })
+    }
+
+    /// Override the flags with keys set in the file.
+    pub fn apply(&self, cmd: &mut Cmd) -> Result<(), Error> {
+        let file = &self.file;
+        set(&mut cmd.log_level, &file.log_level);
+        set(&mut cmd.iface, &file.interfaces);
+        set(&mut cmd.xdp_mode, &file.xdp_mode);
+        set(&mut cmd.node_name, &file.node_name);
+        set(&mut cmd.conntrack, &file.conntrack);
+
+        set(&mut cmd.vip_source, &file.vips.source);
+        if let Some(vips) = file.vips.static_vips.as_ref() {
+            cmd.vips = vips
+                .iter()
+                .map(|vip| {
+                    StaticVip::from_str(vip).map_err(|e| Error::Config(format!("vips.static: {e}")))
+                })
+                .collect::<Result<Vec<StaticVip>, Error>>()?;
+        }
+        if let Some(path) = file.vips.file.as_ref() {
+            cmd.vip_file = Some(path.clone());
+        }
+        set(&mut cmd.vip_file_interval, &file.vips.file_interval);
+        set(&mut cmd.vip_ranges, &file.vips.ranges);
+        set(&mut cmd.metallb_pools, &file.vips.metallb_pools);
+
+        set(&mut cmd.namespaces, &file.filter.namespaces);
+        set(&mut cmd.exclude_namespaces, &file.filter.exclude_namespaces);
+        if let Some(selector) = file.filter.selector.as_ref() {
+            cmd.label_selector = Some(selector.clone());
+        }
+        set(&mut cmd.opt_in, &file.filter.opt_in);
+        set(
+            &mut cmd.load_balancer_classes,
+            &file.filter.load_balancer_classes,
+        );
+        set(&mut cmd.track_local_policy, &file.filter.track_local_policy);
+
+        set(&mut cmd.src_label, &file.labels.src);
+        set(&mut cmd.src_prefix_v4, &file.labels.src_prefix_v4);
+        set(&mut cmd.src_prefix_v6, &file.labels.src_prefix_v6);
+        if cmd.src_prefix_v4 > 32 || cmd.src_prefix_v6 > 128 {
+            return Err(Error::Config(
+                "labels.src-prefix-v4 must be up to 32 and labels.src-prefix-v6 up to 128"
+                    .to_string(),
+            ));
+        }
+        if let Some(groups) = file.labels.src_groups.as_ref() {
+            cmd.src_groups = groups
+                .iter()
+                .map(|group| {
+                    SrcGroup::from_str(group)
+                        .map_err(|e| Error::Config(format!("labels.src-groups: {e}")))
+                })
+                .collect::<Result<Vec<SrcGroup>, Error>>()?;
+        }
+        set(&mut cmd.src_top_k, &file.labels.src_top_k);
+        set(&mut cmd.src_idle_timeout, &file.labels.src_idle_timeout);
+        set(&mut cmd.series_ttl, &file.labels.series_ttl);
+
+        set(&mut cmd.port, &file.exporters.port);
+        set(&mut cmd.grpc_port, &file.exporters.grpc_port);
+        if let Some(endpoint) = file.exporters.otlp_traces_endpoint.as_ref() {
+            cmd.otlp_traces_endpoint = Some(endpoint.clone());
+        }
+        set(
+            &mut cmd.otlp_traces_protocol,
+            &file.exporters.otlp_traces_protocol,
+        );
+        if let Some(endpoint) = file.exporters.otlp_metrics_endpoint.as_ref() {
+            cmd.otlp_metrics_endpoint = Some(endpoint.clone());
+        }
+        set(
+            &mut cmd.otlp_metrics_protocol,
+            &file.exporters.otlp_metrics_protocol,
+        );
+        if let Some(endpoint) = file.exporters.otlp_logs_endpoint.as_ref() {
+            cmd.otlp_logs_endpoint = Some(endpoint.clone());
+        }
+        set(
+            &mut cmd.otlp_logs_protocol,
+            &file.exporters.otlp_logs_protocol,
+        );
+        set(
+            &mut cmd.otlp_metrics_interval,
+            &file.exporters.otlp_metrics_interval,
+        );
+        if let Some(collector) = file.exporters.ipfix_collector.as_ref() {
+            cmd.ipfix_collector = Some(collector.clone());
+        }
+        if let Some(domain) = file.exporters.ipfix_observation_domain {
+            cmd.ipfix_observation_domain = Some(domain);
+        }
+        set(
+            &mut cmd.ipfix_template_refresh,
+            &file.exporters.ipfix_template_refresh,
+        );
+        set(
+            &mut cmd.program_stats_interval,
+            &file.exporters.program_stats_interval,
+        );
+        set(&mut cmd.recent_events, &file.exporters.recent_events);
+
+        set(&mut cmd.sample_rate, &file.sampling.rate);
+        Ok(())
+    }
+
+    /// Override the live part of the flags with keys set in the file.
+    pub fn live(&self, base: &LiveConfig) -> LiveConfig {
+        let file = &self.file;
+        let mut live = base.clone();
+        set(&mut live.log_level, &file.log_level);
+        set(&mut live.filter.namespaces, &file.filter.namespaces);
+        set(
+            &mut live.filter.exclude_namespaces,
+            &file.filter.exclude_namespaces,
+        );
+        if let Some(selector) = file.filter.selector.as_ref() {
+            live.filter.label_selector = Some(selector.clone());
+        }
+        set(&mut live.filter.opt_in, &file.filter.opt_in);
+        set(
+            &mut live.filter.load_balancer_classes,
+            &file.filter.load_balancer_classes,
+        );
+        set(
+            &mut live.filter.track_local_policy,
+            &file.filter.track_local_policy,
+        );
+        set(&mut live.sample_rate, &file.sampling.rate);
+        live
+    }
+
+    /// Keys changed from the other config file that are applied only after a restart.
+    pub fn restart_required(&self, other: &Config) -> Vec<String> {
+        let mut keys = BTreeMap::new();
+        flatten("", &self.raw, &mut keys);
+        let mut other_keys = BTreeMap::new();
+        flatten("", &other.raw, &mut other_keys);
+
+        let mut changed: Vec<String> = keys
+            .keys()
+            .chain(other_keys.keys())
+            .filter(|key| keys.get(*key) != other_keys.get(*key))
+            .filter(|key| {
+                !LIVE_KEYS
+                    .iter()
+                    .any(|live| *key == live || key.starts_with(&format!("{live}.")))
+            })
+            .cloned()
+            .collect();
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+}
+
+fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *target = value.clone();
+    }
+}
+
+// flatten nested tables into dotted keys like `labels.src`
+fn flatten(prefix: &str, value: &Value, keys: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(table) => {
+            for (key, value) in table.iter() {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&key, value, keys);
+            }
+        }
+        _ => {
+            keys.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+/// ConfigWatcher reloads the config file on changes and applies the live part of it.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    // the flags the live part of the file overrides
+    base: LiveConfig,
+    fixed: FixedConfig,
+    // the config file the agent started with
+    started: Config,
+    current: LiveConfig,
+    last_content: String,
+    log_level: LogLevelHandle,
+    filter: watch::Sender<ServiceFilter>,
+    sample_rate: watch::Sender<f64>,
+    metrics: AgentMetrics,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        base: LiveConfig,
+        fixed: FixedConfig,
+        started: Config,
+        log_level: LogLevelHandle,
+        filter: watch::Sender<ServiceFilter>,
+        sample_rate: watch::Sender<f64>,
+        metrics: AgentMetrics,
+    ) -> Self {
+        let current = started.live(&base);
+        ConfigWatcher {
+            path: started.path.clone(),
+            base,
+            fixed,
+            current,
+            last_content: started.content.clone(),
+            started,
+            log_level,
+            filter,
+            sample_rate,
+            metrics,
+        }
+    }
+
+    async fn reload(&mut self) -> Result<(), Error> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(Error::StdIo)?;
+        if self.last_content == content {
+            return Ok(());
+        }
+        // an invalid file is reported only once until it changes again
+        self.last_content = content.clone();
+        let config = Config::parse(&self.path, &content)?;
+        let live = config.live(&self.base);
+        if let Err(e) = self.fixed.validate(&live) {
+            // keep the current config until the file is fixed
+            tracing::error!(path=?self.path, error=?e, "Reject the invalid config file");
+            self.metrics.config_reloaded("invalid");
+            return Ok(());
+        }
+
+        if live.log_level != self.current.log_level {
+            tracing::info!(level = live.log_level, "Change the log level");
+            match LevelFilter::from_str(&live.log_level) {
+                Ok(level) => {
+                    if let Err(e) = self.log_level.modify(|filter| *filter = level) {
+                        tracing::error!(error=?e, "Failed to change the log level");
+                    }
+                }
+                Err(e) => tracing::error!(level = live.log_level, error=?e, "Invalid log level"),
+            }
+        }
+        if live.filter != self.current.filter {
+            tracing::info!(filter=?live.filter, "Change the Service filter");
+            self.filter.send_replace(live.filter.clone());
+        }
+        if live.sample_rate != self.current.sample_rate {
+            tracing::info!(rate = live.sample_rate, "Change the sample rate");
+            self.sample_rate.send_replace(live.sample_rate);
+        }
+        self.current = live;
+
+        let restart_required = config.restart_required(&self.started);
+        if !restart_required.is_empty() {
+            tracing::warn!(keys=?restart_required, "Changes of the config file are applied after a restart");
+        }
+        self.metrics.config_reloaded("success");
+        self.metrics
+            .config_restart_required(!restart_required.is_empty());
+        tracing::info!(path=?self.path, "Reload the config file");
+        Ok(())
+    }
+}
+
+impl Task for ConfigWatcher {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut ticker = tokio::time::interval(CONFIG_CHECK_INTERVAL);
+        tracing::info!(path=?self.path, "Start config file watcher");
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.reload().await {
+                // keep the last valid config until the file is fixed
+                tracing::error!(path=?self.path, error=?e, "Failed to reload the config file");
+                self.metrics.config_reloaded("failure");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const TOML: &str = r#"
+log-level = "debug"
+interfaces = ["eth1", "eth2"]
+conntrack = true
+
+[vips]
+static = ["web=10.0.0.1:80"]
+
+[filter]
+namespaces = ["default"]
+opt-in = true
+
+[labels]
+src = "prefix"
+src-prefix-v4 = 16
+
+[exporters]
+grpc-port = 9090
+
+[sampling]
+rate = 0.5
+"#;
+
+    const YAML: &str = "
+log-level: debug
+interfaces: [eth1, eth2]
+conntrack: true
+vips:
+  static: [web=10.0.0.1:80]
+filter:
+  namespaces: [default]
+  opt-in: true
+labels:
+  src: prefix
+  src-prefix-v4: 16
+exporters:
+  grpc-port: 9090
+sampling:
+  rate: 0.5
+";
+
+    fn cmd() -> Cmd {
+        Cmd::parse_from(["lb-inter-node-exporter"])
+    }
+
+    fn parse(path: &str, content: &str) -> Result<Config, Error> {
+        Config::parse(Path::new(path), content)
+    }
+
+    #[test]
+    fn test_config_parse() {
+        for config in [
+            parse("config.toml", TOML).unwrap(),
+            parse("config.yaml", YAML).unwrap(),
+        ] {
+            assert_eq!(config.file.log_level.as_deref(), Some("debug"));
+            assert_eq!(
+                config.file.interfaces,
+                Some(vec!["eth1".to_string(), "eth2".to_string()])
+            );
+            assert_eq!(config.file.labels.src_prefix_v4, Some(16));
+            assert_eq!(config.file.exporters.grpc_port, Some(9090));
+            assert_eq!(config.file.sampling.rate, Some(0.5));
+        }
+
+        for empty in ["", "\n", "# no keys\n"] {
+            let config = parse("config.yaml", empty).unwrap();
+            assert!(config.file.log_level.is_none());
+            assert!(config
+                .restart_required(&parse("config.yaml", "").unwrap())
+                .is_empty());
+        }
+        assert!(parse("config.toml", "").unwrap().file.interfaces.is_none());
+
+        for (path, invalid) in [
+            ("config.toml", "log-level = "),
+            ("config.yaml", "log-level: [debug"),
+            ("config.yaml", "unknown: true"),
+            ("config.yaml", "filter: {unknown: true}"),
+            ("config.yaml", "log-level: verbose"),
+            ("config.yaml", "interfaces: eth0"),
+        ] {
+            assert!(parse(path, invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_config_sampling_rate() {
+        for rate in ["0", "0.0", "0.25", "1", "1.0"] {
+            let config = parse("config.yaml", &format!("sampling: {{rate: {rate}}}")).unwrap();
+            assert!(config.file.sampling.rate.is_some());
+        }
+        for rate in ["-0.1", "1.5", "2"] {
+            assert!(parse("config.yaml", &format!("sampling: {{rate: {rate}}}")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_apply() {
+        let mut cmd = cmd();
+        parse("config.toml", TOML).unwrap().apply(&mut cmd).unwrap();
+        assert_eq!(cmd.log_level, "debug");
+        assert_eq!(cmd.iface, ["eth1", "eth2"]);
+        assert!(cmd.conntrack);
+        assert_eq!(cmd.vips, [StaticVip::from_str("web=10.0.0.1:80").unwrap()]);
+        assert_eq!(cmd.namespaces, ["default"]);
+        assert!(cmd.opt_in);
+        assert_eq!(cmd.src_label, SrcLabelMode::Prefix);
+        assert_eq!(cmd.src_prefix_v4, 16);
+        assert_eq!(cmd.grpc_port, 9090);
+        assert_eq!(cmd.sample_rate, 0.5);
+        // keys not in the file keep the flags
+        assert_eq!(cmd.xdp_mode, self::cmd().xdp_mode);
+        assert_eq!(cmd.port, self::cmd().port);
+
+        for invalid in [
+            "vips: {static: [10.0.0.1]}",
+            "labels: {src-prefix-v4: 33}",
+            "labels: {src-prefix-v6: 129}",
+            "labels: {src-groups: [invalid]}",
+        ] {
+            let config = parse("config.yaml", invalid).unwrap();
+            assert!(config.apply(&mut self::cmd()).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_config_live() {
+        let base = LiveConfig::from_cmd(&cmd());
+        let empty = parse("config.yaml", "").unwrap();
+        assert_eq!(empty.live(&base), base);
+
+        let live = parse("config.yaml", YAML).unwrap().live(&base);
+        assert_eq!(
+            live,
+            LiveConfig {
+                log_level: "debug".to_string(),
+                filter: ServiceFilter {
+                    namespaces: vec!["default".to_string()],
+                    opt_in: true,
+                    ..base.filter.clone()
+                },
+                sample_rate: 0.5,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fixed_config_validate() {
+        let mut cmd = cmd();
+        let live = parse("config.yaml", "filter: {track-local-policy: true}")
+            .unwrap()
+            .live(&LiveConfig::from_cmd(&cmd));
+
+        cmd.node_name = String::new();
+        assert!(FixedConfig::from_cmd(&cmd).validate(&live).is_err());
+        assert!(FixedConfig::from_cmd(&cmd)
+            .validate(&LiveConfig::from_cmd(&cmd))
+            .is_ok());
+
+        cmd.vip_source = VipSourceKind::Static;
+        assert!(FixedConfig::from_cmd(&cmd).validate(&live).is_ok());
+
+        cmd.vip_source = VipSourceKind::Kubernetes;
+        cmd.node_name = "node1".to_string();
+        assert!(FixedConfig::from_cmd(&cmd).validate(&live).is_ok());
+    }
+
+    #[test]
+    fn test_config_restart_required() {
+        let started = parse("config.yaml", YAML).unwrap();
+        assert!(parse("config.toml", TOML)
+            .unwrap()
+            .restart_required(&started)
+            .is_empty());
+
+        // live keys are applied without a restart
+        let live_changed = YAML
+            .replace("log-level: debug", "log-level: warn")
+            .replace("namespaces: [default]", "namespaces: [other]")
+            .replace("rate: 0.5", "rate: 0.1");
+        let config = parse("config.yaml", &live_changed).unwrap();
+        assert!(config.restart_required(&started).is_empty());
+
+        // changed, added and removed keys
+        let changed = YAML
+            .replace("src-prefix-v4: 16", "src-prefix-v4: 24")
+            .replace("conntrack: true\n", "node-name: node1\n");
+        let config = parse("config.yaml", &changed).unwrap();
+        assert_eq!(
+            config.restart_required(&started),
+            ["conntrack", "labels.src-prefix-v4", "node-name"]
+        );
+    }
+
+    #[test]
+    fn test_flatten() {
+        let value = serde_json::json!({
+            "log-level": "info",
+            "labels": {"src": "prefix", "src-groups": ["a=10.0.0.0/8"]},
+            "exporters": {},
+        });
+        let mut keys = BTreeMap::new();
+        flatten("", &value, &mut keys);
+        assert_eq!(
+            keys,
+            BTreeMap::from([
+                ("labels.src".to_string(), serde_json::json!("prefix")),
+                (
+                    "labels.src-groups".to_string(),
+                    serde_json::json!(["a=10.0.0.0/8"])
+                ),
+                ("log-level".to_string(), serde_json::json!("info")),
+            ])
+        );
+    }
+}
@@ -0,0 +1,665 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use netlink_sys::{protocols::NETLINK_NETFILTER, AsyncSocket, AsyncSocketExt, TokioSocket};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::broadcast;
+
+use crate::{error::Error, range::VipRanges, supervisor::Task, vip::VipTable};
+
+// multicast group for new conntrack entries
+const NFNLGRP_CONNTRACK_NEW: u32 = 1;
+
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_NEW: u16 = 0;
+
+const NLMSG_HDR_LEN: usize = 16;
+const NFGEN_MSG_LEN: usize = 4;
+const NLA_HDR_LEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_IP_V6_SRC: u16 = 3;
+const CTA_IP_V6_DST: u16 = 4;
+const CTA_PROTO_NUM: u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+
+const IPPROTO_TCP: u8 = 6;
+
+// the number of new entries kept for slow subscribers
+const INSERTED_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tuple {
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConntrackEntry {
+    pub orig: Tuple,
+    pub reply: Tuple,
+}
+
+/// ConntrackTable keeps recently created TCP conntrack entries keyed by the original tuple.
+///
+/// XDP sees a SYN before netfilter creates its conntrack entry,
+/// so original tuples of new entries are also sent to subscribers waiting for them.
+#[derive(Debug)]
+pub struct ConntrackTable {
+    entries: Mutex<Entries>,
+    inserted: broadcast::Sender<Tuple>,
+    ttl: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    // original tuple to (reply tuple, created)
+    map: HashMap<Tuple, (Tuple, Instant)>,
+    // original tuples in order of creation to expire the oldest entries first
+    created: VecDeque<(Instant, Tuple)>,
+}
+
+impl ConntrackTable {
+    pub fn new(ttl: Duration) -> Self {
+        let (inserted, _) = broadcast::channel(INSERTED_CAPACITY);
+        ConntrackTable {
+            entries: Mutex::new(Entries::default()),
+            inserted,
+            ttl,
+        }
+    }
+
+    pub fn insert(&self, entry: ConntrackEntry) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        while let Some((created, orig)) = entries.created.front().copied() {
+            if now.duration_since(created) < self.ttl {
+                break;
+            }
+            entries.created.pop_front();
+            // the tuple may be reused by a newer entry
+            if matches!(entries.map.get(&orig), Some((_, c)) if *c == created) {
+                entries.map.remove(&orig);
+            }
+        }
+        entries.map.insert(entry.orig, (entry.reply, now));
+        entries.created.push_back((now, entry.orig));
+        drop(entries);
+        // no subscribers is not an error
+        let _ = self.inserted.send(entry.orig);
+    }
+
+    /// Subscribe original tuples of entries inserted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Tuple> {
+        self.inserted.subscribe()
+    }
+
+    /// Return the reply tuple of the connection.
+    pub fn lookup(&self, orig: &Tuple) -> Option<Tuple> {
+        self.entries
+            .lock()
+            .map
+            .get(orig)
+            .filter(|(_, created)| created.elapsed() < self.ttl)
+            .map(|(reply, _)| *reply)
+    }
+}
+
+/// ConntrackWatcher subscribes new conntrack entries via ctnetlink.
+///
+/// Only entries of connections to tracked VIPs and VIP ranges are kept in the table.
+pub struct ConntrackWatcher {
+    table: Arc<ConntrackTable>,
+    vips: Arc<RwLock<VipTable>>,
+    vip_ranges: Arc<Mutex<VipRanges>>,
+}
+
+impl ConntrackWatcher {
+    pub fn new(
+        table: Arc<ConntrackTable>,
+        vips: Arc<RwLock<VipTable>>,
+        vip_ranges: Arc<Mutex<VipRanges>>,
+    ) -> Self {
+        ConntrackWatcher {
+            table,
+            vips,
+            vip_ranges,
+        }
+    }
+
+    fn is_tracked(&self, addr: &IpAddr) -> bool {
+        if self.vips.read().contains(addr) {
+            return true;
+        }
+        match addr {
+            IpAddr::V4(addr) => self.vip_ranges.lock().contains(*addr),
+            IpAddr::V6(_) => false,
+        }
+    }
+}
+
+impl Task for ConntrackWatcher {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut sock = TokioSocket::new(NETLINK_NETFILTER).map_err(Error::StdIo)?;
+        sock.socket_mut().bind_auto().map_err(Error::StdIo)?;
+        sock.socket_ref()
+            .add_membership(NFNLGRP_CONNTRACK_NEW)
+            .map_err(Error::StdIo)?;
+
+        tracing::info!("Start conntrack watcher");
+        loop {
+            let (buf, _) = sock.recv_from_full().await.map_err(Error::StdIo)?;
+            for entry in parse_messages(&buf).into_iter() {
+                if self.is_tracked(&entry.orig.dst_addr) {
+                    self.table.insert(entry);
+                }
+            }
+        }
+    }
+}
+
+/// Parse netlink messages and return TCP conntrack entries in them.
+pub fn parse_messages(buf: &[u8]) -> Vec<ConntrackEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDR_LEN <= buf.len() {
+        let len = u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(buf[offset + 4..offset + 6].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || offset + len > buf.len() {
+            break;
+        }
+        if msg_type == (NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_NEW {
+            let payload = &buf[offset + NLMSG_HDR_LEN..offset + len];
+            if payload.len() >= NFGEN_MSG_LEN {
+                if let Some(entry) = parse_entry(&payload[NFGEN_MSG_LEN..]) {
+                    entries.push(entry);
+                }
+            }
+        }
+        offset += align(len);
+    }
+    entries
+}
+
+fn parse_entry(attrs: &[u8]) -> Option<ConntrackEntry> {
+    let mut orig = None;
+    let mut reply = None;
+    for (kind, value) in Attrs(attrs) {
+        match kind {
+            CTA_TUPLE_ORIG => orig = parse_tuple(value),
+            CTA_TUPLE_REPLY => reply = parse_tuple(value),
+            _ => {}
+        }
+    }
+    Some(ConntrackEntry {
+        orig: orig?,
+        reply: reply?,
+    })
+}
+
+fn parse_tuple(attrs: &[u8]) -> Option<Tuple> {
+    let mut src_addr = None;
+    let mut dst_addr = None;
+    let mut proto = None;
+    let mut src_port = None;
+    let mut dst_port = None;
+    for (kind, value) in Attrs(attrs) {
+        match kind {
+            CTA_TUPLE_IP => {
+                for (kind, value) in Attrs(value) {
+                    match kind {
+                        CTA_IP_V4_SRC => src_addr = parse_v4(value),
+                        CTA_IP_V4_DST => dst_addr = parse_v4(value),
+                        CTA_IP_V6_SRC => src_addr = parse_v6(value),
+                        CTA_IP_V6_DST => dst_addr = parse_v6(value),
+                        _ => {}
+                    }
+                }
+            }
+            CTA_TUPLE_PROTO => {
+                for (kind, value) in Attrs(value) {
+                    match kind {
+                        CTA_PROTO_NUM => proto = value.first().copied(),
+                        CTA_PROTO_SRC_PORT => src_port = parse_port(value),
+                        CTA_PROTO_DST_PORT => dst_port = parse_port(value),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if proto? != IPPROTO_TCP {
+        return None;
+    }
+    Some(Tuple {
+        src_addr: src_addr?,
+        src_port: src_port?,
+        dst_addr: dst_addr?,
+        dst_port: dst_port?,
+    })
+}
+
+fn parse_v4(value: &[u8]) -> Option<IpAddr> {
+    let octets: [u8; 4] = value.get(..4)?.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_v6(value: &[u8]) -> Option<IpAddr> {
+    let octets: [u8; 16] = value.get(..16)?.try_into().ok()?;
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+fn parse_port(value: &[u8]) -> Option<u16> {
+    // ports are in network byte order
+    Some(u16::from_be_bytes(value.get(..2)?.try_into().ok()?))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// Iterator over netlink attributes yielding (type, value)
+struct Attrs<'a>(&'a [u8]);
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.len() < NLA_HDR_LEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([self.0[0], self.0[1]]) as usize;
+        let kind = u16::from_ne_bytes([self.0[2], self.0[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDR_LEN || len > self.0.len() {
+            return None;
+        }
+        let value = &self.0[NLA_HDR_LEN..len];
+        self.0 = &self.0[align(len).min(self.0.len())..];
+        Some((kind, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NLA_F_NESTED: u16 = 0x8000;
+    const IPCTNL_MSG_CT_DELETE: u16 = 2;
+
+    fn nla(kind: u16, value: &[u8]) -> Vec<u8> {
+        let len = NLA_HDR_LEN + value.len();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(value);
+        buf.resize(align(len), 0);
+        buf
+    }
+
+    fn nested(kind: u16, attrs: &[Vec<u8>]) -> Vec<u8> {
+        nla(kind | NLA_F_NESTED, &attrs.concat())
+    }
+
+    fn message(msg_type: u16, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let attrs = attrs.concat();
+        let len = NLMSG_HDR_LEN + NFGEN_MSG_LEN + attrs.len();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&((NFNL_SUBSYS_CTNETLINK << 8) | msg_type).to_ne_bytes());
+        // flags, sequence number and port id
+        buf.extend_from_slice(&[0; 10]);
+        // nfgenmsg with AF_INET
+        buf.extend_from_slice(&[2, 0, 0, 0]);
+        buf.extend_from_slice(&attrs);
+        buf
+    }
+
+    fn tuple(kind: u16, src: &str, src_port: u16, dst: &str, dst_port: u16, proto: u8) -> Vec<u8> {
+        let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+        let ip = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => vec![
+                nla(CTA_IP_V4_SRC, &src.octets()),
+                nla(CTA_IP_V4_DST, &dst.octets()),
+            ],
+            (IpAddr::V6(src), IpAddr::V6(dst)) => vec![
+                nla(CTA_IP_V6_SRC, &src.octets()),
+                nla(CTA_IP_V6_DST, &dst.octets()),
+            ],
+            _ => unreachable!(),
+        };
+        nested(
+            kind,
+            &[
+                nested(CTA_TUPLE_IP, &ip),
+                nested(
+                    CTA_TUPLE_PROTO,
+                    &[
+                        nla(CTA_PROTO_NUM, &[proto]),
+                        nla(CTA_PROTO_SRC_PORT, &src_port.to_be_bytes()),
+                        nla(CTA_PROTO_DST_PORT, &dst_port.to_be_bytes()),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    fn entry(
+        src: &str,
+        src_port: u16,
+        dst: &str,
+        dst_port: u16,
+        backend: &str,
+        backend_port: u16,
+    ) -> ConntrackEntry {
+        ConntrackEntry {
+            orig: Tuple {
+                src_addr: src.parse().unwrap(),
+                src_port,
+                dst_addr: dst.parse().unwrap(),
+                dst_port,
+            },
+            reply: Tuple {
+                src_addr: backend.parse().unwrap(),
+                src_port: backend_port,
+                dst_addr: src.parse().unwrap(),
+                dst_port: src_port,
+            },
+        }
+    }
+
+    // A NEW event of a DNATed connection 192.168.0.2:60618 -> 10.0.10.0:80 to 10.244.1.5:8080
+    // as sent by the kernel on a little endian host.
+    #[cfg(target_endian = "little")]
+    const CT_NEW: [u8; 188] = [
+        // nlmsghdr: length 188, type IPCTNL_MSG_CT_NEW, flags NLM_F_CREATE|NLM_F_EXCL
+        0xbc, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, // nfgenmsg: AF_INET
+        0x02, 0x00, 0x00, 0x00, // CTA_TUPLE_ORIG
+        0x34, 0x00, 0x01, 0x80, // CTA_TUPLE_IP: 192.168.0.2 -> 10.0.10.0
+        0x14, 0x00, 0x01, 0x80, 0x08, 0x00, 0x01, 0x00, 0xc0, 0xa8, 0x00, 0x02, 0x08, 0x00, 0x02,
+        0x00, 0x0a, 0x00, 0x0a, 0x00, // CTA_TUPLE_PROTO: TCP 60618 -> 80
+        0x1c, 0x00, 0x02, 0x80, 0x05, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, 0x06, 0x00, 0x02,
+        0x00, 0xec, 0xca, 0x00, 0x00, 0x06, 0x00, 0x03, 0x00, 0x00, 0x50, 0x00, 0x00,
+        // CTA_TUPLE_REPLY
+        0x34, 0x00, 0x02, 0x80, // CTA_TUPLE_IP: 10.244.1.5 -> 192.168.0.2
+        0x14, 0x00, 0x01, 0x80, 0x08, 0x00, 0x01, 0x00, 0x0a, 0xf4, 0x01, 0x05, 0x08, 0x00, 0x02,
+        0x00, 0xc0, 0xa8, 0x00, 0x02, // CTA_TUPLE_PROTO: TCP 8080 -> 60618
+        0x1c, 0x00, 0x02, 0x80, 0x05, 0x00, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, 0x06, 0x00, 0x02,
+        0x00, 0x1f, 0x90, 0x00, 0x00, 0x06, 0x00, 0x03, 0x00, 0xec, 0xca, 0x00, 0x00,
+        // CTA_PROTOINFO: CTA_PROTOINFO_TCP with SYN_SENT state and flags
+        0x20, 0x00, 0x04, 0x80, 0x1c, 0x00, 0x01, 0x80, 0x05, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x06, 0x00, 0x04, 0x00, 0x03, 0x00, 0x00, 0x00, 0x06, 0x00, 0x05, 0x00, 0x00, 0x00,
+        0x00, 0x00, // CTA_STATUS
+        0x08, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x88, // CTA_TIMEOUT
+        0x08, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x78, // CTA_MARK
+        0x08, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // CTA_ID
+        0x08, 0x00, 0x0c, 0x00, 0x5d, 0x3c, 0x2a, 0x11,
+    ];
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn test_parse_ct_new() {
+        assert_eq!(
+            parse_messages(&CT_NEW),
+            vec![entry(
+                "192.168.0.2",
+                60618,
+                "10.0.10.0",
+                80,
+                "10.244.1.5",
+                8080
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let new = message(
+            IPCTNL_MSG_CT_NEW,
+            &[
+                tuple(
+                    CTA_TUPLE_ORIG,
+                    "192.168.0.2",
+                    60618,
+                    "10.0.10.0",
+                    80,
+                    IPPROTO_TCP,
+                ),
+                tuple(
+                    CTA_TUPLE_REPLY,
+                    "10.244.1.5",
+                    8080,
+                    "192.168.0.2",
+                    60618,
+                    IPPROTO_TCP,
+                ),
+            ],
+        );
+        let udp = message(
+            IPCTNL_MSG_CT_NEW,
+            &[
+                tuple(CTA_TUPLE_ORIG, "192.168.0.2", 5353, "10.0.10.0", 53, 17),
+                tuple(CTA_TUPLE_REPLY, "10.244.1.5", 53, "192.168.0.2", 5353, 17),
+            ],
+        );
+        let delete = message(
+            IPCTNL_MSG_CT_DELETE,
+            &[
+                tuple(
+                    CTA_TUPLE_ORIG,
+                    "192.168.0.3",
+                    60618,
+                    "10.0.10.0",
+                    80,
+                    IPPROTO_TCP,
+                ),
+                tuple(
+                    CTA_TUPLE_REPLY,
+                    "10.244.1.5",
+                    8080,
+                    "192.168.0.3",
+                    60618,
+                    IPPROTO_TCP,
+                ),
+            ],
+        );
+        // the reply tuple is required
+        let orig_only = message(
+            IPCTNL_MSG_CT_NEW,
+            &[tuple(
+                CTA_TUPLE_ORIG,
+                "192.168.0.4",
+                60618,
+                "10.0.10.0",
+                80,
+                IPPROTO_TCP,
+            )],
+        );
+        let buf = [new, udp, delete, orig_only].concat();
+        assert_eq!(
+            parse_messages(&buf),
+            vec![entry(
+                "192.168.0.2",
+                60618,
+                "10.0.10.0",
+                80,
+                "10.244.1.5",
+                8080
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_ipv6_tuple() {
+        let buf = message(
+            IPCTNL_MSG_CT_NEW,
+            &[
+                tuple(
+                    CTA_TUPLE_ORIG,
+                    "fd00::2",
+                    60618,
+                    "fd00:10::1",
+                    443,
+                    IPPROTO_TCP,
+                ),
+                tuple(
+                    CTA_TUPLE_REPLY,
+                    "fd00:244::5",
+                    8443,
+                    "fd00::2",
+                    60618,
+                    IPPROTO_TCP,
+                ),
+            ],
+        );
+        assert_eq!(
+            parse_messages(&buf),
+            vec![entry(
+                "fd00::2",
+                60618,
+                "fd00:10::1",
+                443,
+                "fd00:244::5",
+                8443
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_without_nested_flag() {
+        // older kernels don't set NLA_F_NESTED
+        let orig = tuple(
+            CTA_TUPLE_ORIG,
+            "192.168.0.2",
+            60618,
+            "10.0.10.0",
+            80,
+            IPPROTO_TCP,
+        );
+        let reply = tuple(
+            CTA_TUPLE_REPLY,
+            "10.244.1.5",
+            8080,
+            "192.168.0.2",
+            60618,
+            IPPROTO_TCP,
+        );
+        let mut buf = message(IPCTNL_MSG_CT_NEW, &[orig, reply]);
+        let mut offset = NLMSG_HDR_LEN + NFGEN_MSG_LEN;
+        while offset < buf.len() {
+            let len = u16::from_ne_bytes([buf[offset], buf[offset + 1]]) as usize;
+            let kind = u16::from_ne_bytes([buf[offset + 2], buf[offset + 3]]) & !NLA_F_NESTED;
+            buf[offset + 2..offset + 4].copy_from_slice(&kind.to_ne_bytes());
+            offset += align(len);
+        }
+        assert_eq!(
+            parse_messages(&buf),
+            vec![entry(
+                "192.168.0.2",
+                60618,
+                "10.0.10.0",
+                80,
+                "10.244.1.5",
+                8080
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let buf = message(
+            IPCTNL_MSG_CT_NEW,
+            &[
+                tuple(
+                    CTA_TUPLE_ORIG,
+                    "192.168.0.2",
+                    60618,
+                    "10.0.10.0",
+                    80,
+                    IPPROTO_TCP,
+                ),
+                tuple(
+                    CTA_TUPLE_REPLY,
+                    "10.244.1.5",
+                    8080,
+                    "192.168.0.2",
+                    60618,
+                    IPPROTO_TCP,
+                ),
+            ],
+        );
+        // the message length exceeds the buffer
+        for len in 0..buf.len() {
+            assert_eq!(parse_messages(&buf[..len]), vec![]);
+        }
+
+        // the message is complete but the attributes are cut
+        for cut in 1..buf.len() - NLMSG_HDR_LEN - NFGEN_MSG_LEN {
+            let mut truncated = buf[..buf.len() - cut].to_vec();
+            let len = truncated.len() as u32;
+            truncated[0..4].copy_from_slice(&len.to_ne_bytes());
+            assert_eq!(parse_messages(&truncated), vec![]);
+        }
+
+        // a message length shorter than the header stops parsing
+        let mut short = buf.clone();
+        short[0..4].copy_from_slice(&4u32.to_ne_bytes());
+        assert_eq!(parse_messages(&short), vec![]);
+    }
+
+    #[test]
+    fn test_attrs_misaligned() {
+        // the last attribute is not padded to 4 bytes
+        let buf = [nla(CTA_PROTO_SRC_PORT, &[0x00, 0x50]), vec![5, 0, 1, 0, 6]].concat();
+        assert_eq!(
+            Attrs(&buf).collect::<Vec<_>>(),
+            vec![
+                (CTA_PROTO_SRC_PORT, &[0x00, 0x50][..]),
+                (CTA_PROTO_NUM, &[6][..])
+            ]
+        );
+
+        // an attribute shorter than its header or longer than the rest stops iteration
+        let buf = [nla(CTA_PROTO_NUM, &[6]), vec![2, 0, 2, 0, 0, 0, 0, 0]].concat();
+        assert_eq!(Attrs(&buf).count(), 1);
+        let buf = [nla(CTA_PROTO_NUM, &[6]), vec![16, 0, 2, 0, 0, 0, 0, 0]].concat();
+        assert_eq!(Attrs(&buf).count(), 1);
+        assert_eq!(Attrs(&[8, 0, 1]).count(), 0);
+
+        // values shorter than an address or a port are ignored
+        let tuple = [
+            nested(
+                CTA_TUPLE_IP,
+                &[
+                    nla(CTA_IP_V4_SRC, &[192, 168]),
+                    nla(CTA_IP_V4_DST, &[10, 0, 10, 0]),
+                ],
+            ),
+            nested(
+                CTA_TUPLE_PROTO,
+                &[
+                    nla(CTA_PROTO_NUM, &[IPPROTO_TCP]),
+                    nla(CTA_PROTO_SRC_PORT, &[0xec]),
+                    nla(CTA_PROTO_DST_PORT, &[0x00, 0x50]),
+                ],
+            ),
+        ]
+        .concat();
+        assert_eq!(parse_tuple(&tuple), None);
+    }
+}
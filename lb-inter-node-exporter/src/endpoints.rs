@@ -0,0 +1,403 @@
+use std::{collections::HashMap, net::IpAddr, pin::pin, str::FromStr, sync::Arc};
+
+use futures::TryStreamExt;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::{
+    runtime::{watcher, WatchStreamExt},
+    Client, ResourceExt,
+};
+use parking_lot::RwLock;
+use tokio::sync::watch;
+
+use crate::{
+    error::Error,
+    kubernetes::{ServiceFilter, TrafficPolicy},
+    supervisor::Task,
+    trace::AgentMetrics,
+    vip::ServiceRef,
+};
+
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub addr: IpAddr,
+    pub node: Option<String>,
+    pub pod: Option<String>,
+    pub ready: bool,
+}
+
+/// Delivery is how the node delivers a connection it received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    // served by a backend on this node
+    Local,
+    // forwarded to a backend on another node
+    Forwarded,
+    // no backend to receive the connection
+    Dropped,
+    // the connection may go either way
+    Unknown,
+}
+
+impl Delivery {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Delivery::Local => "local",
+            Delivery::Forwarded => "forwarded",
+            Delivery::Dropped => "dropped",
+            Delivery::Unknown => "unknown",
+        }
+    }
+}
+
+/// EndpointStore keeps endpoints of each Service built from EndpointSlices.
+///
+/// Until the initial list of EndpointSlices is received, no endpoints are known and
+/// connections are classified as Unknown instead of Dropped.
+#[derive(Debug, Default)]
+pub struct EndpointStore {
+    // key is (Service, EndpointSlice name)
+    slices: HashMap<(ServiceRef, String), Vec<Endpoint>>,
+    synced: bool,
+}
+
+impl EndpointStore {
+    pub fn apply(&mut self, slice: &EndpointSlice) {
+        let Some(key) = slice_key(slice) else {
+            return;
+        };
+        self.slices.insert(key, get_endpoints(slice));
+    }
+
+    pub fn delete(&mut self, slice: &EndpointSlice) {
+        if let Some(key) = slice_key(slice) {
+            self.slices.remove(&key);
+        }
+    }
+
+    pub fn restart(&mut self, slices: &[EndpointSlice]) {
+        self.slices.clear();
+        for slice in slices.iter() {
+            self.apply(slice);
+        }
+        self.synced = true;
+    }
+
+    pub fn endpoints<'a>(&'a self, svc: &'a ServiceRef) -> impl Iterator<Item = &'a Endpoint> {
+        self.slices
+            .iter()
+            .filter(move |((s, _), _)| s == svc)
+            .flat_map(|(_, eps)| eps.iter())
+    }
+
+    /// Find the endpoint that has the address.
+    pub fn find(&self, addr: &IpAddr) -> Option<&Endpoint> {
+        self.slices
+            .values()
+            .flat_map(|eps| eps.iter())
+            .find(|ep| ep.addr == *addr)
+    }
+
+    /// The probability that a connection to the Service is served on the given node
+    /// when kube-proxy picks one of ready endpoints uniformly.
+    pub fn expected_local_ratio(
+        &self,
+        svc: &ServiceRef,
+        policy: TrafficPolicy,
+        node: &str,
+    ) -> Option<f64> {
+        if !self.synced {
+            return None;
+        }
+        let (local, total) = self.count_ready(svc, node);
+        if total == 0 {
+            return None;
+        }
+        match policy {
+            TrafficPolicy::Cluster => Some(local as f64 / total as f64),
+            TrafficPolicy::Local => Some(if local > 0 { 1.0 } else { 0.0 }),
+        }
+    }
+
+    /// Classify the connection to the Service received on the given node from its endpoints.
+    ///
+    /// This is the fallback when the backend is not identified by conntrack, i.e. `--conntrack` is off
+    /// or the conntrack entry was not found in time. A Cluster Service with ready endpoints on this node
+    /// and others is Unknown then, and `expected_local_ratio` tells how its connections are split.
+    pub fn delivery(&self, svc: &ServiceRef, policy: TrafficPolicy, node: &str) -> Delivery {
+        if node.is_empty() || !self.synced {
+            return Delivery::Unknown;
+        }
+        let (local, total) = self.count_ready(svc, node);
+        match policy {
+            TrafficPolicy::Cluster => {
+                if total == 0 {
+                    Delivery::Dropped
+                } else if local == total {
+                    Delivery::Local
+                } else if local == 0 {
+                    Delivery::Forwarded
+                } else {
+                    Delivery::Unknown
+                }
+            }
+            TrafficPolicy::Local => {
+                if local > 0 {
+                    Delivery::Local
+                } else {
+                    Delivery::Dropped
+                }
+            }
+        }
+    }
+
+    // count ready endpoints on the node and in total
+    fn count_ready(&self, svc: &ServiceRef, node: &str) -> (usize, usize) {
+        self.endpoints(svc)
+            .filter(|ep| ep.ready)
+            .fold((0, 0), |(local, total), ep| {
+                if ep.node.as_deref() == Some(node) {
+                    (local + 1, total + 1)
+                } else {
+                    (local, total + 1)
+                }
+            })
+    }
+}
+
+pub struct EndpointWatcher {
+    client: Client,
+    // replaced on reloading the config file
+    filter: watch::Receiver<ServiceFilter>,
+    store: Arc<RwLock<EndpointStore>>,
+    metrics: AgentMetrics,
+}
+
+impl EndpointWatcher {
+    pub async fn new(
+        store: Arc<RwLock<EndpointStore>>,
+        filter: watch::Receiver<ServiceFilter>,
+        metrics: AgentMetrics,
+    ) -> Result<Self, Error> {
+        let client = Client::try_default().await.map_err(Error::Kube)?;
+        Ok(EndpointWatcher {
+            client,
+            filter,
+            store,
+            metrics,
+        })
+    }
+}
+
+impl Task for EndpointWatcher {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let filter = self.filter.borrow_and_update().clone();
+            let slice_api = filter.api::<EndpointSlice>(self.client.clone());
+            let mut watcher_config = watcher::Config::default().labels(SERVICE_NAME_LABEL);
+            if let Some(fields) = filter.namespace_fields() {
+                watcher_config = watcher_config.fields(&fields);
+            }
+            let slice_events = watcher(slice_api, watcher_config).default_backoff();
+            let mut slice_events = pin!(slice_events);
+
+            tracing::info!("Start EndpointSlice watcher");
+            loop {
+                let event = tokio::select! {
+                    event = slice_events.try_next() => event.map_err(Error::KubeWatcher)?,
+                    Ok(()) = self.filter.changed() => break,
+                };
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                self.metrics.watcher_event("endpointslice");
+                let mut store = self.store.write();
+                match event {
+                    watcher::Event::Applied(slice) => store.apply(&slice),
+                    watcher::Event::Deleted(slice) => store.delete(&slice),
+                    watcher::Event::Restarted(slices) => {
+                        self.metrics.watcher_restarted("endpointslice");
+                        store.restart(&slices)
+                    }
+                }
+            }
+            tracing::info!("Restart EndpointSlice watcher to apply the new filter");
+        }
+    }
+}
+
+fn slice_key(slice: &EndpointSlice) -> Option<(ServiceRef, String)> {
+    let name = slice.labels().get(SERVICE_NAME_LABEL)?;
+    Some((
+        ServiceRef {
+            namespace: slice.namespace().unwrap_or_default(),
+            name: name.clone(),
+        },
+        slice.name_any(),
+    ))
+}
+
+fn get_endpoints(slice: &EndpointSlice) -> Vec<Endpoint> {
+    let mut endpoints = Vec::new();
+    for ep in slice.endpoints.iter() {
+        // A nil ready condition should be interpreted as ready.
+        let ready = ep.conditions.as_ref().and_then(|c| c.ready).unwrap_or(true);
+        let pod = ep
+            .target_ref
+            .as_ref()
+            .filter(|r| r.kind.as_deref() == Some("Pod"))
+            .and_then(|r| r.name.clone());
+        for addr in ep.addresses.iter() {
+            if let Ok(addr) = IpAddr::from_str(addr) {
+                endpoints.push(Endpoint {
+                    addr,
+                    node: ep.node_name.clone(),
+                    pod: pod.clone(),
+                    ready,
+                });
+            }
+        }
+    }
+    endpoints
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::{
+        core::v1::ObjectReference,
+        discovery::v1::{Endpoint as SliceEndpoint, EndpointConditions},
+    };
+    use kube::api::ObjectMeta;
+
+    use super::*;
+
+    fn svc() -> ServiceRef {
+        ServiceRef {
+            namespace: "test".to_string(),
+            name: "app".to_string(),
+        }
+    }
+
+    // endpoints are given as (address, node, ready)
+    fn slice(name: &str, endpoints: &[(&str, &str, bool)]) -> EndpointSlice {
+        EndpointSlice {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("test".to_string()),
+                labels: Some([(SERVICE_NAME_LABEL.to_string(), "app".to_string())].into()),
+                ..Default::default()
+            },
+            address_type: "IPv4".to_string(),
+            endpoints: endpoints
+                .iter()
+                .map(|(addr, node, ready)| SliceEndpoint {
+                    addresses: vec![addr.to_string()],
+                    node_name: Some(node.to_string()),
+                    conditions: Some(EndpointConditions {
+                        ready: Some(*ready),
+                        ..Default::default()
+                    }),
+                    target_ref: Some(ObjectReference {
+                        kind: Some("Pod".to_string()),
+                        name: Some(format!("pod-{addr}")),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ports: None,
+        }
+    }
+
+    #[test]
+    fn test_store_apply_delete_restart() {
+        let mut store = EndpointStore::default();
+        store.restart(&[]);
+        store.apply(&slice("app-a", &[("10.1.0.1", "node1", true)]));
+        store.apply(&slice("app-b", &[("10.1.0.2", "node2", false)]));
+        assert_eq!(store.endpoints(&svc()).count(), 2);
+        assert_eq!(
+            store.find(&"10.1.0.1".parse().unwrap()).unwrap().pod,
+            Some("pod-10.1.0.1".to_string())
+        );
+        assert_eq!(store.count_ready(&svc(), "node1"), (1, 1));
+
+        // apply replaces the endpoints of the slice
+        store.apply(&slice("app-a", &[("10.1.0.3", "node2", true)]));
+        assert!(store.find(&"10.1.0.1".parse().unwrap()).is_none());
+        assert_eq!(store.count_ready(&svc(), "node1"), (0, 1));
+
+        store.delete(&slice("app-a", &[]));
+        assert_eq!(store.endpoints(&svc()).count(), 1);
+
+        // restart drops slices not listed
+        store.restart(&[slice("app-c", &[("10.1.0.4", "node1", true)])]);
+        assert_eq!(store.endpoints(&svc()).count(), 1);
+        assert_eq!(store.count_ready(&svc(), "node1"), (1, 1));
+    }
+
+    #[test]
+    fn test_store_delivery() {
+        let mut store = EndpointStore::default();
+        store.apply(&slice("app-a", &[("10.1.0.1", "node2", true)]));
+        // not synced yet
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Local, "node1"),
+            Delivery::Unknown
+        );
+        assert_eq!(
+            store.expected_local_ratio(&svc(), TrafficPolicy::Cluster, "node1"),
+            None
+        );
+
+        store.restart(&[]);
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Cluster, "node1"),
+            Delivery::Dropped
+        );
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Local, "node1"),
+            Delivery::Dropped
+        );
+
+        store.apply(&slice(
+            "app-a",
+            &[("10.1.0.1", "node2", true), ("10.1.0.2", "node1", false)],
+        ));
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Cluster, "node1"),
+            Delivery::Forwarded
+        );
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Cluster, "node2"),
+            Delivery::Local
+        );
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Cluster, ""),
+            Delivery::Unknown
+        );
+
+        store.apply(&slice(
+            "app-b",
+            &[("10.1.0.3", "node1", true), ("10.1.0.4", "node3", true)],
+        ));
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Cluster, "node1"),
+            Delivery::Unknown
+        );
+        assert_eq!(
+            store.expected_local_ratio(&svc(), TrafficPolicy::Cluster, "node1"),
+            Some(1.0 / 3.0)
+        );
+        assert_eq!(
+            store.delivery(&svc(), TrafficPolicy::Local, "node1"),
+            Delivery::Local
+        );
+        assert_eq!(
+            store.expected_local_ratio(&svc(), TrafficPolicy::Local, "node1"),
+            Some(1.0)
+        );
+    }
+}
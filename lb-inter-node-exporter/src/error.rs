@@ -19,4 +19,47 @@ pub enum Error {
 
     #[error("Failed to get eBPF Map: {0}")]
     FailedGetEBPFMap(String),
+
+    #[error("gRPC transport error: {0}")]
+    Grpc(#[source] tonic::transport::Error),
+
+    #[error("channel closed: {0}")]
+    ChannelClosed(String),
+
+    #[error("invalid VIP list: {0}")]
+    VipList(#[source] serde_yaml::Error),
+
+    #[error("invalid config file: {0}")]
+    Config(String),
+
+    #[error("OTLP export error: {0}")]
+    Otlp(String),
+
+    #[error("IPFIX export error: {0}")]
+    Ipfix(String),
+}
+
+impl Error {
+    /// Whether restarting the task can't recover from the error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::FailedGetEBPFMap(_) | Error::ChannelClosed(_))
+    }
+
+    /// The short reason of the error used as a label value.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::Regex(_) => "regex",
+            Error::StdIo(_) => "io",
+            Error::Netlink(_) => "netlink",
+            Error::Kube(_) => "kube",
+            Error::KubeWatcher(_) => "kube_watcher",
+            Error::FailedGetEBPFMap(_) => "ebpf_map",
+            Error::Grpc(_) => "grpc",
+            Error::ChannelClosed(_) => "channel_closed",
+            Error::VipList(_) => "vip_list",
+            Error::Config(_) => "config",
+            Error::Otlp(_) => "otlp",
+            Error::Ipfix(_) => "ipfix",
+        }
+    }
 }
@@ -0,0 +1,462 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aya::maps::{MapData, MapError, PerCpuArray, RingBuf};
+use lb_inter_node_exporter_common::Ipv4Event;
+use parking_lot::{Mutex, RwLock};
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{error::TrySendError, Receiver, Sender},
+        watch,
+    },
+};
+
+use crate::{
+    conntrack::{ConntrackTable, Tuple},
+    endpoints::{Delivery, EndpointStore},
+    error::Error,
+    feed::{ConnectionEvent, EventFeed},
+    health::Component,
+    kubernetes::{AddrSource, TrafficPolicy},
+    label::SrcLabeler,
+    supervisor::Task,
+    trace::{AgentMetrics, EventLabels, Metrics},
+    vip::{ServicePortRef, VipTable},
+};
+
+/// How long to wait for the conntrack entry of a received SYN.
+const CONNTRACK_WAIT: Duration = Duration::from_millis(50);
+/// The number of events read from the ring buffer and waiting to be processed.
+pub const EVENT_QUEUE_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone)]
+pub struct Backend {
+    pub addr: IpAddr,
+    pub port: u16,
+    pub pod: Option<String>,
+    pub node: Option<String>,
+}
+
+/// EventProcessor enriches events from the eBPF program and emits logs and metrics.
+///
+/// Events whose conntrack entries are not created yet wait in `pending` without blocking later events,
+/// and are processed when the entry is inserted or CONNTRACK_WAIT passes.
+pub struct EventProcessor {
+    pub vips: Arc<RwLock<VipTable>>,
+    pub endpoints: Arc<RwLock<EndpointStore>>,
+    pub conntrack: Option<Arc<ConntrackTable>>,
+    pub metrics: Metrics,
+    pub agent_metrics: AgentMetrics,
+    pub health: Component,
+    pub labeler: SrcLabeler,
+    pub node_name: String,
+    pub events: Receiver<(Ipv4Event, Received)>,
+    pub feed: EventFeed,
+    // ifindex to name of interfaces the XDP program is attached to
+    pub interfaces: HashMap<u32, String>,
+    // the fraction of events to process, replaced on reloading the config file
+    pub sample_rate: watch::Receiver<f64>,
+    pub sample_credit: f64,
+    pub pending: PendingEvents,
+}
+
+impl Task for EventProcessor {
+    async fn run(&mut self) -> Result<(), Error> {
+        let _alive = self.health.start();
+        let mut inserted = self.conntrack.as_ref().map(|c| c.subscribe());
+        // entries inserted while the task was restarting are not notified
+        self.resolve_all();
+        loop {
+            let deadline = self.pending.next_deadline();
+            tokio::select! {
+                event = self.events.recv() => {
+                    let Some((event, received)) = event else {
+                        return Err(Error::ChannelClosed("IPv4 events".to_string()));
+                    };
+                    self.health.begin();
+                    self.agent_metrics.event_queue_length(self.events.len());
+                    if self.sample() {
+                        self.receive(event, received);
+                    } else {
+                        self.agent_metrics.event_sampled_out();
+                    }
+                    self.health.done();
+                }
+                tuple = next_inserted(&mut inserted) => match tuple {
+                    Ok(tuple) => {
+                        if let Some((event, received)) = self.pending.take(&tuple) {
+                            self.complete(event, received);
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => self.resolve_all(),
+                    Err(RecvError::Closed) => {
+                        return Err(Error::ChannelClosed("conntrack entries".to_string()));
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    for (event, received) in self.pending.expire(Instant::now()).into_iter() {
+                        self.complete(event, received);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn next_inserted(
+    inserted: &mut Option<broadcast::Receiver<Tuple>>,
+) -> Result<Tuple, RecvError> {
+    match inserted {
+        Some(inserted) => inserted.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl EventProcessor {
+    // Pick events evenly at the sample rate, e.g. every other event at 0.5.
+    fn sample(&mut self) -> bool {
+        let rate = *self.sample_rate.borrow();
+        if rate >= 1.0 {
+            return true;
+        }
+        self.sample_credit += rate.max(0.0);
+        if self.sample_credit >= 1.0 {
+            self.sample_credit -= 1.0;
+            return true;
+        }
+        false
+    }
+
+    // Process the event now if its conntrack entry exists or conntrack is off,
+    // otherwise keep it until the entry is inserted.
+    fn receive(&mut self, ipv4_event: Ipv4Event, received: Received) {
+        let Some(conntrack) = self.conntrack.as_ref() else {
+            self.complete(ipv4_event, received);
+            return;
+        };
+        let orig = orig_tuple(&ipv4_event);
+        if conntrack.lookup(&orig).is_some() || self.pending.contains(&orig) {
+            // a retransmitted SYN doesn't wait behind the first one
+            self.complete(ipv4_event, received);
+            return;
+        }
+        self.pending
+            .push(orig, ipv4_event, received, received.at + CONNTRACK_WAIT);
+    }
+
+    // Look up pending events again when notifications of inserted entries are lost.
+    fn resolve_all(&mut self) {
+        let Some(conntrack) = self.conntrack.clone() else {
+            return;
+        };
+        for tuple in self.pending.tuples().into_iter() {
+            if conntrack.lookup(&tuple).is_some() {
+                if let Some((event, received)) = self.pending.take(&tuple) {
+                    self.complete(event, received);
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, ipv4_event: Ipv4Event, received: Received) {
+        self.process(ipv4_event, received);
+        self.agent_metrics.event_processed();
+    }
+
+    fn process(&mut self, ipv4_event: Ipv4Event, received: Received) {
+        let src_addr = u32_to_addr(ipv4_event.src_addr);
+        let dst_addr = u32_to_addr(ipv4_event.dst_addr);
+        let svc = self
+            .vips
+            .read()
+            .lookup(IpAddr::V4(dst_addr), ipv4_event.dst_port)
+            .cloned();
+        let source = svc.as_ref().map(|s| s.source.as_str()).unwrap_or("");
+
+        let backend = self.backend(&orig_tuple(&ipv4_event));
+
+        let (delivery, expected_local_ratio) = self.delivery(svc.as_ref(), backend.as_ref());
+        let (src_label, evicted) = self.labeler.label(IpAddr::V4(src_addr), received.at);
+        for src in evicted.iter() {
+            self.metrics.remove_src(src);
+        }
+        let ifname = self
+            .interfaces
+            .get(&ipv4_event.ifindex)
+            .cloned()
+            .unwrap_or_default();
+        let labels = EventLabels {
+            src: src_label,
+            dst: dst_addr.to_string(),
+            service: svc
+                .as_ref()
+                .map(|s| s.service.name.clone())
+                .unwrap_or_default(),
+            namespace: svc
+                .as_ref()
+                .map(|s| s.service.namespace.clone())
+                .unwrap_or_default(),
+            port_name: svc
+                .as_ref()
+                .and_then(|s| s.port_name.clone())
+                .unwrap_or_default(),
+            source: source.to_string(),
+            delivery: delivery.as_str().to_string(),
+            backend_pod: backend
+                .as_ref()
+                .and_then(|b| b.pod.clone())
+                .unwrap_or_default(),
+            backend_node: backend
+                .as_ref()
+                .and_then(|b| b.node.clone())
+                .unwrap_or_default(),
+        };
+
+        tracing::info!(src_addr=?src_addr, dst_addr=?dst_addr, src_port = ipv4_event.src_port, dst_port = ipv4_event.dst_port, service = labels.service, namespace = labels.namespace, port_name = labels.port_name, source, delivery = labels.delivery, backend_addr = ?backend.as_ref().map(|b| b.addr), backend_port = backend.as_ref().map(|b| b.port), backend_pod = labels.backend_pod, backend_node = labels.backend_node, ifname = ifname, "Received by intermediate node");
+        self.metrics.picked_total(&labels);
+        let mut misrouted = false;
+        if let Some(svc) = svc.as_ref() {
+            if let Some(ratio) = expected_local_ratio {
+                self.metrics.expected_local_ratio(&svc.service, ratio);
+            }
+            if svc.policy == TrafficPolicy::Local && delivery == Delivery::Dropped {
+                // externalTrafficPolicy=Local traffic must not reach a node without local endpoints
+                tracing::warn!(src_addr=?src_addr, dst_addr=?dst_addr, src_port = ipv4_event.src_port, dst_port = ipv4_event.dst_port, service = labels.service, namespace = labels.namespace, port_name = labels.port_name, source, "Misrouted to the node without local endpoints");
+                self.metrics.misrouted_total(&labels);
+                misrouted = true;
+            }
+        }
+
+        self.feed.publish(ConnectionEvent {
+            timestamp: received.timestamp,
+            src_addr: IpAddr::V4(src_addr),
+            src_port: ipv4_event.src_port,
+            dst_addr: IpAddr::V4(dst_addr),
+            dst_port: ipv4_event.dst_port,
+            service: svc.as_ref().map(|s| s.service.name.clone()),
+            namespace: svc.as_ref().map(|s| s.service.namespace.clone()),
+            port_name: svc.as_ref().and_then(|s| s.port_name.clone()),
+            source: svc.as_ref().map(|s| s.source.as_str().to_string()),
+            delivery: labels.delivery,
+            backend_addr: backend.as_ref().map(|b| b.addr),
+            backend_port: backend.as_ref().map(|b| b.port),
+            backend_pod: backend.as_ref().and_then(|b| b.pod.clone()),
+            backend_node: backend.as_ref().and_then(|b| b.node.clone()),
+            misrouted,
+            ifindex: ipv4_event.ifindex,
+            ifname: (!ifname.is_empty()).then_some(ifname),
+        });
+    }
+
+    // Find the backend chosen by kube-proxy from the reply tuple of the conntrack entry.
+    fn backend(&self, orig: &Tuple) -> Option<Backend> {
+        let conntrack = self.conntrack.as_ref()?;
+        let reply = conntrack.lookup(orig)?;
+        if reply.src_addr == orig.dst_addr {
+            // not DNATed
+            return None;
+        }
+        let endpoints = self.endpoints.read();
+        let ep = endpoints.find(&reply.src_addr);
+        Some(Backend {
+            addr: reply.src_addr,
+            port: reply.src_port,
+            pod: ep.and_then(|ep| ep.pod.clone()),
+            node: ep.and_then(|ep| ep.node.clone()),
+        })
+    }
+
+    fn delivery(
+        &self,
+        svc: Option<&ServicePortRef>,
+        backend: Option<&Backend>,
+    ) -> (Delivery, Option<f64>) {
+        let Some(svc) = svc else {
+            return (Delivery::Unknown, None);
+        };
+        if svc.source == AddrSource::Static {
+            // no endpoints are known without Kubernetes
+            return (Delivery::Unknown, None);
+        }
+        let endpoints = self.endpoints.read();
+        let expected = endpoints.expected_local_ratio(&svc.service, svc.policy, &self.node_name);
+        let delivery = match backend.and_then(|b| b.node.as_deref()) {
+            Some(node) if !self.node_name.is_empty() => {
+                if node == self.node_name {
+                    Delivery::Local
+                } else {
+                    Delivery::Forwarded
+                }
+            }
+            _ => endpoints.delivery(&svc.service, svc.policy, &self.node_name),
+        };
+        (delivery, expected)
+    }
+}
+
+fn orig_tuple(ipv4_event: &Ipv4Event) -> Tuple {
+    Tuple {
+        src_addr: IpAddr::V4(u32_to_addr(ipv4_event.src_addr)),
+        src_port: ipv4_event.src_port,
+        dst_addr: IpAddr::V4(u32_to_addr(ipv4_event.dst_addr)),
+        dst_port: ipv4_event.dst_port,
+    }
+}
+
+/// PendingEvents keeps events waiting for their conntrack entries until the deadline.
+#[derive(Debug, Default)]
+pub struct PendingEvents {
+    // keyed by the original tuple, with the id of the deadline
+    events: HashMap<Tuple, (u64, Ipv4Event, Received)>,
+    // deadlines in order of arrival, outdated ones are skipped by the id
+    deadlines: VecDeque<(Instant, Tuple, u64)>,
+    next_id: u64,
+}
+
+impl PendingEvents {
+    fn contains(&self, tuple: &Tuple) -> bool {
+        self.events.contains_key(tuple)
+    }
+
+    fn push(&mut self, tuple: Tuple, event: Ipv4Event, received: Received, deadline: Instant) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.events.insert(tuple, (id, event, received));
+        self.deadlines.push_back((deadline, tuple, id));
+    }
+
+    fn take(&mut self, tuple: &Tuple) -> Option<(Ipv4Event, Received)> {
+        self.events
+            .remove(tuple)
+            .map(|(_, event, received)| (event, received))
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.front().map(|(deadline, _, _)| *deadline)
+    }
+
+    // Remove events past their deadline.
+    fn expire(&mut self, now: Instant) -> Vec<(Ipv4Event, Received)> {
+        let mut expired = Vec::new();
+        while let Some((deadline, tuple, id)) = self.deadlines.front().copied() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop_front();
+            if matches!(self.events.get(&tuple), Some((i, _, _)) if *i == id) {
+                expired.extend(self.take(&tuple));
+            }
+        }
+        expired
+    }
+
+    fn tuples(&self) -> Vec<Tuple> {
+        self.events.keys().copied().collect()
+    }
+}
+
+/// Received is when the agent read an event from the ring buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Received {
+    pub at: Instant,
+    // the wall clock time in seconds since the unix epoch
+    pub timestamp: f64,
+}
+
+impl Received {
+    pub fn now() -> Self {
+        Received {
+            at: Instant::now(),
+            timestamp: ConnectionEvent::now(),
+        }
+    }
+}
+
+/// EventReader drains the ring buffer the eBPF program writes events to.
+pub struct EventReader {
+    ring_buf: AsyncFd<RingBuf<MapData>>,
+    events: Sender<(Ipv4Event, Received)>,
+    drops: Arc<Mutex<PerCpuArray<MapData, u64>>>,
+    // the count of dropped events already reported
+    last_drops: u64,
+    metrics: AgentMetrics,
+}
+
+impl EventReader {
+    pub fn new(
+        ring_buf: RingBuf<MapData>,
+        events: Sender<(Ipv4Event, Received)>,
+        drops: Arc<Mutex<PerCpuArray<MapData, u64>>>,
+        metrics: AgentMetrics,
+    ) -> Result<Self, Error> {
+        // SAFETY: the ring buffer owns its map fd, which stays open while AsyncFd holds it.
+        let ring_buf = unsafe { AsyncFd::register(ring_buf) }
+            .map_err(|e| Error::StdIo(std::io::Error::from(e)))?;
+        Ok(EventReader {
+            ring_buf,
+            events,
+            drops,
+            last_drops: 0,
+            metrics,
+        })
+    }
+
+    fn report_drops(&mut self) {
+        match event_drops(&self.drops.lock()) {
+            Ok(drops) => {
+                self.metrics
+                    .ringbuf_dropped_events(drops.saturating_sub(self.last_drops));
+                self.last_drops = drops;
+            }
+            Err(e) => {
+                tracing::error!(error=?e, "Failed to read the EVENT_DROPS map");
+                self.metrics.map_operation_failed("EVENT_DROPS", "lookup");
+            }
+        }
+    }
+}
+
+/// Return the count of events the eBPF program dropped because the ring buffer was full.
+pub fn event_drops(drops: &PerCpuArray<MapData, u64>) -> Result<u64, MapError> {
+    Ok(drops.get(&0, 0)?.iter().sum())
+}
+
+impl Task for EventReader {
+    async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let mut guard = self.ring_buf.readable_mut().await.map_err(Error::StdIo)?;
+            let ring_buf = guard.get_inner_mut();
+            let mut drained = 0;
+            while let Some(event) = ring_buf.next() {
+                let ipv4_event: Ipv4Event = (*event).into();
+                match self.events.try_send((ipv4_event, Received::now())) {
+                    Ok(()) => {}
+                    // drop events rather than growing the queue while the processor falls behind
+                    Err(TrySendError::Full(_)) => self.metrics.event_queue_dropped(),
+                    Err(TrySendError::Closed(_)) => {
+                        return Err(Error::ChannelClosed("IPv4 events".to_string()))
+                    }
+                }
+                drained += 1;
+            }
+            guard.clear_ready();
+            // the size of a batch, not the fill level of the ring buffer, which aya doesn't expose
+            self.metrics.ringbuf_drained(drained);
+            self.report_drops();
+        }
+    }
+}
+
+pub fn u32_to_addr(x: u32) -> Ipv4Addr {
+    let b1: u8 = ((x >> 24) & 0xff) as u8;
+    let b2: u8 = ((x >> 16) & 0xff) as u8;
+    let b3: u8 = ((x >> 8) & 0xff) as u8;
+    let b4: u8 = (x & 0xff) as u8;
+    Ipv4Addr::new(b1, b2, b3, b4)
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// events buffered for each slow subscriber before it lags
+const FEED_CAPACITY: usize = 1024;
+const DEFAULT_RECENT_EVENTS: usize = 1000;
+
+/// ConnectionEvent is a decoded event of a connection received by the node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionEvent {
+    // unix time in seconds
+    pub timestamp: f64,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+    pub service: Option<String>,
+    pub namespace: Option<String>,
+    pub port_name: Option<String>,
+    pub source: Option<String>,
+    pub delivery: String,
+    pub backend_addr: Option<IpAddr>,
+    pub backend_port: Option<u16>,
+    pub backend_pod: Option<String>,
+    pub backend_node: Option<String>,
+    pub misrouted: bool,
+    // the interface the connection was received on
+    pub ifindex: u32,
+    pub ifname: Option<String>,
+}
+
+impl ConnectionEvent {
+    pub fn now() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    /// Return the field used in filter expressions.
+    pub fn field(&self, key: &str) -> Option<String> {
+        match key {
+            "src" | "src_addr" => Some(self.src_addr.to_string()),
+            "src_port" => Some(self.src_port.to_string()),
+            "vip" | "dst" | "dst_addr" => Some(self.dst_addr.to_string()),
+            "port" | "dst_port" => Some(self.dst_port.to_string()),
+            "service" => self.service.clone(),
+            "namespace" => self.namespace.clone(),
+            "port_name" => self.port_name.clone(),
+            "source" => self.source.clone(),
+            "delivery" => Some(self.delivery.clone()),
+            "backend_addr" => self.backend_addr.map(|a| a.to_string()),
+            "backend_port" => self.backend_port.map(|p| p.to_string()),
+            "backend_pod" => self.backend_pod.clone(),
+            "backend_node" => self.backend_node.clone(),
+            "misrouted" => Some(self.misrouted.to_string()),
+            "ifindex" => Some(self.ifindex.to_string()),
+            "ifname" => self.ifname.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// EventFilter selects events by VIP, Service, source CIDR and port.
+///
+/// Service is given as `name` or `namespace/name`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventFilter {
+    pub vip: Option<IpAddr>,
+    pub service: Option<String>,
+    pub src: Option<IpNet>,
+    pub port: Option<u16>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &ConnectionEvent) -> bool {
+        if let Some(vip) = self.vip {
+            if event.dst_addr != vip {
+                return false;
+            }
+        }
+        if let Some(service) = self.service.as_deref() {
+            let name = event.service.as_deref().unwrap_or_default();
+            let matched = match service.split_once('/') {
+                Some((ns, svc)) => event.namespace.as_deref() == Some(ns) && name == svc,
+                None => name == service,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(src) = self.src {
+            if !src.contains(&event.src_addr) {
+                return false;
+            }
+        }
+        if let Some(port) = self.port {
+            if event.dst_port != port {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// FilterExpr is a conjunction of `key=value` or `key!=value` terms joined with `&&`.
+///
+/// An address value can be a CIDR to match addresses in it
+/// and `service` can be given as `namespace/name`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterExpr {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    key: String,
+    negate: bool,
+    value: String,
+}
+
+impl FromStr for FilterExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut terms = Vec::new();
+        for term in s.split("&&").map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, negate, value) = if let Some((k, v)) = term.split_once("!=") {
+                (k, true, v)
+            } else if let Some((k, v)) = term.split_once('=') {
+                (k, false, v.strip_prefix('=').unwrap_or(v))
+            } else {
+                return Err(format!(
+                    "invalid filter term {term}: expected key=value or key!=value"
+                ));
+            };
+            let key = key.trim();
+            if key.is_empty() {
+                return Err(format!("invalid filter term {term}: empty key"));
+            }
+            terms.push(Term {
+                key: key.to_string(),
+                negate,
+                value: value.trim().to_string(),
+            });
+        }
+        Ok(FilterExpr { terms })
+    }
+}
+
+impl FilterExpr {
+    /// Return true if all terms match values looked up by key.
+    pub fn matches(&self, lookup: impl Fn(&str) -> Option<String>) -> bool {
+        self.terms.iter().all(|t| t.matches(&lookup) != t.negate)
+    }
+
+    pub fn matches_event(&self, event: &ConnectionEvent) -> bool {
+        self.matches(|key| event.field(key))
+    }
+}
+
+impl Term {
+    fn matches(&self, lookup: &impl Fn(&str) -> Option<String>) -> bool {
+        if self.key == "service" {
+            if let Some((ns, name)) = self.value.split_once('/') {
+                return lookup("namespace").as_deref() == Some(ns)
+                    && lookup("service").as_deref() == Some(name);
+            }
+        }
+        let actual = lookup(&self.key).unwrap_or_default();
+        if let (Ok(net), Ok(addr)) = (IpNet::from_str(&self.value), IpAddr::from_str(&actual)) {
+            return net.contains(&addr);
+        }
+        actual == self.value
+    }
+}
+
+/// EventFeed fans out decoded events to live subscribers and keeps the recent ones.
+#[derive(Debug, Clone)]
+pub struct EventFeed {
+    sender: broadcast::Sender<Arc<ConnectionEvent>>,
+    recent: Arc<Mutex<VecDeque<Arc<ConnectionEvent>>>>,
+    recent_capacity: usize,
+}
+
+impl Default for EventFeed {
+    fn default() -> Self {
+        EventFeed::new(DEFAULT_RECENT_EVENTS)
+    }
+}
+
+impl EventFeed {
+    pub fn new(recent_capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        EventFeed {
+            sender,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(recent_capacity))),
+            recent_capacity,
+        }
+    }
+
+    pub fn publish(&self, event: ConnectionEvent) {
+        let event = Arc::new(event);
+        if self.recent_capacity > 0 {
+            let mut recent = self.recent.lock();
+            if recent.len() >= self.recent_capacity {
+                recent.pop_front();
+            }
+            recent.push_back(event.clone());
+        }
+        // no subscriber is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ConnectionEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Return the recent events, oldest first.
+    pub fn recent(&self) -> Vec<Arc<ConnectionEvent>> {
+        self.recent.lock().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> ConnectionEvent {
+        ConnectionEvent {
+            timestamp: 0.0,
+            src_addr: "192.168.0.10".parse().unwrap(),
+            src_port: 40000,
+            dst_addr: "10.0.0.1".parse().unwrap(),
+            dst_port: 80,
+            service: Some("web".to_string()),
+            namespace: Some("test".to_string()),
+            port_name: Some("http".to_string()),
+            source: Some("kubernetes".to_string()),
+            delivery: "local".to_string(),
+            backend_addr: None,
+            backend_port: None,
+            backend_pod: None,
+            backend_node: None,
+            misrouted: false,
+            ifindex: 2,
+            ifname: Some("eth0".to_string()),
+        }
+    }
+
+    fn expr(s: &str) -> FilterExpr {
+        FilterExpr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_filter_expr_parse() {
+        assert_eq!(
+            expr(" vip = 10.0.0.1 && port!=80 && delivery==local "),
+            FilterExpr {
+                terms: vec![
+                    Term {
+                        key: "vip".to_string(),
+                        negate: false,
+                        value: "10.0.0.1".to_string(),
+                    },
+                    Term {
+                        key: "port".to_string(),
+                        negate: true,
+                        value: "80".to_string(),
+                    },
+                    Term {
+                        key: "delivery".to_string(),
+                        negate: false,
+                        value: "local".to_string(),
+                    },
+                ],
+            }
+        );
+        assert_eq!(expr(""), FilterExpr::default());
+
+        assert!(FilterExpr::from_str("vip").is_err());
+        assert!(FilterExpr::from_str("vip=10.0.0.1 && port").is_err());
+        assert!(FilterExpr::from_str("=80").is_err());
+        assert!(FilterExpr::from_str(" != 80").is_err());
+    }
+
+    #[test]
+    fn test_filter_expr_matches() {
+        let event = event();
+
+        assert!(expr("").matches_event(&event));
+        assert!(expr("vip=10.0.0.1").matches_event(&event));
+        assert!(!expr("vip!=10.0.0.1").matches_event(&event));
+        assert!(expr("port!=443").matches_event(&event));
+        assert!(expr("vip=10.0.0.1 && port=80").matches_event(&event));
+        assert!(!expr("vip=10.0.0.1 && port=443").matches_event(&event));
+
+        // CIDR
+        assert!(expr("src=192.168.0.0/24").matches_event(&event));
+        assert!(!expr("src=192.168.1.0/24").matches_event(&event));
+        assert!(!expr("src!=192.168.0.0/16").matches_event(&event));
+        assert!(expr("src=192.168.0.10").matches_event(&event));
+
+        // namespace/name
+        assert!(expr("service=web").matches_event(&event));
+        assert!(expr("service=test/web").matches_event(&event));
+        assert!(!expr("service=other/web").matches_event(&event));
+        assert!(expr("service!=other/web").matches_event(&event));
+
+        // a missing value matches only != terms
+        assert!(!expr("backend_pod=web-0").matches_event(&event));
+        assert!(expr("backend_pod!=web-0").matches_event(&event));
+        // an unknown key
+        assert!(!expr("unknown=x").matches_event(&event));
+        assert!(expr("unknown!=x").matches_event(&event));
+    }
+
+    #[test]
+    fn test_event_filter_matches() {
+        let event = event();
+
+        assert!(EventFilter::default().matches(&event));
+
+        let filter = EventFilter {
+            vip: Some("10.0.0.1".parse().unwrap()),
+            service: Some("test/web".to_string()),
+            src: Some("192.168.0.0/24".parse().unwrap()),
+            port: Some(80),
+        };
+        assert!(filter.matches(&event));
+
+        for filter in [
+            EventFilter {
+                vip: Some("10.0.0.2".parse().unwrap()),
+                ..Default::default()
+            },
+            EventFilter {
+                service: Some("other".to_string()),
+                ..Default::default()
+            },
+            EventFilter {
+                service: Some("other/web".to_string()),
+                ..Default::default()
+            },
+            EventFilter {
+                src: Some("192.168.1.0/24".parse().unwrap()),
+                ..Default::default()
+            },
+            EventFilter {
+                port: Some(443),
+                ..Default::default()
+            },
+        ] {
+            assert!(!filter.matches(&event), "{filter:?}");
+        }
+
+        let mut unknown = event.clone();
+        unknown.service = None;
+        unknown.namespace = None;
+        let filter = EventFilter {
+            service: Some("web".to_string()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&unknown));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{net::SocketAddr, pin::Pin, str::FromStr, sync::Arc};
+
+use futures::Stream;
+use parking_lot::RwLock;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    error::Error,
+    feed::{ConnectionEvent, EventFeed, FilterExpr},
+    supervisor::Task,
+    trace::Metrics,
+    vip::VipTable,
+};
+
+pub mod proto {
+    tonic::include_proto!("lb_inter_node_exporter.v1");
+}
+
+use proto::exporter_server::{Exporter, ExporterServer};
+
+/// ExporterService serves the gRPC API backed by the same state as metrics.
+#[derive(Clone)]
+pub struct ExporterService {
+    pub vips: Arc<RwLock<VipTable>>,
+    pub metrics: Metrics,
+    pub feed: EventFeed,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Exporter for ExporterService {
+    async fn list_vips(
+        &self,
+        _req: Request<proto::ListVipsRequest>,
+    ) -> Result<Response<proto::ListVipsResponse>, Status> {
+        let vips = self
+            .vips
+            .read()
+            .entries()
+            .into_iter()
+            .map(|(addr, ports)| proto::Vip {
+                addr: addr.to_string(),
+                ports: ports
+                    .into_iter()
+                    .map(|(port, svc)| proto::ServicePort {
+                        namespace: svc.service.namespace,
+                        name: svc.service.name,
+                        port_name: svc.port_name.unwrap_or_default(),
+                        port: port as u32,
+                        source: svc.source.as_str().to_string(),
+                        traffic_policy: svc.policy.as_str().to_string(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(Response::new(proto::ListVipsResponse { vips }))
+    }
+
+    async fn get_counters(
+        &self,
+        req: Request<proto::GetCountersRequest>,
+    ) -> Result<Response<proto::GetCountersResponse>, Status> {
+        let filter =
+            FilterExpr::from_str(&req.into_inner().filter).map_err(Status::invalid_argument)?;
+        let counters = self
+            .metrics
+            .counters()
+            .into_iter()
+            .filter(|c| filter.matches(|key| c.labels.get(key).cloned()))
+            .map(|c| proto::Counter {
+                name: c.name,
+                labels: c.labels.into_iter().collect(),
+                value: c.value,
+            })
+            .collect();
+        Ok(Response::new(proto::GetCountersResponse { counters }))
+    }
+
+    type WatchEventsStream = EventStream;
+
+    async fn watch_events(
+        &self,
+        req: Request<proto::WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let filter =
+            FilterExpr::from_str(&req.into_inner().filter).map_err(Status::invalid_argument)?;
+        let events = futures::stream::unfold(self.feed.subscribe(), move |mut rx| {
+            let filter = filter.clone();
+            async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) if filter.matches_event(&event) => {
+                            return Some((Ok(event.as_ref().into()), rx));
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(n)) => {
+                            tracing::warn!(skipped = n, "gRPC subscriber lagged");
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+impl From<&ConnectionEvent> for proto::Event {
+    fn from(event: &ConnectionEvent) -> Self {
+        proto::Event {
+            timestamp: event.timestamp,
+            src_addr: event.src_addr.to_string(),
+            src_port: event.src_port as u32,
+            dst_addr: event.dst_addr.to_string(),
+            dst_port: event.dst_port as u32,
+            service: event.service.clone().unwrap_or_default(),
+            namespace: event.namespace.clone().unwrap_or_default(),
+            port_name: event.port_name.clone().unwrap_or_default(),
+            source: event.source.clone().unwrap_or_default(),
+            delivery: event.delivery.clone(),
+            backend_addr: event
+                .backend_addr
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            backend_port: event.backend_port.unwrap_or_default() as u32,
+            backend_pod: event.backend_pod.clone().unwrap_or_default(),
+            backend_node: event.backend_node.clone().unwrap_or_default(),
+            misrouted: event.misrouted,
+            ifname: event.ifname.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// GrpcServer runs the gRPC API.
+pub struct GrpcServer {
+    addr: SocketAddr,
+    service: ExporterService,
+}
+
+impl GrpcServer {
+    pub fn new(addr: SocketAddr, service: ExporterService) -> Self {
+        GrpcServer { addr, service }
+    }
+}
+
+impl Task for GrpcServer {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        tracing::info!(addr=?self.addr, "Start gRPC server");
+        Server::builder()
+            .add_service(ExporterServer::new(self.service.clone()))
+            .serve(self.addr)
+            .await
+            .map_err(Error::Grpc)
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+pub const VIP_SOURCE: &str = "vip_source";
+pub const VIP_SYNC: &str = "vip_sync";
+pub const EVENT_CONSUMER: &str = "event_consumer";
+
+/// A component busy with one item for longer than this is considered stuck.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct ComponentState {
+    running: bool,
+    // whether the task has run once, not running after that means it is backing off
+    started: bool,
+    synced: bool,
+    busy_since: Option<Instant>,
+    last_progress: Option<Instant>,
+}
+
+impl ComponentState {
+    fn backing_off(&self) -> bool {
+        self.started && !self.running
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    attached_interfaces: Vec<String>,
+    components: BTreeMap<&'static str, ComponentState>,
+}
+
+/// Health tracks the state of the agent for readiness and liveness probes.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub attached_interfaces: Vec<String>,
+    pub vip_source_synced: bool,
+    // components waiting to be restarted by the supervisor after a failure
+    pub backing_off: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Liveness {
+    pub healthy: bool,
+    pub components: BTreeMap<&'static str, ComponentStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentStatus {
+    pub running: bool,
+    pub backing_off: bool,
+    pub stalled: bool,
+    pub last_progress_seconds_ago: Option<f64>,
+}
+
+impl Health {
+    /// Register the component of a supervised task.
+    pub fn component(&self, name: &'static str) -> Component {
+        self.inner.lock().components.entry(name).or_default();
+        Component {
+            name,
+            health: self.clone(),
+        }
+    }
+
+    pub fn attached(&self, ifname: &str) {
+        self.inner
+            .lock()
+            .attached_interfaces
+            .push(ifname.to_string());
+    }
+
+    /// The agent is ready when the XDP program is attached, the initial VIP list is synced
+    /// and no component is backing off.
+    pub fn readiness(&self) -> Readiness {
+        let inner = self.inner.lock();
+        let vip_source_synced = inner
+            .components
+            .get(VIP_SOURCE)
+            .map(|c| c.synced)
+            .unwrap_or(false);
+        let backing_off: Vec<&'static str> = inner
+            .components
+            .iter()
+            .filter(|(_, c)| c.backing_off())
+            .map(|(name, _)| *name)
+            .collect();
+        Readiness {
+            ready: !inner.attached_interfaces.is_empty()
+                && vip_source_synced
+                && backing_off.is_empty(),
+            attached_interfaces: inner.attached_interfaces.clone(),
+            vip_source_synced,
+            backing_off,
+        }
+    }
+
+    /// The agent is healthy when none of the components is stuck.
+    ///
+    /// A failed task is restarted by the supervisor and a fatal error exits the agent,
+    /// so a restart of the pod doesn't help a component that is not running.
+    pub fn liveness(&self) -> Liveness {
+        let now = Instant::now();
+        let inner = self.inner.lock();
+        let components: BTreeMap<&'static str, ComponentStatus> = inner
+            .components
+            .iter()
+            .map(|(name, state)| {
+                let stalled = state
+                    .busy_since
+                    .map(|since| now.duration_since(since) > STALL_TIMEOUT)
+                    .unwrap_or(false);
+                let status = ComponentStatus {
+                    running: state.running,
+                    backing_off: state.backing_off(),
+                    stalled,
+                    last_progress_seconds_ago: state
+                        .last_progress
+                        .map(|t| now.duration_since(t).as_secs_f64()),
+                };
+                (*name, status)
+            })
+            .collect();
+        Liveness {
+            healthy: components.values().all(|c| !c.stalled),
+            components,
+        }
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut ComponentState)) {
+        let mut inner = self.inner.lock();
+        f(inner.components.entry(name).or_default());
+    }
+}
+
+/// Component reports the state of a background task.
+#[derive(Debug, Clone)]
+pub struct Component {
+    name: &'static str,
+    health: Health,
+}
+
+impl Component {
+    /// Mark the component running until the returned guard is dropped.
+    ///
+    /// The guard is dropped on panic too.
+    pub fn start(&self) -> AliveGuard {
+        self.health.update(self.name, |c| {
+            c.running = true;
+            c.started = true;
+            c.busy_since = None;
+        });
+        AliveGuard(self.clone())
+    }
+
+    /// Mark the component busy with an item.
+    pub fn begin(&self) {
+        self.health
+            .update(self.name, |c| c.busy_since = Some(Instant::now()));
+    }
+
+    /// Mark the component done with the item.
+    pub fn done(&self) {
+        self.health.update(self.name, |c| {
+            c.busy_since = None;
+            c.last_progress = Some(Instant::now());
+        });
+    }
+
+    /// Mark the initial state of the component synced.
+    pub fn synced(&self) {
+        self.health.update(self.name, |c| c.synced = true);
+    }
+}
+
+pub struct AliveGuard(Component);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.health.update(self.0.name, |c| {
+            c.running = false;
+            c.busy_since = None;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health() {
+        let health = Health::default();
+        let source = health.component(VIP_SOURCE);
+        let consumer = health.component(EVENT_CONSUMER);
+        assert!(!health.readiness().ready);
+        assert!(health.liveness().healthy);
+
+        health.attached("eth0");
+        let source_alive = source.start();
+        let consumer_alive = consumer.start();
+        assert!(!health.readiness().ready);
+        source.synced();
+        assert!(health.readiness().ready);
+
+        // a failed task backing off isn't ready but still alive
+        drop(consumer_alive);
+        let readiness = health.readiness();
+        assert!(!readiness.ready);
+        assert_eq!(readiness.backing_off, [EVENT_CONSUMER]);
+        let liveness = health.liveness();
+        assert!(liveness.healthy);
+        assert!(liveness.components[EVENT_CONSUMER].backing_off);
+        assert!(!liveness.components[EVENT_CONSUMER].running);
+
+        let _consumer_alive = consumer.start();
+        assert!(health.readiness().ready);
+
+        // a stuck component isn't alive
+        source.begin();
+        health.update(VIP_SOURCE, |c| {
+            c.busy_since = Some(Instant::now() - STALL_TIMEOUT * 2)
+        });
+        let liveness = health.liveness();
+        assert!(!liveness.healthy);
+        assert!(liveness.components[VIP_SOURCE].stalled);
+        source.done();
+        assert!(health.liveness().healthy);
+        drop(source_alive);
+    }
+}
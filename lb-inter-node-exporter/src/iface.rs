@@ -13,7 +13,7 @@ pub struct Iface {
 pub async fn get_ifaces(ifaces: &[String]) -> Result<Vec<Iface>, Error> {
     let mut regex_ifaces = Vec::new();
     for iface in ifaces.iter() {
-        let r_iface = Regex::new(&iface).map_err(Error::Regex)?;
+        let r_iface = Regex::new(iface).map_err(Error::Regex)?;
         regex_ifaces.push(r_iface);
     }
     let iface_list = list_link().await?;
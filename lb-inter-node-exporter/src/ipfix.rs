@@ -0,0 +1,563 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{net::UdpSocket, sync::broadcast::error::RecvError};
+
+use crate::{
+    error::Error,
+    feed::{ConnectionEvent, EventFeed},
+    supervisor::Task,
+    trace::AgentMetrics,
+};
+
+const VERSION: u16 = 10;
+const HEADER_LEN: usize = 16;
+const SET_HEADER_LEN: usize = 4;
+const TEMPLATE_SET_ID: u16 = 2;
+// keep messages within the MTU to avoid IP fragmentation
+const MAX_MESSAGE_LEN: usize = 1400;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const BATCH_SIZE: usize = 512;
+const TCP: u8 = 6;
+
+// Information Elements assigned by IANA
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const INGRESS_INTERFACE: u16 = 10;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const POST_NAT_DESTINATION_IPV4_ADDRESS: u16 = 226;
+const POST_NAPT_DESTINATION_TRANSPORT_PORT: u16 = 228;
+const POST_NAT_DESTINATION_IPV6_ADDRESS: u16 = 282;
+
+/// Template describes the fields of data records with its ID.
+struct Template {
+    id: u16,
+    // (Information Element ID, length)
+    fields: &'static [(u16, u16)],
+}
+
+const IPV4: Template = Template {
+    id: 256,
+    fields: &[
+        (SOURCE_IPV4_ADDRESS, 4),
+        (DESTINATION_IPV4_ADDRESS, 4),
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (INGRESS_INTERFACE, 4),
+        (FLOW_START_MILLISECONDS, 8),
+    ],
+};
+
+const IPV4_BACKEND: Template = Template {
+    id: 257,
+    fields: &[
+        (SOURCE_IPV4_ADDRESS, 4),
+        (DESTINATION_IPV4_ADDRESS, 4),
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (INGRESS_INTERFACE, 4),
+        (FLOW_START_MILLISECONDS, 8),
+        (POST_NAT_DESTINATION_IPV4_ADDRESS, 4),
+        (POST_NAPT_DESTINATION_TRANSPORT_PORT, 2),
+    ],
+};
+
+const IPV6: Template = Template {
+    id: 258,
+    fields: &[
+        (SOURCE_IPV6_ADDRESS, 16),
+        (DESTINATION_IPV6_ADDRESS, 16),
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (INGRESS_INTERFACE, 4),
+        (FLOW_START_MILLISECONDS, 8),
+    ],
+};
+
+const IPV6_BACKEND: Template = Template {
+    id: 259,
+    fields: &[
+        (SOURCE_IPV6_ADDRESS, 16),
+        (DESTINATION_IPV6_ADDRESS, 16),
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (INGRESS_INTERFACE, 4),
+        (FLOW_START_MILLISECONDS, 8),
+        (POST_NAT_DESTINATION_IPV6_ADDRESS, 16),
+        (POST_NAPT_DESTINATION_TRANSPORT_PORT, 2),
+    ],
+};
+
+const TEMPLATES: [&Template; 4] = [&IPV4, &IPV4_BACKEND, &IPV6, &IPV6_BACKEND];
+
+impl Template {
+    fn record(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + self.fields.len() * 4);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&(self.fields.len() as u16).to_be_bytes());
+        for (id, len) in self.fields.iter() {
+            buf.extend_from_slice(&id.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+        buf
+    }
+}
+
+/// Encode the event as a data record with the ID of its template.
+fn data_record(event: &ConnectionEvent) -> Option<(u16, Vec<u8>)> {
+    // the backend is recorded only with the same address family as the VIP
+    let backend = match (event.dst_addr, event.backend_addr, event.backend_port) {
+        (IpAddr::V4(_), Some(addr @ IpAddr::V4(_)), Some(port))
+        | (IpAddr::V6(_), Some(addr @ IpAddr::V6(_)), Some(port)) => Some((addr, port)),
+        _ => None,
+    };
+    let template = match (event.src_addr, event.dst_addr, backend.is_some()) {
+        (IpAddr::V4(_), IpAddr::V4(_), false) => &IPV4,
+        (IpAddr::V4(_), IpAddr::V4(_), true) => &IPV4_BACKEND,
+        (IpAddr::V6(_), IpAddr::V6(_), false) => &IPV6,
+        (IpAddr::V6(_), IpAddr::V6(_), true) => &IPV6_BACKEND,
+        _ => return None,
+    };
+
+    let mut buf = Vec::with_capacity(64);
+    put_addr(&mut buf, event.src_addr);
+    put_addr(&mut buf, event.dst_addr);
+    buf.extend_from_slice(&event.src_port.to_be_bytes());
+    buf.extend_from_slice(&event.dst_port.to_be_bytes());
+    buf.push(TCP);
+    buf.extend_from_slice(&event.ifindex.to_be_bytes());
+    buf.extend_from_slice(&((event.timestamp * 1000.0) as u64).to_be_bytes());
+    if let Some((addr, port)) = backend {
+        put_addr(&mut buf, addr);
+        buf.extend_from_slice(&port.to_be_bytes());
+    }
+    Some((template.id, buf))
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(addr) => buf.extend_from_slice(&addr.octets()),
+        IpAddr::V6(addr) => buf.extend_from_slice(&addr.octets()),
+    }
+}
+
+/// Message is an IPFIX message being built.
+struct Message {
+    buf: Vec<u8>,
+    // (Set ID, offset) of the set being written
+    set: Option<(u16, usize)>,
+    // the number of data records
+    records: u32,
+}
+
+impl Message {
+    fn new() -> Self {
+        Message {
+            buf: vec![0; HEADER_LEN],
+            set: None,
+            records: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.len() == HEADER_LEN
+    }
+
+    fn fits(&self, set_id: u16, len: usize) -> bool {
+        let set_header = match self.set {
+            Some((id, _)) if id == set_id => 0,
+            _ => SET_HEADER_LEN,
+        };
+        self.buf.len() + set_header + len <= MAX_MESSAGE_LEN
+    }
+
+    fn push(&mut self, set_id: u16, record: &[u8]) {
+        if !matches!(self.set, Some((id, _)) if id == set_id) {
+            self.close_set();
+            self.set = Some((set_id, self.buf.len()));
+            self.buf.extend_from_slice(&set_id.to_be_bytes());
+            self.buf.extend_from_slice(&[0, 0]);
+        }
+        self.buf.extend_from_slice(record);
+        if set_id != TEMPLATE_SET_ID {
+            self.records += 1;
+        }
+    }
+
+    fn close_set(&mut self) {
+        if let Some((_, start)) = self.set.take() {
+            let len = (self.buf.len() - start) as u16;
+            self.buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    fn finish(mut self, sequence: u32, domain: u32, export_time: u32) -> Vec<u8> {
+        self.close_set();
+        let len = self.buf.len() as u16;
+        self.buf[0..2].copy_from_slice(&VERSION.to_be_bytes());
+        self.buf[2..4].copy_from_slice(&len.to_be_bytes());
+        self.buf[4..8].copy_from_slice(&export_time.to_be_bytes());
+        self.buf[8..12].copy_from_slice(&sequence.to_be_bytes());
+        self.buf[12..16].copy_from_slice(&domain.to_be_bytes());
+        self.buf
+    }
+}
+
+/// The default observation domain of the node derived from its name.
+pub fn observation_domain(node_name: &str) -> u32 {
+    if node_name.is_empty() {
+        return 0;
+    }
+    let digest = Sha256::digest(node_name.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// IpfixExporter sends a data record per connection event to an IPFIX collector over UDP.
+///
+/// Templates are sent before the first data records and every template refresh interval
+/// because a collector may restart and lose them without noticing over UDP.
+pub struct IpfixExporter {
+    feed: EventFeed,
+    collector: String,
+    domain: u32,
+    template_refresh: Duration,
+    metrics: AgentMetrics,
+    // the number of data records sent in the observation domain
+    sequence: u32,
+    templates_sent: Option<Instant>,
+}
+
+impl IpfixExporter {
+    pub fn new(
+        feed: EventFeed,
+        collector: String,
+        domain: u32,
+        template_refresh: Duration,
+        metrics: AgentMetrics,
+    ) -> Self {
+        IpfixExporter {
+            feed,
+            collector,
+            domain,
+            template_refresh,
+            metrics,
+            sequence: 0,
+            templates_sent: None,
+        }
+    }
+
+    async fn connect(&self) -> Result<UdpSocket, Error> {
+        let collector = tokio::net::lookup_host(self.collector.as_str())
+            .await
+            .map_err(Error::StdIo)?
+            .next()
+            .ok_or(Error::Ipfix(format!(
+                "failed to resolve {}",
+                self.collector
+            )))?;
+        let local: SocketAddr = match collector {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await.map_err(Error::StdIo)?;
+        socket.connect(collector).await.map_err(Error::StdIo)?;
+        tracing::info!(collector=?collector, domain = self.domain, "Start IPFIX exporter");
+        Ok(socket)
+    }
+
+    fn templates(&self, export_time: u32) -> Vec<u8> {
+        let mut message = Message::new();
+        for template in TEMPLATES.iter() {
+            message.push(TEMPLATE_SET_ID, &template.record());
+        }
+        message.finish(self.sequence, self.domain, export_time)
+    }
+
+    // Encode events into messages with the number of data records in each of them.
+    fn encode(
+        &mut self,
+        batch: &mut Vec<Arc<ConnectionEvent>>,
+        export_time: u32,
+    ) -> Vec<(Vec<u8>, u32)> {
+        let mut messages = Vec::new();
+        let mut message = Message::new();
+        for event in batch.drain(..) {
+            let Some((template_id, record)) = data_record(&event) else {
+                self.metrics.ipfix_records_dropped("unsupported", 1);
+                continue;
+            };
+            if !message.fits(template_id, record.len()) {
+                messages.push(std::mem::replace(&mut message, Message::new()));
+            }
+            message.push(template_id, &record);
+        }
+        if !message.is_empty() {
+            messages.push(message);
+        }
+
+        messages
+            .into_iter()
+            .map(|message| {
+                let records = message.records;
+                let buf = message.finish(self.sequence, self.domain, export_time);
+                // the sequence number counts data records whether the collector receives them or not
+                self.sequence = self.sequence.wrapping_add(records);
+                (buf, records)
+            })
+            .collect()
+    }
+
+    async fn send_templates(&mut self, socket: &UdpSocket) {
+        let templates = self.templates(ConnectionEvent::now() as u32);
+        if let Err(e) = socket.send(&templates).await {
+            tracing::warn!(error=?e, "Failed to send IPFIX templates");
+            return;
+        }
+        self.templates_sent = Some(Instant::now());
+    }
+
+    async fn flush(&mut self, socket: &UdpSocket, batch: &mut Vec<Arc<ConnectionEvent>>) {
+        let templates_due = self
+            .templates_sent
+            .map(|sent| sent.elapsed() >= self.template_refresh)
+            .unwrap_or(true);
+        if templates_due {
+            self.send_templates(socket).await;
+        }
+
+        for (buf, records) in self.encode(batch, ConnectionEvent::now() as u32) {
+            match socket.send(&buf).await {
+                Ok(_) => self.metrics.ipfix_records_exported(records),
+                Err(e) => {
+                    tracing::warn!(records, error=?e, "Failed to send IPFIX message");
+                    self.metrics.ipfix_records_dropped("send", records as u64);
+                }
+            }
+        }
+    }
+}
+
+impl Task for IpfixExporter {
+    async fn run(&mut self) -> Result<(), Error> {
+        let socket = self.connect().await?;
+        // the collector may not know the templates of the previous socket
+        self.templates_sent = None;
+        let mut events = self.feed.subscribe();
+        let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        batch.push(event);
+                        if batch.len() >= BATCH_SIZE {
+                            self.flush(&socket, &mut batch).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => self.metrics.ipfix_records_dropped("lagged", n),
+                    Err(RecvError::Closed) => {
+                        return Err(Error::ChannelClosed("event feed".to_string()))
+                    }
+                },
+                _ = ticker.tick() => self.flush(&socket, &mut batch).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT_TIME: u32 = 1_700_000_000;
+    const DOMAIN: u32 = 0x01020304;
+
+    fn exporter() -> IpfixExporter {
+        IpfixExporter::new(
+            EventFeed::default(),
+            "127.0.0.1:4739".to_string(),
+            DOMAIN,
+            Duration::from_secs(600),
+            AgentMetrics::default(),
+        )
+    }
+
+    fn event(src: &str, dst: &str, backend: Option<(&str, u16)>) -> Arc<ConnectionEvent> {
+        Arc::new(ConnectionEvent {
+            timestamp: 1_700_000_000.5,
+            src_addr: src.parse().unwrap(),
+            src_port: 40000,
+            dst_addr: dst.parse().unwrap(),
+            dst_port: 80,
+            service: None,
+            namespace: None,
+            port_name: None,
+            source: None,
+            delivery: "forwarded".to_string(),
+            backend_addr: backend.map(|(addr, _)| addr.parse().unwrap()),
+            backend_port: backend.map(|(_, port)| port),
+            backend_pod: None,
+            backend_node: None,
+            misrouted: false,
+            ifindex: 2,
+            ifname: None,
+        })
+    }
+
+    fn header(len: u16, sequence: u32) -> Vec<u8> {
+        let mut buf = vec![0, 10];
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&EXPORT_TIME.to_be_bytes());
+        buf.extend_from_slice(&sequence.to_be_bytes());
+        buf.extend_from_slice(&DOMAIN.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_templates() {
+        let buf = exporter().templates(EXPORT_TIME);
+        let records: [&[u8]; 4] = [
+            &[
+                1, 0, 0, 7, // ID 256 with 7 fields
+                0, 8, 0, 4, 0, 12, 0, 4, 0, 7, 0, 2, 0, 11, 0, 2, 0, 4, 0, 1, 0, 10, 0, 4, 0, 152,
+                0, 8,
+            ],
+            &[
+                1, 1, 0, 9, // ID 257 with 9 fields
+                0, 8, 0, 4, 0, 12, 0, 4, 0, 7, 0, 2, 0, 11, 0, 2, 0, 4, 0, 1, 0, 10, 0, 4, 0, 152,
+                0, 8, 0, 226, 0, 4, 0, 228, 0, 2,
+            ],
+            &[
+                1, 2, 0, 7, // ID 258 with 7 fields
+                0, 27, 0, 16, 0, 28, 0, 16, 0, 7, 0, 2, 0, 11, 0, 2, 0, 4, 0, 1, 0, 10, 0, 4, 0,
+                152, 0, 8,
+            ],
+            &[
+                1, 3, 0, 9, // ID 259 with 9 fields
+                0, 27, 0, 16, 0, 28, 0, 16, 0, 7, 0, 2, 0, 11, 0, 2, 0, 4, 0, 1, 0, 10, 0, 4, 0,
+                152, 0, 8, 1, 26, 0, 16, 0, 228, 0, 2,
+            ],
+        ];
+        let set_len = SET_HEADER_LEN + records.iter().map(|r| r.len()).sum::<usize>();
+        let mut expected = header((HEADER_LEN + set_len) as u16, 0);
+        // a single template set
+        expected.extend_from_slice(&[0, 2]);
+        expected.extend_from_slice(&(set_len as u16).to_be_bytes());
+        for record in records.iter() {
+            expected.extend_from_slice(record);
+        }
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_data_records() {
+        let mut exporter = exporter();
+        let mut batch = vec![
+            event("192.168.0.2", "10.0.0.1", None),
+            event("192.168.0.3", "10.0.0.1", Some(("10.244.1.5", 8080))),
+            // the backend of another address family isn't recorded
+            event("192.168.0.4", "10.0.0.1", Some(("fd00::5", 8080))),
+        ];
+        let messages = exporter.encode(&mut batch, EXPORT_TIME);
+        assert!(batch.is_empty());
+        assert_eq!(messages.len(), 1);
+        let (buf, records) = &messages[0];
+        assert_eq!(*records, 3);
+
+        let start: [u8; 8] = 1_700_000_000_500u64.to_be_bytes();
+        let mut expected = header(16 + 4 + 25 + 4 + 31 + 4 + 25, 0);
+        expected.extend_from_slice(&[1, 0, 0, 29]);
+        expected.extend_from_slice(&[
+            192, 168, 0, 2, 10, 0, 0, 1, 0x9c, 0x40, 0, 80, 6, 0, 0, 0, 2,
+        ]);
+        expected.extend_from_slice(&start);
+        expected.extend_from_slice(&[1, 1, 0, 35]);
+        expected.extend_from_slice(&[
+            192, 168, 0, 3, 10, 0, 0, 1, 0x9c, 0x40, 0, 80, 6, 0, 0, 0, 2,
+        ]);
+        expected.extend_from_slice(&start);
+        expected.extend_from_slice(&[10, 244, 1, 5, 0x1f, 0x90]);
+        expected.extend_from_slice(&[1, 0, 0, 29]);
+        expected.extend_from_slice(&[
+            192, 168, 0, 4, 10, 0, 0, 1, 0x9c, 0x40, 0, 80, 6, 0, 0, 0, 2,
+        ]);
+        expected.extend_from_slice(&start);
+        assert_eq!(*buf, expected);
+
+        // mixed address families are unsupported
+        let mut batch = vec![event("fd00::2", "10.0.0.1", None)];
+        assert!(exporter.encode(&mut batch, EXPORT_TIME).is_empty());
+        assert_eq!(exporter.sequence, 3);
+    }
+
+    #[test]
+    fn test_split_messages() {
+        let mut exporter = exporter();
+        // 25 bytes for each IPv4 record
+        let per_message = (MAX_MESSAGE_LEN - HEADER_LEN - SET_HEADER_LEN) / 25;
+        let mut batch: Vec<_> = (0..per_message * 2 + 1)
+            .map(|_| event("192.168.0.2", "10.0.0.1", None))
+            .collect();
+        let messages = exporter.encode(&mut batch, EXPORT_TIME);
+
+        let records: Vec<u32> = messages.iter().map(|(_, records)| *records).collect();
+        assert_eq!(records, [per_message as u32, per_message as u32, 1]);
+        for (i, (buf, records)) in messages.iter().enumerate() {
+            assert!(buf.len() <= MAX_MESSAGE_LEN);
+            let len = HEADER_LEN + SET_HEADER_LEN + *records as usize * 25;
+            assert_eq!(buf.len(), len);
+            assert_eq!(buf[2..4], (len as u16).to_be_bytes());
+            // the sequence number of the first data record in the message
+            let sequence = (i * per_message) as u32;
+            assert_eq!(buf[8..12], sequence.to_be_bytes());
+            // a single data set of the IPv4 template
+            let set_len = ((len - HEADER_LEN) as u16).to_be_bytes();
+            assert_eq!(buf[16..20], [1, 0, set_len[0], set_len[1]]);
+        }
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut exporter = exporter();
+        assert_eq!(exporter.templates(EXPORT_TIME)[8..12], 0u32.to_be_bytes());
+
+        let mut batch = vec![
+            event("192.168.0.2", "10.0.0.1", None),
+            event("192.168.0.3", "10.0.0.1", None),
+        ];
+        let messages = exporter.encode(&mut batch, EXPORT_TIME);
+        assert_eq!(messages[0].0[8..12], 0u32.to_be_bytes());
+
+        // templates don't advance the sequence number
+        assert_eq!(exporter.templates(EXPORT_TIME)[8..12], 2u32.to_be_bytes());
+        assert_eq!(exporter.templates(EXPORT_TIME)[8..12], 2u32.to_be_bytes());
+
+        let mut batch = vec![event("192.168.0.4", "10.0.0.1", None)];
+        let messages = exporter.encode(&mut batch, EXPORT_TIME);
+        assert_eq!(messages[0].0[8..12], 2u32.to_be_bytes());
+        assert_eq!(exporter.sequence, 3);
+
+        // the sequence number wraps around
+        exporter.sequence = u32::MAX;
+        let mut batch = vec![
+            event("192.168.0.2", "10.0.0.1", None),
+            event("192.168.0.3", "10.0.0.1", None),
+        ];
+        let messages = exporter.encode(&mut batch, EXPORT_TIME);
+        assert_eq!(messages[0].0[8..12], u32::MAX.to_be_bytes());
+        assert_eq!(exporter.sequence, 1);
+    }
+}
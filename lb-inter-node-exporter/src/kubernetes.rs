@@ -1,14 +1,31 @@
-use std::{net::IpAddr, pin::pin, str::FromStr};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+    pin::pin,
+    str::FromStr,
+    time::Duration,
+};
 
 use futures::TryStreamExt;
-use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::{api::core::v1::Service, NamespaceResourceScope};
 use kube::{
     runtime::{watcher, WatchStreamExt},
-    Api, Client, ResourceExt,
+    Api, Client, Resource, ResourceExt,
+};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    time::Instant,
 };
-use tokio::sync::mpsc::UnboundedSender;
 
-use crate::error::Error;
+use crate::{error::Error, health::Component, supervisor::Task, trace::AgentMetrics};
+
+const METALLB_LOADBALANCER_IPS_ANNOTATION: &str = "metallb.universe.tf/loadBalancerIPs";
+pub const TRACK_ANNOTATION: &str = "lb-inter-node-exporter.terassyi.net/track";
+// how long the resolved addresses of an ingress hostname are used before resolving it again
+const HOSTNAME_TTL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VipEvent {
@@ -16,101 +33,980 @@ pub enum VipEvent {
     Delete(Lb),
 }
 
+impl VipEvent {
+    /// The events to move from the current Lbs to the desired ones.
+    pub fn diff(current: &[Lb], desired: &[Lb]) -> Vec<VipEvent> {
+        let mut events: Vec<VipEvent> = current
+            .iter()
+            .filter(|lb| !desired.contains(lb))
+            .map(|lb| VipEvent::Delete(lb.clone()))
+            .collect();
+        events.extend(
+            desired
+                .iter()
+                .filter(|lb| !current.contains(lb))
+                .map(|lb| VipEvent::Add(lb.clone())),
+        );
+        events
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Lb {
     pub name: String,
     pub namespace: String,
     pub addr: Option<IpAddr>,
+    pub source: AddrSource,
+    pub policy: TrafficPolicy,
+    pub ports: Vec<LbPort>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LbPort {
+    pub name: Option<String>,
+    pub port: u16,
+    pub protocol: String,
+}
+
+/// TrafficPolicy is the externalTrafficPolicy of the Service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum TrafficPolicy {
+    #[default]
+    Cluster,
+    Local,
+}
+
+impl TrafficPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficPolicy::Cluster => "Cluster",
+            TrafficPolicy::Local => "Local",
+        }
+    }
+}
+
+/// AddrSource is where the address of the Service comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AddrSource {
+    // status.loadBalancer.ingress
+    Ingress,
+    // spec.externalIPs
+    ExternalIp,
+    // spec.loadBalancerIP or the loadBalancerIPs annotation
+    Requested,
+    // given by --vip or --vip-file without Kubernetes
+    Static,
+}
+
+impl AddrSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddrSource::Ingress => "ingress",
+            AddrSource::ExternalIp => "externalIP",
+            AddrSource::Requested => "requested",
+            AddrSource::Static => "static",
+        }
+    }
+}
+
+/// ServiceFilter selects Services to be tracked.
+///
+/// The label selector, a single included namespace and excluded namespaces are applied server-side,
+/// and the rest are applied to each Service by ServiceTracker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceFilter {
+    pub namespaces: Vec<String>,
+    pub exclude_namespaces: Vec<String>,
+    pub label_selector: Option<String>,
+    // When true, only Services annotated with `TRACK_ANNOTATION: "true"` are tracked.
+    // Otherwise, Services annotated with `TRACK_ANNOTATION: "false"` are ignored.
+    pub opt_in: bool,
+    pub load_balancer_classes: Vec<String>,
+    // Track Services with externalTrafficPolicy=Local too.
+    pub track_local_policy: bool,
+}
+
+impl ServiceFilter {
+    /// Build the Api scoped to the included namespace if only one is specified.
+    pub fn api<K>(&self, client: Client) -> Api<K>
+    where
+        K: Resource<Scope = NamespaceResourceScope>,
+        <K as Resource>::DynamicType: Default,
+    {
+        match self.namespaces.as_slice() {
+            [ns] => Api::<K>::namespaced(client, ns),
+            _ => Api::<K>::all(client),
+        }
+    }
+
+    /// Field selector to exclude namespaces server-side.
+    pub fn namespace_fields(&self) -> Option<String> {
+        if self.exclude_namespaces.is_empty() {
+            return None;
+        }
+        Some(
+            self.exclude_namespaces
+                .iter()
+                .map(|ns| format!("metadata.namespace!={ns}"))
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+
+    fn watcher_config(&self) -> watcher::Config {
+        let mut config = watcher::Config::default();
+        if let Some(selector) = self.label_selector.as_ref() {
+            config = config.labels(selector);
+        }
+        if let Some(fields) = self.namespace_fields() {
+            config = config.fields(&fields);
+        }
+        config
+    }
+
+    pub fn matches(&self, svc: &Service) -> bool {
+        let ns = svc.namespace().unwrap_or_default();
+        if !self.namespaces.is_empty() && !self.namespaces.contains(&ns) {
+            return false;
+        }
+        if self.exclude_namespaces.contains(&ns) {
+            return false;
+        }
+
+        match svc.annotations().get(TRACK_ANNOTATION).map(|v| v.as_str()) {
+            Some("true") => {}
+            Some("false") => return false,
+            _ => {
+                if self.opt_in {
+                    return false;
+                }
+            }
+        }
+
+        if !self.load_balancer_classes.is_empty() {
+            let class = svc
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.load_balancer_class.as_ref());
+            match class {
+                Some(class) => {
+                    if !self.load_balancer_classes.contains(class) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
 }
 
 pub struct ServiceWatcher {
     client: Client,
+    // replaced on reloading the config file
+    filter: watch::Receiver<ServiceFilter>,
     // ebpf map
-    vip_events: UnboundedSender<VipEvent>,
+    vip_events: UnboundedSender<Vec<VipEvent>>,
+    metrics: AgentMetrics,
+    health: Component,
+    // kept across restarts to diff the relisted Services against
+    tracker: ServiceTracker,
+    // ingress hostnames to be resolved by HostnameResolver and its results
+    hostnames: watch::Sender<BTreeSet<String>>,
+    resolved: UnboundedReceiver<(String, Vec<IpAddr>)>,
 }
 
 impl ServiceWatcher {
-    pub async fn new(vip_events: UnboundedSender<VipEvent>) -> Self {
-        let client = Client::try_default()
-            .await
-            .expect("Failed to create kube client");
-        ServiceWatcher { client, vip_events }
+    /// Create the watcher with the HostnameResolver that resolves ingress hostnames for it.
+    pub async fn new(
+        vip_events: UnboundedSender<Vec<VipEvent>>,
+        filter: watch::Receiver<ServiceFilter>,
+        metrics: AgentMetrics,
+        health: Component,
+    ) -> Result<(Self, HostnameResolver), Error> {
+        let client = Client::try_default().await.map_err(Error::Kube)?;
+        let tracker = ServiceTracker::new(filter.borrow().clone());
+        let (hostnames, hostnames_recv) = watch::channel(BTreeSet::new());
+        let (resolved_send, resolved) = unbounded_channel();
+        let resolver = HostnameResolver {
+            hostnames: hostnames_recv,
+            resolved: resolved_send,
+            due: HashMap::new(),
+        };
+        Ok((
+            ServiceWatcher {
+                client,
+                tracker,
+                filter,
+                vip_events,
+                metrics,
+                health,
+                hostnames,
+                resolved,
+            },
+            resolver,
+        ))
+    }
+
+    fn send(&self, vip_events: Vec<VipEvent>) -> Result<(), Error> {
+        if !vip_events.is_empty() {
+            self.vip_events
+                .send(vip_events)
+                .map_err(|_| Error::ChannelClosed("VIP events".to_string()))?;
+        }
+        // ask the resolver for hostnames of newly tracked Services and forget removed ones
+        let hostnames = self.tracker.hostnames();
+        self.hostnames.send_if_modified(|current| {
+            if *current == hostnames {
+                return false;
+            }
+            *current = hostnames;
+            true
+        });
+        Ok(())
     }
+}
 
+impl Task for ServiceWatcher {
     #[tracing::instrument(skip_all)]
-    pub async fn run(&self) -> Result<(), Error> {
-        let svc_api = Api::<Service>::all(self.client.clone());
-        let watcher_config = watcher::Config::default();
-        let svc_events = watcher(svc_api, watcher_config)
-            .default_backoff()
-            .touched_objects();
-        let mut svc_events = pin!(svc_events);
-
-        tracing::info!("Start Service watcher");
-        while let Some(svc) = svc_events.try_next().await.map_err(Error::KubeWatcher)? {
-            let ns = svc.namespace().unwrap();
-            let namespaced_svc_api = Api::<Service>::namespaced(self.client.clone(), &ns);
-            match namespaced_svc_api.get_opt(&svc.name_any()).await {
-                Ok(svc_opt) => match svc_opt {
-                    Some(svc) => {
-                        if let Some(vip) = get_lb_addr(&svc) {
-                            if let Ok(vip) = IpAddr::from_str(&vip) {
-                                self.vip_events
-                                    .send(VipEvent::Add(Lb {
-                                        name: svc.name_any(),
-                                        namespace: ns.clone(),
-                                        addr: Some(vip),
-                                    }))
-                                    .unwrap();
-                            }
-                        }
+    async fn run(&mut self) -> Result<(), Error> {
+        let _alive = self.health.start();
+        loop {
+            let filter = self.filter.borrow_and_update().clone();
+            let svc_api = filter.api(self.client.clone());
+            let watcher_config = filter.watcher_config();
+            self.tracker.filter = filter;
+            let svc_events = watcher(svc_api, watcher_config).default_backoff();
+            let mut svc_events = pin!(svc_events);
+
+            tracing::info!("Start Service watcher");
+            loop {
+                let event = tokio::select! {
+                    event = svc_events.try_next() => event.map_err(Error::KubeWatcher)?,
+                    Ok(()) = self.filter.changed() => break,
+                    Some((hostname, addrs)) = self.resolved.recv() => {
+                        self.health.begin();
+                        let vip_events = self.tracker.set_resolved(hostname, addrs);
+                        self.send(vip_events)?;
+                        self.health.done();
+                        continue;
                     }
-                    None => {
-                        self.vip_events
-                            .send(VipEvent::Delete(Lb {
-                                name: svc.name_any(),
-                                namespace: ns.clone(),
-                                addr: None,
-                            }))
-                            .unwrap();
+                };
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                self.health.begin();
+                self.metrics.watcher_event("service");
+                let restarted = matches!(event, watcher::Event::Restarted(_));
+                let vip_events = match event {
+                    watcher::Event::Applied(svc) => self.tracker.apply(&svc),
+                    watcher::Event::Deleted(svc) => self.tracker.delete(&svc),
+                    watcher::Event::Restarted(svcs) => {
+                        self.metrics.watcher_restarted("service");
+                        self.tracker.restart(&svcs)
                     }
-                },
-                Err(e) => {
-                    tracing::warn!(name=svc.name_any(), namespace = ns, error=?e, "Failed to get svc");
+                };
+                self.send(vip_events)?;
+                if restarted {
+                    // the initial list of Services is synced
+                    self.health.synced();
                 }
+                self.health.done();
             }
+            // the relist with the new filter tracks and untracks Services by ServiceTracker::restart
+            tracing::info!("Restart Service watcher to apply the new filter");
         }
-
-        Ok(())
     }
 }
 
-fn get_lb_addr(svc: &Service) -> Option<String> {
-    if let Some(svc_spec) = svc.spec.as_ref() {
-        if let Some(svc_type) = svc_spec.type_.as_ref() {
-            if svc_type.ne("LoadBalancer") {
-                return None;
+/// HostnameResolver resolves ingress hostnames of tracked Services apart from the Service watcher,
+/// so that a slow resolver doesn't block Service events.
+///
+/// Each hostname is resolved again every HOSTNAME_TTL and the results are sent back to the ServiceWatcher.
+pub struct HostnameResolver {
+    hostnames: watch::Receiver<BTreeSet<String>>,
+    resolved: UnboundedSender<(String, Vec<IpAddr>)>,
+    // when to resolve each hostname next
+    due: HashMap<String, Instant>,
+}
+
+impl Task for HostnameResolver {
+    async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let hostnames = self.hostnames.borrow_and_update().clone();
+            self.due.retain(|hostname, _| hostnames.contains(hostname));
+            for hostname in hostnames.into_iter() {
+                self.due.entry(hostname).or_insert_with(Instant::now);
+            }
+
+            let now = Instant::now();
+            let due: Vec<String> = self
+                .due
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(hostname, _)| hostname.clone())
+                .collect();
+            for hostname in due.into_iter() {
+                match tokio::net::lookup_host((hostname.as_str(), 0)).await {
+                    Ok(addrs) => {
+                        let mut addrs: Vec<IpAddr> = addrs.map(|a| a.ip()).collect();
+                        addrs.sort();
+                        addrs.dedup();
+                        self.resolved
+                            .send((hostname.clone(), addrs))
+                            .map_err(|_| Error::ChannelClosed("resolved hostnames".to_string()))?;
+                    }
+                    Err(e) => {
+                        // keep the last resolved addresses until the next try
+                        tracing::warn!(hostname, error=?e, "Failed to resolve the ingress hostname");
+                    }
+                }
+                self.due.insert(hostname, Instant::now() + HOSTNAME_TTL);
+            }
+
+            let next = self
+                .due
+                .values()
+                .min()
+                .copied()
+                .unwrap_or_else(|| Instant::now() + HOSTNAME_TTL);
+            tokio::select! {
+                changed = self.hostnames.changed() => {
+                    if changed.is_err() {
+                        return Err(Error::ChannelClosed("ingress hostnames".to_string()));
+                    }
+                }
+                _ = tokio::time::sleep_until(next) => {}
             }
+        }
+    }
+}
+
+/// ServiceTracker keeps the last desired state of each Service and
+/// translates Service changes into the VipEvents needed to reach the new one.
+#[derive(Debug, Default)]
+pub struct ServiceTracker {
+    // key is (namespace, name)
+    tracked: HashMap<(String, String), Vec<Lb>>,
+    // tracked Services with ingress hostnames, re-applied when their hostnames are resolved
+    with_hostnames: HashMap<(String, String), Service>,
+    // resolved addresses of ingress hostnames of the Services above
+    resolved: HashMap<String, Vec<IpAddr>>,
+    filter: ServiceFilter,
+}
+
+impl ServiceTracker {
+    pub fn new(filter: ServiceFilter) -> Self {
+        ServiceTracker {
+            filter,
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&mut self, svc: &Service) -> Vec<VipEvent> {
+        let desired = self.desired_lbs(svc);
+        self.remember_hostnames(svc);
+        self.prune_resolved();
+        self.update(service_key(svc), desired)
+    }
+
+    pub fn delete(&mut self, svc: &Service) -> Vec<VipEvent> {
+        self.with_hostnames.remove(&service_key(svc));
+        self.prune_resolved();
+        self.update(service_key(svc), Vec::new())
+    }
+
+    /// Replace the whole state with the listed Services.
+    /// Services that are tracked but not listed are assumed to be deleted.
+    pub fn restart(&mut self, svcs: &[Service]) -> Vec<VipEvent> {
+        let listed: HashMap<(String, String), Vec<Lb>> = svcs
+            .iter()
+            .map(|svc| (service_key(svc), self.desired_lbs(svc)))
+            .collect();
+        self.with_hostnames.clear();
+        for svc in svcs.iter() {
+            self.remember_hostnames(svc);
+        }
+        self.prune_resolved();
+
+        let mut vanished: Vec<(String, String)> = self
+            .tracked
+            .keys()
+            .filter(|key| !listed.contains_key(*key))
+            .cloned()
+            .collect();
+        vanished.sort();
+
+        let mut events = Vec::new();
+        for key in vanished.into_iter() {
+            events.extend(self.update(key, Vec::new()));
+        }
+        let mut listed: Vec<((String, String), Vec<Lb>)> = listed.into_iter().collect();
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, desired) in listed.into_iter() {
+            events.extend(self.update(key, desired));
+        }
+        events
+    }
+
+    /// Set the resolved addresses of the hostname and re-apply Services with it.
+    pub fn set_resolved(&mut self, hostname: String, addrs: Vec<IpAddr>) -> Vec<VipEvent> {
+        if self.resolved.get(&hostname) == Some(&addrs) || !self.hostnames().contains(&hostname) {
+            return Vec::new();
+        }
+        self.resolved.insert(hostname.clone(), addrs);
+        let mut svcs: Vec<Service> = self
+            .with_hostnames
+            .values()
+            .filter(|svc| get_lb_hostnames(svc).contains(&hostname))
+            .cloned()
+            .collect();
+        svcs.sort_by_key(service_key);
+        svcs.iter()
+            .flat_map(|svc| {
+                let desired = self.desired_lbs(svc);
+                self.update(service_key(svc), desired)
+            })
+            .collect()
+    }
+
+    /// Ingress hostnames of tracked Services.
+    pub fn hostnames(&self) -> BTreeSet<String> {
+        self.with_hostnames
+            .values()
+            .flat_map(get_lb_hostnames)
+            .collect()
+    }
+
+    fn remember_hostnames(&mut self, svc: &Service) {
+        let key = service_key(svc);
+        if self.filter.matches(svc)
+            && is_target(svc, self.filter.track_local_policy)
+            && !get_lb_hostnames(svc).is_empty()
+        {
+            self.with_hostnames.insert(key, svc.clone());
         } else {
-            return None;
+            self.with_hostnames.remove(&key);
         }
-        if let Some(etp) = svc_spec.external_traffic_policy.as_ref() {
-            if etp.eq("Local") {
-                return None;
-            }
+    }
+
+    // forget the addresses of hostnames no tracked Service has anymore
+    fn prune_resolved(&mut self) {
+        let hostnames = self.hostnames();
+        self.resolved
+            .retain(|hostname, _| hostnames.contains(hostname));
+    }
+
+    fn desired_lbs(&self, svc: &Service) -> Vec<Lb> {
+        if !self.filter.matches(svc) {
+            return Vec::new();
+        }
+        desired_lbs(svc, &self.resolved, self.filter.track_local_policy)
+    }
+
+    fn update(&mut self, key: (String, String), desired: Vec<Lb>) -> Vec<VipEvent> {
+        let current = self.tracked.remove(&key).unwrap_or_default();
+        let events = VipEvent::diff(&current, &desired);
+
+        if !desired.is_empty() {
+            self.tracked.insert(key, desired);
         }
-    } else {
-        return None;
+        events
+    }
+}
+
+fn service_key(svc: &Service) -> (String, String) {
+    (svc.namespace().unwrap_or_default(), svc.name_any())
+}
+
+fn desired_lbs(
+    svc: &Service,
+    resolved: &HashMap<String, Vec<IpAddr>>,
+    track_local: bool,
+) -> Vec<Lb> {
+    if !is_target(svc, track_local) {
+        return Vec::new();
+    }
+    let policy = traffic_policy(svc);
+    let ports = get_lb_ports(svc);
+    let mut seen = HashSet::new();
+    let mut lbs = Vec::new();
+    for (addr, source) in get_lb_addrs(svc, resolved).into_iter() {
+        // the same address may be listed by several sources, keep the first one
+        if !seen.insert(addr) {
+            continue;
+        }
+        lbs.push(Lb {
+            name: svc.name_any(),
+            namespace: svc.namespace().unwrap_or_default(),
+            addr: Some(addr),
+            source,
+            policy,
+            ports: ports.clone(),
+        });
+    }
+    lbs
+}
+
+fn get_lb_ports(svc: &Service) -> Vec<LbPort> {
+    svc.spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_ref())
+        .map(|ports| {
+            ports
+                .iter()
+                .filter_map(|p| {
+                    Some(LbPort {
+                        name: p.name.clone(),
+                        port: u16::try_from(p.port).ok()?,
+                        protocol: p.protocol.clone().unwrap_or("TCP".to_string()),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn is_target(svc: &Service, track_local: bool) -> bool {
+    if svc.spec.is_none() {
+        return false;
+    }
+    track_local || traffic_policy(svc) == TrafficPolicy::Cluster
+}
+
+fn traffic_policy(svc: &Service) -> TrafficPolicy {
+    match svc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.external_traffic_policy.as_deref())
+    {
+        Some("Local") => TrafficPolicy::Local,
+        _ => TrafficPolicy::Cluster,
     }
+}
+
+fn is_load_balancer(svc: &Service) -> bool {
+    svc.spec
+        .as_ref()
+        .and_then(|spec| spec.type_.as_ref())
+        .map(|t| t.eq("LoadBalancer"))
+        .unwrap_or(false)
+}
+
+/// Collect all addresses the Service is reachable on, in order of ingress, externalIPs and requested addresses.
+fn get_lb_addrs(
+    svc: &Service,
+    resolved: &HashMap<String, Vec<IpAddr>>,
+) -> Vec<(IpAddr, AddrSource)> {
+    let mut addrs = Vec::new();
 
-    if let Some(svc_status) = svc.status.as_ref() {
-        if let Some(lb_status) = svc_status.load_balancer.as_ref() {
-            if let Some(ingress) = lb_status.ingress.as_ref() {
-                if let Some(lb_ingress) = ingress.first() {
-                    return lb_ingress.ip.clone();
+    if is_load_balancer(svc) {
+        let ingress = svc
+            .status
+            .as_ref()
+            .and_then(|status| status.load_balancer.as_ref())
+            .and_then(|lb_status| lb_status.ingress.as_ref());
+        for lb_ingress in ingress.into_iter().flatten() {
+            if let Some(ip) = lb_ingress.ip.as_ref() {
+                if let Ok(addr) = IpAddr::from_str(ip) {
+                    addrs.push((addr, AddrSource::Ingress));
+                }
+            } else if let Some(hostname) = lb_ingress.hostname.as_ref() {
+                for addr in resolved.get(hostname).into_iter().flatten() {
+                    addrs.push((*addr, AddrSource::Ingress));
                 }
             }
         }
     }
-    None
+
+    // externalIPs are routed by kube-proxy regardless of the Service type
+    let spec = svc.spec.as_ref();
+    for ip in spec
+        .and_then(|spec| spec.external_ips.as_ref())
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(addr) = IpAddr::from_str(ip) {
+            addrs.push((addr, AddrSource::ExternalIp));
+        }
+    }
+
+    if is_load_balancer(svc) {
+        let requested = spec
+            .and_then(|spec| spec.load_balancer_ip.clone())
+            .into_iter()
+            .chain(
+                svc.annotations()
+                    .get(METALLB_LOADBALANCER_IPS_ANNOTATION)
+                    .into_iter()
+                    .flat_map(|ips| ips.split(',').map(|ip| ip.trim().to_string())),
+            );
+        for ip in requested {
+            if let Ok(addr) = IpAddr::from_str(&ip) {
+                addrs.push((addr, AddrSource::Requested));
+            }
+        }
+    }
+
+    addrs
+}
+
+fn get_lb_hostnames(svc: &Service) -> Vec<String> {
+    if !is_load_balancer(svc) {
+        return Vec::new();
+    }
+    svc.status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|lb_status| lb_status.ingress.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|lb_ingress| lb_ingress.ip.is_none())
+        .filter_map(|lb_ingress| lb_ingress.hostname.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{
+        LoadBalancerIngress, LoadBalancerStatus, ServicePort, ServiceSpec, ServiceStatus,
+    };
+    use kube::api::ObjectMeta;
+
+    use super::*;
+
+    fn service(name: &str, type_: &str, etp: &str, vip: Option<&str>) -> Service {
+        Service {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("test".to_string()),
+                ..Default::default()
+            },
+            spec: Some(ServiceSpec {
+                type_: Some(type_.to_string()),
+                external_traffic_policy: Some(etp.to_string()),
+                ports: Some(vec![ServicePort {
+                    name: Some("http".to_string()),
+                    port: 80,
+                    protocol: Some("TCP".to_string()),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            status: Some(ServiceStatus {
+                load_balancer: Some(LoadBalancerStatus {
+                    ingress: vip.map(|vip| {
+                        vec![LoadBalancerIngress {
+                            ip: Some(vip.to_string()),
+                            ..Default::default()
+                        }]
+                    }),
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn lb(name: &str, vip: &str) -> Lb {
+        Lb {
+            name: name.to_string(),
+            namespace: "test".to_string(),
+            addr: Some(IpAddr::from_str(vip).unwrap()),
+            source: AddrSource::Ingress,
+            policy: TrafficPolicy::Cluster,
+            ports: vec![LbPort {
+                name: Some("http".to_string()),
+                port: 80,
+                protocol: "TCP".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_tracker_add_and_delete() {
+        let mut tracker = ServiceTracker::default();
+        let svc = service("app", "LoadBalancer", "Cluster", Some("10.0.10.0"));
+
+        assert_eq!(
+            tracker.apply(&svc),
+            vec![VipEvent::Add(lb("app", "10.0.10.0"))]
+        );
+        // no change, no event
+        assert_eq!(tracker.apply(&svc), vec![]);
+        assert_eq!(
+            tracker.delete(&svc),
+            vec![VipEvent::Delete(lb("app", "10.0.10.0"))]
+        );
+        assert_eq!(tracker.delete(&svc), vec![]);
+    }
+
+    #[test]
+    fn test_tracker_pending_vip() {
+        let mut tracker = ServiceTracker::default();
+
+        assert_eq!(
+            tracker.apply(&service("app", "LoadBalancer", "Cluster", None)),
+            vec![]
+        );
+        assert_eq!(
+            tracker.apply(&service(
+                "app",
+                "LoadBalancer",
+                "Cluster",
+                Some("10.0.10.0")
+            )),
+            vec![VipEvent::Add(lb("app", "10.0.10.0"))]
+        );
+    }
+
+    #[test]
+    fn test_tracker_vip_change() {
+        let mut tracker = ServiceTracker::default();
+        tracker.apply(&service(
+            "app",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.0"),
+        ));
+
+        assert_eq!(
+            tracker.apply(&service(
+                "app",
+                "LoadBalancer",
+                "Cluster",
+                Some("10.0.10.1")
+            )),
+            vec![
+                VipEvent::Delete(lb("app", "10.0.10.0")),
+                VipEvent::Add(lb("app", "10.0.10.1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_type_change() {
+        let mut tracker = ServiceTracker::default();
+        tracker.apply(&service(
+            "app",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.0"),
+        ));
+
+        assert_eq!(
+            tracker.apply(&service("app", "ClusterIP", "Cluster", Some("10.0.10.0"))),
+            vec![VipEvent::Delete(lb("app", "10.0.10.0"))]
+        );
+        assert_eq!(
+            tracker.apply(&service(
+                "app",
+                "LoadBalancer",
+                "Cluster",
+                Some("10.0.10.0")
+            )),
+            vec![VipEvent::Add(lb("app", "10.0.10.0"))]
+        );
+    }
+
+    #[test]
+    fn test_tracker_etp_change() {
+        let mut tracker = ServiceTracker::default();
+        tracker.apply(&service(
+            "app",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.0"),
+        ));
+
+        assert_eq!(
+            tracker.apply(&service("app", "LoadBalancer", "Local", Some("10.0.10.0"))),
+            vec![VipEvent::Delete(lb("app", "10.0.10.0"))]
+        );
+    }
+
+    #[test]
+    fn test_tracker_restart() {
+        let mut tracker = ServiceTracker::default();
+        tracker.apply(&service(
+            "app1",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.0"),
+        ));
+        tracker.apply(&service(
+            "app2",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.1"),
+        ));
+
+        let events = tracker.restart(&[
+            service("app2", "LoadBalancer", "Cluster", Some("10.0.10.2")),
+            service("app3", "LoadBalancer", "Cluster", Some("10.0.10.3")),
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                VipEvent::Delete(lb("app1", "10.0.10.0")),
+                VipEvent::Delete(lb("app2", "10.0.10.1")),
+                VipEvent::Add(lb("app2", "10.0.10.2")),
+                VipEvent::Add(lb("app3", "10.0.10.3")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracker_multiple_addrs() {
+        let mut tracker = ServiceTracker::default();
+        let mut svc = service("app", "LoadBalancer", "Cluster", None);
+        svc.status.as_mut().unwrap().load_balancer = Some(LoadBalancerStatus {
+            ingress: Some(vec![
+                LoadBalancerIngress {
+                    ip: Some("10.0.10.0".to_string()),
+                    ..Default::default()
+                },
+                LoadBalancerIngress {
+                    ip: Some("fd00::10".to_string()),
+                    ..Default::default()
+                },
+                LoadBalancerIngress {
+                    hostname: Some("lb.example.com".to_string()),
+                    ..Default::default()
+                },
+            ]),
+        });
+        let spec = svc.spec.as_mut().unwrap();
+        spec.external_ips = Some(vec!["10.0.20.0".to_string()]);
+        // already listed in the ingress
+        spec.load_balancer_ip = Some("10.0.10.0".to_string());
+
+        let v6 = lb("app", "fd00::10");
+        let hostname = lb("app", "10.0.30.0");
+        let mut external = lb("app", "10.0.20.0");
+        external.source = AddrSource::ExternalIp;
+        assert_eq!(
+            tracker.apply(&svc),
+            vec![
+                VipEvent::Add(lb("app", "10.0.10.0")),
+                VipEvent::Add(v6.clone()),
+                VipEvent::Add(external.clone()),
+            ]
+        );
+        assert_eq!(
+            tracker.hostnames(),
+            BTreeSet::from(["lb.example.com".to_string()])
+        );
+
+        // the hostname is resolved later
+        assert_eq!(
+            tracker.set_resolved(
+                "lb.example.com".to_string(),
+                vec!["10.0.30.0".parse().unwrap()],
+            ),
+            vec![VipEvent::Add(hostname.clone())]
+        );
+        assert_eq!(
+            tracker.set_resolved(
+                "lb.example.com".to_string(),
+                vec!["10.0.30.0".parse().unwrap()],
+            ),
+            vec![]
+        );
+
+        // drop one ingress entry, the others stay tracked
+        svc.status
+            .as_mut()
+            .unwrap()
+            .load_balancer
+            .as_mut()
+            .unwrap()
+            .ingress
+            .as_mut()
+            .unwrap()
+            .remove(1);
+        assert_eq!(tracker.apply(&svc), vec![VipEvent::Delete(v6.clone())]);
+
+        assert_eq!(
+            tracker.delete(&svc),
+            vec![
+                VipEvent::Delete(lb("app", "10.0.10.0")),
+                VipEvent::Delete(hostname.clone()),
+                VipEvent::Delete(external.clone()),
+            ]
+        );
+        // the addresses of the hostname are forgotten with the Service
+        assert!(tracker.hostnames().is_empty());
+        assert!(tracker.resolved.is_empty());
+    }
+
+    #[test]
+    fn test_tracker_external_ips_on_cluster_ip() {
+        let mut tracker = ServiceTracker::default();
+        let mut svc = service("app", "ClusterIP", "Cluster", None);
+        svc.spec.as_mut().unwrap().external_ips = Some(vec!["10.0.20.0".to_string()]);
+
+        let mut external = lb("app", "10.0.20.0");
+        external.source = AddrSource::ExternalIp;
+        assert_eq!(tracker.apply(&svc), vec![VipEvent::Add(external)]);
+    }
+
+    #[test]
+    fn test_tracker_filter() {
+        let mut tracker = ServiceTracker::new(ServiceFilter {
+            exclude_namespaces: vec!["kube-system".to_string()],
+            opt_in: true,
+            load_balancer_classes: vec!["metallb".to_string()],
+            ..Default::default()
+        });
+        let mut svc = service("app", "LoadBalancer", "Cluster", Some("10.0.10.0"));
+        svc.spec.as_mut().unwrap().load_balancer_class = Some("metallb".to_string());
+
+        // not opted in
+        assert_eq!(tracker.apply(&svc), vec![]);
+
+        svc.metadata.annotations = Some(
+            [(TRACK_ANNOTATION.to_string(), "true".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            tracker.apply(&svc),
+            vec![VipEvent::Add(lb("app", "10.0.10.0"))]
+        );
+
+        // moving to another class stops tracking
+        svc.spec.as_mut().unwrap().load_balancer_class = Some("other".to_string());
+        assert_eq!(
+            tracker.apply(&svc),
+            vec![VipEvent::Delete(lb("app", "10.0.10.0"))]
+        );
+
+        svc.spec.as_mut().unwrap().load_balancer_class = Some("metallb".to_string());
+        svc.metadata.namespace = Some("kube-system".to_string());
+        assert_eq!(tracker.apply(&svc), vec![]);
+    }
+
+    #[test]
+    fn test_tracker_track_local_policy() {
+        let mut tracker = ServiceTracker::new(ServiceFilter {
+            track_local_policy: true,
+            ..Default::default()
+        });
+        let mut local = lb("app", "10.0.10.0");
+        local.policy = TrafficPolicy::Local;
+
+        tracker.apply(&service(
+            "app",
+            "LoadBalancer",
+            "Cluster",
+            Some("10.0.10.0"),
+        ));
+        assert_eq!(
+            tracker.apply(&service("app", "LoadBalancer", "Local", Some("10.0.10.0"))),
+            vec![
+                VipEvent::Delete(lb("app", "10.0.10.0")),
+                VipEvent::Add(local),
+            ]
+        );
+    }
 }
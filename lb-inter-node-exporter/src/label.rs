@@ -0,0 +1,315 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+pub const OTHER: &str = "other";
+// the top-K set is picked from K * CANDIDATES_PER_LABEL clients counted by the space-saving algorithm
+const CANDIDATES_PER_LABEL: usize = 4;
+
+/// SrcLabelMode is how the client address is represented in the `src` label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SrcLabelMode {
+    /// Keep the raw client address
+    #[default]
+    Keep,
+    /// Drop the label (always empty)
+    Drop,
+    /// Mask the client address to the prefix length
+    Prefix,
+    /// Map the client address to the named CIDR group
+    Group,
+    /// Keep the K most frequent client addresses and aggregate the rest to `other`
+    TopK,
+}
+
+/// SrcGroup is a named set of CIDRs, given as `name=cidr[,cidr...]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrcGroup {
+    pub name: String,
+    pub cidrs: Vec<IpNet>,
+}
+
+impl FromStr for SrcGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, cidrs) = s.split_once('=').ok_or(format!(
+            "invalid source group {s}: expected name=cidr[,cidr...]"
+        ))?;
+        let cidrs = cidrs
+            .split(',')
+            .map(|c| IpNet::from_str(c.trim()).map_err(|e| format!("invalid cidr {c}: {e}")))
+            .collect::<Result<Vec<IpNet>, String>>()?;
+        Ok(SrcGroup {
+            name: name.to_string(),
+            cidrs,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SrcLabelPolicy {
+    pub mode: SrcLabelMode,
+    pub prefix_v4: u8,
+    pub prefix_v6: u8,
+    pub groups: Vec<SrcGroup>,
+    pub top_k: usize,
+    // a client not seen for this period is evicted first from the top-K set
+    pub idle_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    // estimated count of connections, never underestimated
+    count: u64,
+    last_seen: Instant,
+}
+
+/// SrcLabeler translates client addresses to `src` label values according to the policy.
+///
+/// In the top-K mode, connections of clients are counted with the space-saving algorithm,
+/// and a client replaces the least frequent one in the top-K set once it has more connections.
+/// Clients idle for `idle_timeout` are replaced regardless of their counts.
+#[derive(Debug)]
+pub struct SrcLabeler {
+    policy: SrcLabelPolicy,
+    candidates: HashMap<IpAddr, Candidate>,
+    // clients labeled with their own addresses
+    top: HashSet<IpAddr>,
+}
+
+impl SrcLabeler {
+    pub fn new(policy: SrcLabelPolicy) -> Self {
+        SrcLabeler {
+            policy,
+            candidates: HashMap::new(),
+            top: HashSet::new(),
+        }
+    }
+
+    /// Return the label value for the client and label values evicted to make room for it.
+    pub fn label(&mut self, src: IpAddr, now: Instant) -> (String, Vec<String>) {
+        match self.policy.mode {
+            SrcLabelMode::Keep => (src.to_string(), Vec::new()),
+            SrcLabelMode::Drop => (String::new(), Vec::new()),
+            SrcLabelMode::Prefix => {
+                let prefix = match src {
+                    IpAddr::V4(_) => self.policy.prefix_v4,
+                    IpAddr::V6(_) => self.policy.prefix_v6,
+                };
+                let label = IpNet::new(src, prefix)
+                    .map(|net| net.trunc().to_string())
+                    .unwrap_or(src.to_string());
+                (label, Vec::new())
+            }
+            SrcLabelMode::Group => (self.group(src), Vec::new()),
+            SrcLabelMode::TopK => self.top_k(src, now),
+        }
+    }
+
+    // longest prefix match against configured groups
+    fn group(&self, src: IpAddr) -> String {
+        self.policy
+            .groups
+            .iter()
+            .flat_map(|g| g.cidrs.iter().map(move |c| (g, c)))
+            .filter(|(_, c)| c.contains(&src))
+            .max_by_key(|(_, c)| c.prefix_len())
+            .map(|(g, _)| g.name.clone())
+            .unwrap_or(OTHER.to_string())
+    }
+
+    fn top_k(&mut self, src: IpAddr, now: Instant) -> (String, Vec<String>) {
+        if self.policy.top_k == 0 {
+            return (OTHER.to_string(), Vec::new());
+        }
+        let count = self.count(src, now);
+        if self.top.contains(&src) {
+            return (src.to_string(), Vec::new());
+        }
+        if self.top.len() < self.policy.top_k {
+            self.top.insert(src);
+            return (src.to_string(), Vec::new());
+        }
+
+        // replace an idle client first, otherwise the least frequent one with fewer connections
+        let idle = self
+            .top
+            .iter()
+            .filter_map(|addr| Some((*addr, self.candidates.get(addr)?)))
+            .filter(|(_, c)| now.duration_since(c.last_seen) >= self.policy.idle_timeout)
+            .min_by_key(|(_, c)| c.last_seen)
+            .map(|(addr, _)| addr);
+        let victim = idle.or_else(|| {
+            self.top
+                .iter()
+                .map(|addr| (*addr, self.candidates.get(addr).map_or(0, |c| c.count)))
+                .min_by_key(|(_, count)| *count)
+                .filter(|(_, min)| count > *min)
+                .map(|(addr, _)| addr)
+        });
+        match victim {
+            Some(victim) => {
+                self.top.remove(&victim);
+                self.top.insert(src);
+                (src.to_string(), vec![victim.to_string()])
+            }
+            None => (OTHER.to_string(), Vec::new()),
+        }
+    }
+
+    // Count the connection of the client and return its estimated count.
+    fn count(&mut self, src: IpAddr, now: Instant) -> u64 {
+        if let Some(candidate) = self.candidates.get_mut(&src) {
+            candidate.count += 1;
+            candidate.last_seen = now;
+            return candidate.count;
+        }
+        let capacity = self.policy.top_k.saturating_mul(CANDIDATES_PER_LABEL);
+        let mut count = 1;
+        if self.candidates.len() >= capacity {
+            // the new client takes over the least frequent candidate not in the top-K set
+            let min = self
+                .candidates
+                .iter()
+                .filter(|(addr, _)| !self.top.contains(*addr))
+                .min_by_key(|(_, c)| c.count)
+                .map(|(addr, c)| (*addr, c.count));
+            if let Some((addr, min)) = min {
+                self.candidates.remove(&addr);
+                count = min + 1;
+            }
+        }
+        self.candidates.insert(
+            src,
+            Candidate {
+                count,
+                last_seen: now,
+            },
+        );
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeler(mode: SrcLabelMode, top_k: usize) -> SrcLabeler {
+        SrcLabeler::new(SrcLabelPolicy {
+            mode,
+            prefix_v4: 16,
+            prefix_v6: 48,
+            groups: vec![
+                SrcGroup::from_str("office=192.168.0.0/16,fd00::/16").unwrap(),
+                SrcGroup::from_str("lab=192.168.10.0/24").unwrap(),
+            ],
+            top_k,
+            idle_timeout: Duration::from_secs(60),
+        })
+    }
+
+    fn label(labeler: &mut SrcLabeler, src: &str, now: Instant) -> (String, Vec<String>) {
+        labeler.label(src.parse().unwrap(), now)
+    }
+
+    #[test]
+    fn test_label_modes() {
+        let now = Instant::now();
+        let mut keep = labeler(SrcLabelMode::Keep, 0);
+        assert_eq!(label(&mut keep, "192.168.1.2", now).0, "192.168.1.2");
+        let mut drop = labeler(SrcLabelMode::Drop, 0);
+        assert_eq!(label(&mut drop, "192.168.1.2", now).0, "");
+
+        let mut prefix = labeler(SrcLabelMode::Prefix, 0);
+        assert_eq!(label(&mut prefix, "192.168.1.2", now).0, "192.168.0.0/16");
+        assert_eq!(label(&mut prefix, "fd00:1:2:3::4", now).0, "fd00:1:2::/48");
+
+        let mut group = labeler(SrcLabelMode::Group, 0);
+        assert_eq!(label(&mut group, "192.168.1.2", now).0, "office");
+        // the longest prefix wins
+        assert_eq!(label(&mut group, "192.168.10.2", now).0, "lab");
+        assert_eq!(label(&mut group, "fd00::1", now).0, "office");
+        assert_eq!(label(&mut group, "10.0.0.1", now).0, OTHER);
+    }
+
+    #[test]
+    fn test_src_group_from_str() {
+        assert_eq!(
+            SrcGroup::from_str("office=192.168.0.0/16, 10.0.0.0/8").unwrap(),
+            SrcGroup {
+                name: "office".to_string(),
+                cidrs: vec![
+                    "192.168.0.0/16".parse().unwrap(),
+                    "10.0.0.0/8".parse().unwrap()
+                ],
+            }
+        );
+        assert!(SrcGroup::from_str("192.168.0.0/16").is_err());
+        assert!(SrcGroup::from_str("office=192.168.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_label_top_k_frequency() {
+        let now = Instant::now();
+        let mut labeler = labeler(SrcLabelMode::TopK, 2);
+        assert_eq!(
+            label(&mut labeler, "10.0.0.1", now),
+            ("10.0.0.1".to_string(), vec![])
+        );
+        assert_eq!(
+            label(&mut labeler, "10.0.0.2", now),
+            ("10.0.0.2".to_string(), vec![])
+        );
+        label(&mut labeler, "10.0.0.1", now);
+
+        // a new client is not more frequent than the top-K set yet
+        assert_eq!(
+            label(&mut labeler, "10.0.0.3", now),
+            (OTHER.to_string(), vec![])
+        );
+        // it replaces the least frequent client once it has more connections
+        assert_eq!(
+            label(&mut labeler, "10.0.0.3", now),
+            ("10.0.0.3".to_string(), vec!["10.0.0.2".to_string()])
+        );
+        assert_eq!(label(&mut labeler, "10.0.0.1", now).0, "10.0.0.1");
+        assert_eq!(label(&mut labeler, "10.0.0.2", now).0, OTHER);
+    }
+
+    #[test]
+    fn test_label_top_k_idle() {
+        let now = Instant::now();
+        let mut labeler = labeler(SrcLabelMode::TopK, 1);
+        for _ in 0..10 {
+            label(&mut labeler, "10.0.0.1", now);
+        }
+        assert_eq!(label(&mut labeler, "10.0.0.2", now).0, OTHER);
+
+        // the heavy client is replaced after it becomes idle
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            label(&mut labeler, "10.0.0.2", later),
+            ("10.0.0.2".to_string(), vec!["10.0.0.1".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_label_top_k_candidates_bounded() {
+        let now = Instant::now();
+        let mut labeler = labeler(SrcLabelMode::TopK, 2);
+        for i in 0..100u8 {
+            label(&mut labeler, &format!("10.0.1.{i}"), now);
+        }
+        assert_eq!(labeler.candidates.len(), 2 * CANDIDATES_PER_LABEL);
+        assert_eq!(labeler.top.len(), 2);
+    }
+}
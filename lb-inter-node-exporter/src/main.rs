@@ -1,42 +1,151 @@
-use std::net::{IpAddr, Ipv4Addr};
-use std::os::fd::AsFd;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_web::web::Data;
 use actix_web::{get, middleware, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use anyhow::Context;
-use aya::maps::{HashMap, Map, MapData, RingBuf};
+use aya::maps::{HashMap, LpmTrie, MapData, PerCpuArray, RingBuf};
 use aya::programs::{Xdp, XdpFlags};
 use aya::{include_bytes_aligned, Bpf};
 use aya_log::BpfLogger;
 use clap::Parser;
 use iface::get_ifaces;
-use kubernetes::VipEvent;
-use lb_inter_node_exporter_common::Ipv4Event;
-use log::{debug, info, warn};
+use log::{debug, warn};
+use parking_lot::{Mutex, RwLock};
 use prometheus::{Encoder, TextEncoder};
-use tokio::signal;
-use tokio::sync::mpsc::unbounded_channel;
-
+use tokio::sync::{
+    mpsc::{channel, unbounded_channel},
+    watch,
+};
+
+use crate::api::AttachedInterface;
+use crate::cli::Command;
+use crate::config::{Config, ConfigWatcher, FixedConfig, LiveConfig};
+use crate::conntrack::{ConntrackTable, ConntrackWatcher};
+use crate::endpoints::{EndpointStore, EndpointWatcher};
 use crate::error::Error;
+use crate::event::{EventProcessor, EventReader, PendingEvents, EVENT_QUEUE_CAPACITY};
+use crate::feed::EventFeed;
+use crate::grpc::{ExporterService, GrpcServer};
+use crate::health::{Health, EVENT_CONSUMER, VIP_SOURCE, VIP_SYNC};
+use crate::ipfix::IpfixExporter;
 use crate::kubernetes::ServiceWatcher;
-use crate::trace::Metrics;
-
+use crate::label::{SrcGroup, SrcLabelMode, SrcLabelPolicy, SrcLabeler};
+use crate::otlp::{OtlpMetrics, OtlpProtocol};
+use crate::otlplogs::OtlpLogs;
+use crate::progstats::ProgramStatsCollector;
+use crate::range::{parse_ranges, PoolWatcher, VipRanges, STATIC_POOL};
+use crate::source::{FileSource, StaticSource, StaticVip, VipSource, VipSourceKind};
+use crate::supervisor::Supervisor;
+use crate::trace::{AgentMetrics, Metrics, SeriesGc};
+use crate::vip::{VipSync, VipTable};
+
+mod api;
+mod cli;
+mod config;
+mod conntrack;
+mod endpoints;
 mod error;
+mod event;
+mod feed;
+mod grpc;
+mod health;
 mod iface;
+mod ipfix;
 mod kubernetes;
+mod label;
+mod otlp;
+mod otlplogs;
+mod progstats;
+mod range;
+mod source;
+mod supervisor;
+mod top;
 mod trace;
+mod vip;
+
+const PROGRAM_NAME: &str = "lb_inter_node_exporter";
 
 #[derive(Debug, Parser)]
 struct Cmd {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(short = 'i', long, default_value = "eth0")]
     iface: Vec<String>,
 
-    #[clap(long = "log-level", default_value = "info")]
+    #[clap(long = "log-level", default_value = "info", value_parser = trace::parse_log_level)]
     log_level: String,
 
-    #[clap(long = "metrics-endpoint", default_value = "http://localhost:61678")]
-    metrics_endpoint: String,
+    #[clap(
+        long = "otlp-traces-endpoint",
+        alias = "metrics-endpoint",
+        help = "OTLP endpoint to export traces to (disabled if not set)"
+    )]
+    otlp_traces_endpoint: Option<String>,
+
+    #[clap(
+        long = "otlp-traces-protocol",
+        value_enum,
+        default_value_t = OtlpProtocol::Grpc,
+        help = "Transport of the OTLP traces exporter"
+    )]
+    otlp_traces_protocol: OtlpProtocol,
+
+    #[clap(
+        long = "otlp-metrics-endpoint",
+        help = "OTLP endpoint to export metrics to (disabled if not set)"
+    )]
+    otlp_metrics_endpoint: Option<String>,
+
+    #[clap(
+        long = "otlp-metrics-protocol",
+        value_enum,
+        default_value_t = OtlpProtocol::Grpc,
+        help = "Transport of the OTLP metrics exporter"
+    )]
+    otlp_metrics_protocol: OtlpProtocol,
+
+    #[clap(
+        long = "otlp-logs-endpoint",
+        help = "OTLP endpoint to export connection events to as logs (disabled if not set)"
+    )]
+    otlp_logs_endpoint: Option<String>,
+
+    #[clap(
+        long = "otlp-logs-protocol",
+        value_enum,
+        default_value_t = OtlpProtocol::Grpc,
+        help = "Transport of the OTLP logs exporter"
+    )]
+    otlp_logs_protocol: OtlpProtocol,
+
+    #[clap(
+        long = "otlp-metrics-interval",
+        default_value = "60",
+        help = "Seconds between exports of OTLP metrics"
+    )]
+    otlp_metrics_interval: u64,
+
+    #[clap(
+        long = "ipfix-collector",
+        help = "IPFIX collector (host:port) to export connection events to over UDP (disabled if not set)"
+    )]
+    ipfix_collector: Option<String>,
+
+    #[clap(
+        long = "ipfix-observation-domain",
+        help = "IPFIX observation domain ID (derived from the node name if not set)"
+    )]
+    ipfix_observation_domain: Option<u32>,
+
+    #[clap(
+        long = "ipfix-template-refresh",
+        default_value = "600",
+        help = "Seconds between resending IPFIX templates"
+    )]
+    ipfix_template_refresh: u64,
 
     #[clap(short = 'p', long = "port", default_value = "8080")]
     port: u32,
@@ -47,11 +156,190 @@ struct Cmd {
         help = "XDP mode(native, hw, skb)"
     )]
     xdp_mode: String,
+
+    #[clap(
+        long = "vip-source",
+        value_enum,
+        default_value_t = VipSourceKind::Kubernetes,
+        help = "Where to learn VIPs to track from"
+    )]
+    vip_source: VipSourceKind,
+
+    #[clap(
+        long = "vip",
+        help = "VIP to track with --vip-source=static ([[namespace/]name=]addr:port[,port...])"
+    )]
+    vips: Vec<StaticVip>,
+
+    #[clap(
+        long = "vip-file",
+        help = "YAML or JSON file listing VIPs to track with --vip-source=file"
+    )]
+    vip_file: Option<PathBuf>,
+
+    #[clap(
+        long = "vip-file-interval",
+        default_value = "5",
+        help = "Seconds between checks of --vip-file for changes"
+    )]
+    vip_file_interval: u64,
+
+    #[clap(
+        long = "vip-range",
+        help = "VIP range to observe as a whole in the XDP program (CIDR or start-end)"
+    )]
+    vip_ranges: Vec<String>,
+
+    #[clap(
+        long = "metallb-pools",
+        help = "Observe address ranges of MetalLB IPAddressPools as VIP ranges"
+    )]
+    metallb_pools: bool,
+
+    #[clap(
+        long = "namespace",
+        help = "Track Services only in these namespaces (all namespaces if empty)"
+    )]
+    namespaces: Vec<String>,
+
+    #[clap(
+        long = "exclude-namespace",
+        help = "Ignore Services in these namespaces"
+    )]
+    exclude_namespaces: Vec<String>,
+
+    #[clap(long = "selector", help = "Label selector for Services to track")]
+    label_selector: Option<String>,
+
+    #[clap(
+        long = "opt-in",
+        help = "Track only Services annotated with lb-inter-node-exporter.terassyi.net/track=true"
+    )]
+    opt_in: bool,
+
+    #[clap(
+        long = "load-balancer-class",
+        help = "Track only Services with these loadBalancerClass (all classes if empty)"
+    )]
+    load_balancer_classes: Vec<String>,
+
+    #[clap(
+        long = "track-local-policy",
+        help = "Track Services with externalTrafficPolicy=Local to detect misrouted traffic"
+    )]
+    track_local_policy: bool,
+
+    #[clap(
+        long = "node-name",
+        env = "NODE_NAME",
+        default_value = "",
+        help = "The name of the node this agent runs on"
+    )]
+    node_name: String,
+
+    #[clap(
+        long = "conntrack",
+        help = "Correlate connections with conntrack entries to identify backends (required to classify connections to Services with endpoints on this and other nodes)"
+    )]
+    conntrack: bool,
+
+    #[clap(
+        long = "src-label",
+        value_enum,
+        default_value_t = SrcLabelMode::Keep,
+        help = "How to represent the client address in the src label"
+    )]
+    src_label: SrcLabelMode,
+
+    #[clap(
+        long = "src-prefix-v4",
+        default_value = "24",
+        value_parser = clap::value_parser!(u8).range(0..=32),
+        help = "Prefix length to mask IPv4 client addresses with --src-label=prefix"
+    )]
+    src_prefix_v4: u8,
+
+    #[clap(
+        long = "src-prefix-v6",
+        default_value = "64",
+        value_parser = clap::value_parser!(u8).range(0..=128),
+        help = "Prefix length to mask IPv6 client addresses with --src-label=prefix"
+    )]
+    src_prefix_v6: u8,
+
+    #[clap(
+        long = "src-group",
+        help = "Named CIDR group for --src-label=group (name=cidr[,cidr...])"
+    )]
+    src_groups: Vec<SrcGroup>,
+
+    #[clap(
+        long = "src-top-k",
+        default_value = "100",
+        help = "The number of client addresses kept with --src-label=top-k"
+    )]
+    src_top_k: usize,
+
+    #[clap(
+        long = "src-idle-timeout",
+        default_value = "300",
+        help = "Seconds after which an idle client is replaced regardless of its count with --src-label=top-k"
+    )]
+    src_idle_timeout: u64,
+
+    #[clap(
+        long = "series-ttl",
+        default_value = "3600",
+        help = "Seconds after which metric series not seen are removed (0 to disable)"
+    )]
+    series_ttl: u64,
+
+    #[clap(
+        long = "program-stats-interval",
+        default_value = "10",
+        help = "Seconds between reads of the XDP program runtime stats (0 to disable)"
+    )]
+    program_stats_interval: u64,
+
+    #[clap(
+        long = "grpc-port",
+        default_value = "0",
+        help = "Port to serve the gRPC API (0 to disable)"
+    )]
+    grpc_port: u16,
+
+    #[clap(
+        long = "recent-events",
+        default_value = "1000",
+        help = "The number of recent events kept for /api/v1/events/recent"
+    )]
+    recent_events: usize,
+
+    #[clap(
+        long = "sample-rate",
+        default_value = "1.0",
+        help = "The fraction of connection events to process (0.0 to 1.0), picked_total and misrouted_total count only the sampled events"
+    )]
+    sample_rate: f64,
+
+    #[clap(
+        long = "config",
+        help = "TOML or YAML config file overriding flags, reloaded when it changes"
+    )]
+    config: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Clone, Default)]
 pub struct State {
     registry: prometheus::Registry,
+    health: Health,
+    feed: EventFeed,
+    vips: Arc<RwLock<VipTable>>,
+    ipv4_vips: Option<Arc<Mutex<HashMap<MapData, u32, u32>>>>,
+    event_drops: Option<Arc<Mutex<PerCpuArray<MapData, u64>>>>,
+    vip_ranges: Option<Arc<Mutex<VipRanges>>>,
+    metrics: Metrics,
+    interfaces: Vec<AttachedInterface>,
 }
 
 impl State {
@@ -62,9 +350,36 @@ impl State {
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let cmd = Cmd::parse();
+    let mut cmd = Cmd::parse();
+    if let Some(command) = cmd.command.take() {
+        return cli::run(command).await;
+    }
 
-    trace::prepare_tracing(&cmd.log_level, &cmd.metrics_endpoint);
+    // flags the live part of the config file overrides on reloading
+    let base_config = LiveConfig::from_cmd(&cmd);
+    let config = cmd.config.as_deref().map(Config::load).transpose()?;
+    if let Some(config) = config.as_ref() {
+        config.apply(&mut cmd)?;
+    }
+    if !(0.0..=1.0).contains(&cmd.sample_rate) {
+        anyhow::bail!("--sample-rate must be between 0 and 1");
+    }
+    let static_ranges =
+        parse_ranges(&cmd.vip_ranges).map_err(|e| anyhow::anyhow!("--vip-range: {e}"))?;
+    let live_config = LiveConfig::from_cmd(&cmd);
+
+    let tracer = cmd
+        .otlp_traces_endpoint
+        .as_deref()
+        .map(|endpoint| {
+            otlp::tracer(
+                endpoint,
+                cmd.otlp_traces_protocol,
+                otlp::resource(&cmd.node_name),
+            )
+        })
+        .transpose()?;
+    let log_level = trace::prepare_tracing(&cmd.log_level, tracer);
 
     let target_ifaces = get_ifaces(&cmd.iface).await.unwrap();
 
@@ -85,22 +400,52 @@ async fn main() -> Result<(), anyhow::Error> {
     // reach for `Bpf::load_file` instead.
 
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/lb-inter-node-exporter"
-    ))?;
+    let bpf_object =
+        include_bytes_aligned!("../../target/bpfel-unknown-none/debug/lb-inter-node-exporter");
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/lb-inter-node-exporter"
-    ))?;
+    let bpf_object =
+        include_bytes_aligned!("../../target/bpfel-unknown-none/release/lb-inter-node-exporter");
+    let mut bpf = Bpf::load(bpf_object)?;
+
+    let mut state = State {
+        feed: EventFeed::new(cmd.recent_events),
+        ..Default::default()
+    };
+    let agent_metrics = AgentMetrics::default().register(&state.registry).unwrap();
+    let (supervisor, mut fatal) = Supervisor::new(agent_metrics.clone());
+    agent_metrics.build_info(bpf_object);
+
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut Xdp = bpf
-        .program_mut("lb_inter_node_exporter")
-        .unwrap()
-        .try_into()?;
+    let program: &mut Xdp = bpf.program_mut(PROGRAM_NAME).unwrap().try_into()?;
     program.load()?;
+    match program.info() {
+        Ok(program_info) => {
+            let program_id = program_info.id();
+            agent_metrics.program_verified_instructions(
+                PROGRAM_NAME,
+                &program_id.to_string(),
+                program_info.verified_instruction_count(),
+            );
+            if cmd.program_stats_interval > 0 {
+                let program_fd = program.fd()?.try_clone().map_err(Error::StdIo)?;
+                let collector = ProgramStatsCollector::new(
+                    PROGRAM_NAME,
+                    program_id,
+                    program_fd,
+                    Duration::from_secs(cmd.program_stats_interval),
+                    agent_metrics.clone(),
+                );
+                supervisor.spawn("program_stats", collector);
+            }
+        }
+        Err(e) => {
+            // the stats are optional, e.g. on kernels without BPF_OBJ_GET_INFO_BY_FD for programs
+            tracing::warn!(error=?e, "Failed to get the program info, program stats are disabled");
+        }
+    }
     let xdp_flag = get_xdp_mode(&cmd.xdp_mode);
     for iface in target_ifaces.iter() {
         program.attach(&iface.name, xdp_flag)
@@ -110,91 +455,251 @@ async fn main() -> Result<(), anyhow::Error> {
             ifindex = iface.index,
             "Attach the XDP program"
         );
+        agent_metrics.attached_interface(&iface.name, iface.index, xdp_mode_name(&cmd.xdp_mode));
+        state.health.attached(&iface.name);
+        state.interfaces.push(AttachedInterface {
+            name: iface.name.clone(),
+            index: iface.index,
+            mode: xdp_mode_name(&cmd.xdp_mode),
+        });
     }
 
-    let mut ipv4_vips = HashMap::try_from(bpf.take_map("IPV4VIP").expect("failed to get IPV4VIP"))?;
-    let mut ipv4_events =
-        RingBuf::try_from(bpf.take_map("IPV4EVENT").expect("failed to get IPV4EVENT"))?;
-
-    let state = State::default();
-
-    let (event_send, mut event_recv) = unbounded_channel();
-
-    tokio::spawn(async move {
-        let mut svc_map = std::collections::HashMap::<(String, String), IpAddr>::new();
-
-        while let Some(event) = event_recv.recv().await {
-            // poll vip events
-            match event {
-                VipEvent::Add(lb) => {
-                    // add Vip
-                    tracing::info!(
-                        name = lb.name,
-                        namespace = lb.namespace,
-                        vip =? lb.addr,
-                        "Add to track VIP"
-                    );
-                    svc_map.insert((lb.name.clone(), lb.namespace.clone()), lb.addr.unwrap());
-                    match lb.addr.unwrap() {
-                        IpAddr::V4(addr) => {
-                            let addr_num: u32 = u32::from(addr);
-                            ipv4_vips.insert(addr_num, 0, 0);
-                        }
-                        IpAddr::V6(_addr) => {
-                            // not implemented
-                        }
-                    }
-                }
-                VipEvent::Delete(lb) => {
-                    // delete Vip
-                    tracing::info!(
-                        name = lb.name,
-                        namespace = lb.namespace,
-                        vip =? lb.addr,
-                        "Delete the tracking VIP"
-                    );
-                    if let Some(addr) = svc_map.remove(&(lb.name.clone(), lb.namespace.clone())) {
-                        match addr {
-                            IpAddr::V4(addr) => {
-                                let addr_num: u32 = u32::from(addr);
-                                ipv4_vips.remove(&addr_num);
-                            }
-                            IpAddr::V6(_addr) => {
-                                // not implemented
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    });
+    let ipv4_vips = Arc::new(Mutex::new(HashMap::try_from(
+        bpf.take_map("IPV4VIP")
+            .ok_or(Error::FailedGetEBPFMap("IPV4VIP".to_string()))?,
+    )?));
+    state.ipv4_vips = Some(ipv4_vips.clone());
+    let event_drops = Arc::new(Mutex::new(PerCpuArray::try_from(
+        bpf.take_map("EVENT_DROPS")
+            .ok_or(Error::FailedGetEBPFMap("EVENT_DROPS".to_string()))?,
+    )?));
+    state.event_drops = Some(event_drops.clone());
+    let vip_ranges = Arc::new(Mutex::new(VipRanges::new(
+        LpmTrie::try_from(
+            bpf.take_map("IPV4VIP_RANGE")
+                .ok_or(Error::FailedGetEBPFMap("IPV4VIP_RANGE".to_string()))?,
+        )?,
+        agent_metrics.clone(),
+    )));
+    vip_ranges.lock().set(STATIC_POOL, &static_ranges);
+    if cmd.metallb_pools {
+        let pool_watcher = PoolWatcher::new(vip_ranges.clone(), agent_metrics.clone()).await?;
+        supervisor.spawn("pool_watcher", pool_watcher);
+    }
+    state.vip_ranges = Some(vip_ranges.clone());
+    let ipv4_events = RingBuf::try_from(
+        bpf.take_map("IPV4EVENT")
+            .ok_or(Error::FailedGetEBPFMap("IPV4EVENT".to_string()))?,
+    )?;
 
-    let svc_watcher = ServiceWatcher::new(event_send.clone()).await;
+    let (event_send, event_recv) = unbounded_channel();
 
-    tokio::spawn(async move {
-        svc_watcher.run().await.expect("Got error");
-    });
+    let vips = state.vips.clone();
 
     let metrics_collector = Metrics::default().register(&state.registry).unwrap();
+    state.metrics = metrics_collector.clone();
+
+    let meter_provider = match cmd.otlp_metrics_endpoint.as_deref() {
+        Some(endpoint) => {
+            let interval = Duration::from_secs(cmd.otlp_metrics_interval.max(1));
+            let provider = otlp::meter_provider(
+                endpoint,
+                cmd.otlp_metrics_protocol,
+                interval,
+                otlp::resource(&cmd.node_name),
+            )?;
+            supervisor.spawn(
+                "otlp_metrics",
+                OtlpMetrics::new(state.registry.clone(), &provider, interval),
+            );
+            Some(provider)
+        }
+        None => None,
+    };
+    if let Some(endpoint) = cmd.otlp_logs_endpoint.as_deref() {
+        let exporter = OtlpLogs::new(
+            endpoint,
+            cmd.otlp_logs_protocol,
+            state.feed.clone(),
+            cmd.node_name.clone(),
+            agent_metrics.clone(),
+        )?;
+        supervisor.spawn("otlp_logs", exporter);
+    }
+    if let Some(collector) = cmd.ipfix_collector.clone() {
+        let domain = cmd
+            .ipfix_observation_domain
+            .unwrap_or_else(|| ipfix::observation_domain(&cmd.node_name));
+        supervisor.spawn(
+            "ipfix_exporter",
+            IpfixExporter::new(
+                state.feed.clone(),
+                collector,
+                domain,
+                Duration::from_secs(cmd.ipfix_template_refresh),
+                agent_metrics.clone(),
+            ),
+        );
+    }
+    supervisor.spawn(
+        VIP_SYNC,
+        VipSync {
+            events: event_recv,
+            vips: vips.clone(),
+            ipv4_vips,
+            metrics: metrics_collector.clone(),
+            agent_metrics: agent_metrics.clone(),
+            health: state.health.component(VIP_SYNC),
+        },
+    );
+
+    let (filter_send, filter_recv) = watch::channel(live_config.filter.clone());
+    let (sample_rate_send, sample_rate_recv) = watch::channel(live_config.sample_rate);
+    if let Some(config) = config {
+        supervisor.spawn(
+            "config_watcher",
+            ConfigWatcher::new(
+                base_config,
+                FixedConfig::from_cmd(&cmd),
+                config,
+                log_level,
+                filter_send,
+                sample_rate_send,
+                agent_metrics.clone(),
+            ),
+        );
+    }
+    let endpoints = Arc::new(RwLock::new(EndpointStore::default()));
+    let vip_source = match cmd.vip_source {
+        VipSourceKind::Kubernetes => {
+            let (svc_watcher, resolver) = ServiceWatcher::new(
+                event_send.clone(),
+                filter_recv.clone(),
+                agent_metrics.clone(),
+                state.health.component(VIP_SOURCE),
+            )
+            .await?;
 
-    tokio::spawn(async move {
-        loop {
-            if let Some(event) = ipv4_events.next() {
-                let ipv4_event: Ipv4Event = (*event).into();
-                let src_addr = u32_to_addr(ipv4_event.src_addr);
-                let dst_addr = u32_to_addr(ipv4_event.dst_addr);
-                tracing::info!(src_addr=?src_addr, dst_addr=?dst_addr, src_port = ipv4_event.src_port, dst_port = ipv4_event.dst_port, "Received by intermediate node");
-                metrics_collector.picked_total(IpAddr::V4(src_addr), IpAddr::V4(dst_addr));
+            FixedConfig::from_cmd(&cmd).validate(&live_config)?;
+            if cmd.node_name.is_empty() {
+                tracing::warn!(
+                    "--node-name or NODE_NAME is not set, the delivery of connections is unknown"
+                );
+            }
+            let endpoint_watcher =
+                EndpointWatcher::new(endpoints.clone(), filter_recv, agent_metrics.clone()).await?;
+            supervisor.spawn("endpoint_watcher", endpoint_watcher);
+            supervisor.spawn("hostname_resolver", resolver);
+            VipSource::Kubernetes(Box::new(svc_watcher))
+        }
+        VipSourceKind::Static => {
+            if cmd.vips.is_empty() {
+                anyhow::bail!("--vip is required with --vip-source=static");
             }
+            VipSource::Static(StaticSource::new(
+                &cmd.vips,
+                event_send.clone(),
+                state.health.component(VIP_SOURCE),
+            ))
         }
-    });
+        VipSourceKind::File => {
+            let Some(path) = cmd.vip_file.clone() else {
+                anyhow::bail!("--vip-file is required with --vip-source=file");
+            };
+            VipSource::File(FileSource::new(
+                path,
+                Duration::from_secs(cmd.vip_file_interval.max(1)),
+                event_send.clone(),
+                state.health.component(VIP_SOURCE),
+            ))
+        }
+    };
+    supervisor.spawn(VIP_SOURCE, vip_source);
+    let node_name = cmd.node_name.clone();
+
+    let conntrack = if cmd.conntrack {
+        let table = Arc::new(ConntrackTable::new(Duration::from_secs(10)));
+        let conntrack_watcher = ConntrackWatcher::new(table.clone(), vips.clone(), vip_ranges);
+        supervisor.spawn("conntrack_watcher", conntrack_watcher);
+        Some(table)
+    } else {
+        None
+    };
+
+    if cmd.series_ttl > 0 {
+        supervisor.spawn(
+            "series_gc",
+            SeriesGc::new(
+                metrics_collector.clone(),
+                Duration::from_secs(cmd.series_ttl),
+            ),
+        );
+    }
+
+    let (ipv4_event_send, ipv4_event_recv) = channel(EVENT_QUEUE_CAPACITY);
+    if cmd.grpc_port > 0 {
+        let service = ExporterService {
+            vips: vips.clone(),
+            metrics: metrics_collector.clone(),
+            feed: state.feed.clone(),
+        };
+        supervisor.spawn(
+            "grpc_server",
+            GrpcServer::new(([0, 0, 0, 0], cmd.grpc_port).into(), service),
+        );
+    }
+
+    let processor = EventProcessor {
+        vips,
+        endpoints,
+        conntrack,
+        metrics: metrics_collector,
+        agent_metrics: agent_metrics.clone(),
+        health: state.health.component(EVENT_CONSUMER),
+        labeler: SrcLabeler::new(SrcLabelPolicy {
+            mode: cmd.src_label,
+            prefix_v4: cmd.src_prefix_v4,
+            prefix_v6: cmd.src_prefix_v6,
+            groups: cmd.src_groups.clone(),
+            top_k: cmd.src_top_k,
+            idle_timeout: Duration::from_secs(cmd.src_idle_timeout),
+        }),
+        node_name,
+        events: ipv4_event_recv,
+        feed: state.feed.clone(),
+        interfaces: target_ifaces
+            .iter()
+            .map(|iface| (iface.index, iface.name.clone()))
+            .collect(),
+        sample_rate: sample_rate_recv,
+        sample_credit: 0.0,
+        pending: PendingEvents::default(),
+    };
+    supervisor.spawn(EVENT_CONSUMER, processor);
+
+    supervisor.spawn(
+        "event_reader",
+        EventReader::new(
+            ipv4_events,
+            ipv4_event_send,
+            event_drops,
+            agent_metrics.clone(),
+        )?,
+    );
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(Data::new(state.clone()))
-            .service(health)
-            .service(ready)
+            .service(healthz)
+            .service(readyz)
             .service(metrics)
+            .service(api::stream_events)
+            .service(api::watch_events)
+            .service(api::recent_events)
+            .service(api::list_vips)
+            .service(api::list_interfaces)
+            .service(api::list_counters)
+            .service(api::dump_maps)
             .wrap(
                 middleware::Logger::default()
                     .exclude("/healthz")
@@ -205,19 +710,43 @@ async fn main() -> Result<(), anyhow::Error> {
     .unwrap()
     .shutdown_timeout(5);
 
-    server.run().await.unwrap();
+    let result = tokio::select! {
+        res = server.run() => res.map_err(anyhow::Error::from),
+        Some((task, e)) = fatal.recv() => {
+            tracing::error!(task, error=?e, "Exit due to the fatal error");
+            Err(e.into())
+        }
+    };
 
-    Ok(())
+    // flush telemetry buffered in the exporters
+    if let Some(provider) = meter_provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!(error=?e, "Failed to shut down the OTLP metrics exporter");
+        }
+    }
+    opentelemetry::global::shutdown_tracer_provider();
+
+    result
 }
 
 #[get("/healthz")]
-async fn health(_: HttpRequest) -> impl Responder {
-    HttpResponse::Ok().json("healthy")
+async fn healthz(c: Data<State>, _req: HttpRequest) -> impl Responder {
+    let liveness = c.health.liveness();
+    if liveness.healthy {
+        HttpResponse::Ok().json(liveness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(liveness)
+    }
 }
 
 #[get("/readyz")]
-async fn ready(_: HttpRequest) -> impl Responder {
-    HttpResponse::Ok().json("ready")
+async fn readyz(c: Data<State>, _req: HttpRequest) -> impl Responder {
+    let readiness = c.health.readiness();
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
 }
 
 #[get("/metrics")]
@@ -229,6 +758,15 @@ async fn metrics(c: Data<State>, _req: HttpRequest) -> impl Responder {
     HttpResponse::Ok().body(buffer)
 }
 
+fn xdp_mode_name(mode: &str) -> &'static str {
+    match mode.to_lowercase().as_str() {
+        "native" => "native",
+        "hw" => "hw",
+        "skb" => "skb",
+        _ => "default",
+    }
+}
+
 fn get_xdp_mode(mode: &str) -> XdpFlags {
     match mode.to_lowercase().as_str() {
         "native" => XdpFlags::DRV_MODE,
@@ -237,11 +775,3 @@ fn get_xdp_mode(mode: &str) -> XdpFlags {
         _ => XdpFlags::default(),
     }
 }
-
-fn u32_to_addr(x: u32) -> Ipv4Addr {
-    let b1: u8 = ((x >> 24) & 0xff) as u8;
-    let b2: u8 = ((x >> 16) & 0xff) as u8;
-    let b3: u8 = ((x >> 8) & 0xff) as u8;
-    let b4: u8 = (x & 0xff) as u8;
-    Ipv4Addr::new(b1, b2, b3, b4)
-}
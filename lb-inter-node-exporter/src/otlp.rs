@@ -0,0 +1,248 @@
+use std::{any::Any, collections::HashMap, sync::Arc, time::Duration};
+
+use clap::ValueEnum;
+use opentelemetry::{
+    metrics::{
+        AsyncInstrument, CallbackRegistration, Meter, MeterProvider, MetricsError,
+        ObservableCounter, ObservableGauge, Observer,
+    },
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::{
+    HttpExporterBuilder, MetricsExporterBuilder, SpanExporterBuilder, TonicExporterBuilder,
+    WithExportConfig,
+};
+use opentelemetry_sdk::{metrics::SdkMeterProvider, runtime, trace::Tracer, Resource};
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::{error::Error, supervisor::Task};
+
+pub const SERVICE_NAME: &str = "lb-inter-node-exporter";
+
+/// OtlpProtocol is the transport of an OTLP exporter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OtlpProtocol {
+    /// OTLP/gRPC, usually on port 4317
+    #[default]
+    Grpc,
+    /// OTLP/HTTP with protobuf payloads, usually on port 4318
+    HttpProtobuf,
+}
+
+impl OtlpProtocol {
+    /// Build the exporter of any signal sending to the endpoint.
+    pub fn exporter<B>(&self, endpoint: &str) -> B
+    where
+        B: From<TonicExporterBuilder> + From<HttpExporterBuilder>,
+    {
+        match self {
+            OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .into(),
+            OtlpProtocol::HttpProtobuf => opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint)
+                .into(),
+        }
+    }
+}
+
+/// The resource describing this agent in exported telemetry.
+pub fn resource(node_name: &str) -> Resource {
+    let mut attrs = vec![
+        KeyValue::new("service.name", SERVICE_NAME),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ];
+    if !node_name.is_empty() {
+        attrs.push(KeyValue::new("k8s.node.name", node_name.to_string()));
+    }
+    Resource::new(attrs)
+}
+
+/// Build the tracer exporting spans in batches.
+pub fn tracer(
+    endpoint: &str,
+    protocol: OtlpProtocol,
+    resource: Resource,
+) -> Result<Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(protocol.exporter::<SpanExporterBuilder>(endpoint))
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+}
+
+/// Build the meter provider exporting metrics periodically.
+pub fn meter_provider(
+    endpoint: &str,
+    protocol: OtlpProtocol,
+    interval: Duration,
+    resource: Resource,
+) -> Result<SdkMeterProvider, MetricsError> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(protocol.exporter::<MetricsExporterBuilder>(endpoint))
+        .with_resource(resource)
+        .with_period(interval)
+        .build()
+}
+
+/// OtlpMetrics exports the metrics of the Prometheus registry over OTLP.
+///
+/// Instruments are created for metric families as they appear in the registry,
+/// and a single callback gathers the registry once on every export to observe all of them.
+pub struct OtlpMetrics {
+    registry: prometheus::Registry,
+    meter: Meter,
+    interval: Duration,
+    // the instruments of families, None for families of types not exported
+    families: HashMap<String, Option<Instrument>>,
+    registration: Option<Box<dyn CallbackRegistration>>,
+    // notified by the callback when it finds families without instruments
+    new_families: Arc<Notify>,
+}
+
+#[derive(Clone)]
+enum Instrument {
+    Counter(ObservableCounter<f64>),
+    Gauge(ObservableGauge<f64>),
+}
+
+impl Instrument {
+    fn as_any(&self) -> Arc<dyn Any> {
+        match self {
+            Instrument::Counter(counter) => counter.as_any(),
+            Instrument::Gauge(gauge) => gauge.as_any(),
+        }
+    }
+
+    fn as_async(&self) -> &dyn AsyncInstrument<f64> {
+        match self {
+            Instrument::Counter(counter) => counter,
+            Instrument::Gauge(gauge) => gauge,
+        }
+    }
+}
+
+impl OtlpMetrics {
+    pub fn new(
+        registry: prometheus::Registry,
+        provider: &SdkMeterProvider,
+        interval: Duration,
+    ) -> Self {
+        OtlpMetrics {
+            registry,
+            meter: provider.meter(SERVICE_NAME),
+            interval,
+            families: HashMap::new(),
+            registration: None,
+            new_families: Arc::new(Notify::new()),
+        }
+    }
+
+    fn register_new_families(&mut self) {
+        let mut added = false;
+        for family in self.registry.gather().iter() {
+            let name = family.get_name().to_string();
+            if self.families.contains_key(&name) {
+                continue;
+            }
+            let help = family.get_help().to_string();
+            let instrument = match family.get_field_type() {
+                MetricType::COUNTER => Some(Instrument::Counter(
+                    self.meter
+                        .f64_observable_counter(name.clone())
+                        .with_description(help)
+                        .init(),
+                )),
+                MetricType::GAUGE | MetricType::UNTYPED => Some(Instrument::Gauge(
+                    self.meter
+                        .f64_observable_gauge(name.clone())
+                        .with_description(help)
+                        .init(),
+                )),
+                _ => {
+                    tracing::debug!(name, "Skip exporting the metric over OTLP");
+                    None
+                }
+            };
+            added |= instrument.is_some();
+            self.families.insert(name, instrument);
+        }
+        if added {
+            self.register_callback();
+        }
+    }
+
+    // Replace the callback with one observing all instruments.
+    fn register_callback(&mut self) {
+        if let Some(mut registration) = self.registration.take() {
+            if let Err(e) = registration.unregister() {
+                tracing::error!(error=?e, "Failed to unregister the OTLP metrics callback");
+            }
+        }
+        let instruments: Vec<Arc<dyn Any>> = self
+            .families
+            .values()
+            .flatten()
+            .map(Instrument::as_any)
+            .collect();
+        let registry = self.registry.clone();
+        let families = self.families.clone();
+        let new_families = self.new_families.clone();
+        let callback = move |observer: &dyn Observer| {
+            let mut found = false;
+            for family in registry.gather().iter() {
+                match families.get(family.get_name()) {
+                    Some(Some(instrument)) => observe(observer, instrument, family),
+                    Some(None) => {}
+                    None => found = true,
+                }
+            }
+            if found {
+                new_families.notify_one();
+            }
+        };
+        match self.meter.register_callback(&instruments, callback) {
+            Ok(registration) => self.registration = Some(registration),
+            Err(e) => tracing::error!(error=?e, "Failed to register the OTLP metrics callback"),
+        }
+    }
+}
+
+impl Task for OtlpMetrics {
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut ticker = tokio::time::interval(self.interval);
+        tracing::info!("Start OTLP metrics exporter");
+        loop {
+            // families found on an export are registered for the next one
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = self.new_families.notified() => {}
+            }
+            self.register_new_families();
+        }
+    }
+}
+
+fn observe(observer: &dyn Observer, instrument: &Instrument, family: &MetricFamily) {
+    for metric in family.get_metric().iter() {
+        let value = match family.get_field_type() {
+            MetricType::COUNTER => metric.get_counter().get_value(),
+            MetricType::GAUGE => metric.get_gauge().get_value(),
+            MetricType::UNTYPED => metric.get_untyped().get_value(),
+            _ => continue,
+        };
+        let attrs: Vec<KeyValue> = metric
+            .get_label()
+            .iter()
+            .map(|label| KeyValue::new(label.get_name().to_string(), label.get_value().to_string()))
+            .collect();
+        observer.observe_f64(instrument.as_async(), value, &attrs);
+    }
+}
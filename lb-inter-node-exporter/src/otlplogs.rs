@@ -0,0 +1,432 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use opentelemetry_proto::tonic::{
+    collector::logs::v1::{logs_service_client::LogsServiceClient, ExportLogsServiceRequest},
+    common::v1::{any_value::Value, AnyValue, InstrumentationScope, KeyValue},
+    logs::v1::{LogRecord, ResourceLogs, ScopeLogs, SeverityNumber},
+    resource::v1::Resource,
+};
+use prost::Message;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::transport::Channel;
+
+use crate::{
+    error::Error,
+    feed::{ConnectionEvent, EventFeed},
+    otlp::{OtlpProtocol, SERVICE_NAME},
+    supervisor::Task,
+    trace::AgentMetrics,
+};
+
+/// A batch is exported when it reaches this size or at the flush interval.
+const BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// LogsClient sends log records to an OTLP endpoint.
+enum LogsClient {
+    Grpc(LogsServiceClient<Channel>),
+    Http {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+impl LogsClient {
+    fn new(endpoint: &str, protocol: OtlpProtocol) -> Result<Self, Error> {
+        match protocol {
+            OtlpProtocol::Grpc => {
+                let channel = Channel::from_shared(endpoint.to_string())
+                    .map_err(|e| Error::Otlp(e.to_string()))?
+                    .timeout(EXPORT_TIMEOUT)
+                    .connect_lazy();
+                Ok(LogsClient::Grpc(LogsServiceClient::new(channel)))
+            }
+            OtlpProtocol::HttpProtobuf => {
+                let client = reqwest::Client::builder()
+                    .timeout(EXPORT_TIMEOUT)
+                    .build()
+                    .map_err(|e| Error::Otlp(e.to_string()))?;
+                Ok(LogsClient::Http {
+                    client,
+                    url: format!("{}/v1/logs", endpoint.trim_end_matches('/')),
+                })
+            }
+        }
+    }
+
+    async fn export(&mut self, request: ExportLogsServiceRequest) -> Result<(), Error> {
+        match self {
+            LogsClient::Grpc(client) => {
+                client
+                    .export(request)
+                    .await
+                    .map_err(|e| Error::Otlp(e.to_string()))?;
+            }
+            LogsClient::Http { client, url } => {
+                client
+                    .post(url.as_str())
+                    .header("content-type", "application/x-protobuf")
+                    .body(request.encode_to_vec())
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| Error::Otlp(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// OtlpLogs exports connection events as OTLP log records.
+///
+/// Events are taken from the feed, so a slow or unreachable collector makes this exporter
+/// lag behind and drop events instead of stalling the event consumer.
+pub struct OtlpLogs {
+    feed: EventFeed,
+    client: LogsClient,
+    node_name: String,
+    metrics: AgentMetrics,
+}
+
+impl OtlpLogs {
+    pub fn new(
+        endpoint: &str,
+        protocol: OtlpProtocol,
+        feed: EventFeed,
+        node_name: String,
+        metrics: AgentMetrics,
+    ) -> Result<Self, Error> {
+        Ok(OtlpLogs {
+            feed,
+            client: LogsClient::new(endpoint, protocol)?,
+            node_name,
+            metrics,
+        })
+    }
+
+    async fn flush(&mut self, batch: &mut Vec<Arc<ConnectionEvent>>) {
+        let events = std::mem::take(batch);
+        let request = request(&self.node_name, &events);
+        match self.client.export(request).await {
+            Ok(()) => self.metrics.otlp_log_records_exported(events.len()),
+            Err(e) => {
+                tracing::warn!(records = events.len(), error=?e, "Failed to export OTLP log records");
+                self.metrics
+                    .otlp_log_records_dropped("export", events.len() as u64);
+            }
+        }
+    }
+}
+
+impl Task for OtlpLogs {
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut events = self.feed.subscribe();
+        let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        tracing::info!("Start OTLP logs exporter");
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        batch.push(event);
+                        if batch.len() >= BATCH_SIZE {
+                            self.flush(&mut batch).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => self.metrics.otlp_log_records_dropped("lagged", n),
+                    Err(RecvError::Closed) => {
+                        return Err(Error::ChannelClosed("event feed".to_string()))
+                    }
+                },
+                _ = ticker.tick() => {
+                    if !batch.is_empty() {
+                        self.flush(&mut batch).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Group records by the resource of the interface and the Service.
+fn request(node_name: &str, events: &[Arc<ConnectionEvent>]) -> ExportLogsServiceRequest {
+    // (ifindex, ifname, namespace, service) -> records
+    let mut groups: BTreeMap<(u32, Option<String>, String, String), Vec<LogRecord>> =
+        BTreeMap::new();
+    for event in events.iter() {
+        groups
+            .entry((
+                event.ifindex,
+                event.ifname.clone(),
+                event.namespace.clone().unwrap_or_default(),
+                event.service.clone().unwrap_or_default(),
+            ))
+            .or_default()
+            .push(log_record(event));
+    }
+    let resource_logs = groups
+        .into_iter()
+        .map(|((ifindex, ifname, namespace, service), log_records)| {
+            let mut attributes = vec![
+                kv("service.name", string(SERVICE_NAME)),
+                kv("service.version", string(env!("CARGO_PKG_VERSION"))),
+            ];
+            match ifname.as_ref() {
+                Some(ifname) => attributes.push(kv("network.interface.name", string(ifname))),
+                None => attributes.push(kv("network.interface.index", int(ifindex as i64))),
+            }
+            if !node_name.is_empty() {
+                attributes.push(kv("k8s.node.name", string(node_name)));
+            }
+            if !service.is_empty() {
+                attributes.push(kv("k8s.namespace.name", string(&namespace)));
+                attributes.push(kv("k8s.service.name", string(&service)));
+            }
+            ResourceLogs {
+                resource: Some(Resource {
+                    attributes,
+                    dropped_attributes_count: 0,
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: SERVICE_NAME.to_string(),
+                        ..Default::default()
+                    }),
+                    log_records,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }
+        })
+        .collect();
+    ExportLogsServiceRequest { resource_logs }
+}
+
+fn log_record(event: &ConnectionEvent) -> LogRecord {
+    let (severity, severity_text, body) = if event.misrouted {
+        (
+            SeverityNumber::Warn,
+            "WARN",
+            "Misrouted to the node without local endpoints",
+        )
+    } else {
+        (
+            SeverityNumber::Info,
+            "INFO",
+            "Received by intermediate node",
+        )
+    };
+    let mut attributes = vec![
+        kv("source.address", string(&event.src_addr.to_string())),
+        kv("source.port", int(event.src_port as i64)),
+        kv("destination.address", string(&event.dst_addr.to_string())),
+        kv("destination.port", int(event.dst_port as i64)),
+        kv("network.transport", string("tcp")),
+        kv("network.interface.index", int(event.ifindex as i64)),
+        kv("delivery", string(&event.delivery)),
+        kv("misrouted", Value::BoolValue(event.misrouted)),
+    ];
+    if let Some(port_name) = event.port_name.as_ref() {
+        attributes.push(kv("port_name", string(port_name)));
+    }
+    if let Some(source) = event.source.as_ref() {
+        attributes.push(kv("source", string(source)));
+    }
+    if let Some(addr) = event.backend_addr.as_ref() {
+        attributes.push(kv("backend.address", string(&addr.to_string())));
+    }
+    if let Some(port) = event.backend_port {
+        attributes.push(kv("backend.port", int(port as i64)));
+    }
+    if let Some(pod) = event.backend_pod.as_ref() {
+        attributes.push(kv("backend.pod", string(pod)));
+    }
+    if let Some(node) = event.backend_node.as_ref() {
+        attributes.push(kv("backend.node", string(node)));
+    }
+    LogRecord {
+        time_unix_nano: (event.timestamp * 1e9) as u64,
+        observed_time_unix_nano: (ConnectionEvent::now() * 1e9) as u64,
+        severity_number: severity as i32,
+        severity_text: severity_text.to_string(),
+        body: Some(AnyValue {
+            value: Some(string(body)),
+        }),
+        attributes,
+        ..Default::default()
+    }
+}
+
+fn kv(key: &str, value: Value) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value::StringValue(s.to_string())
+}
+
+fn int(n: i64) -> Value {
+    Value::IntValue(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(ifname: Option<&str>, service: Option<&str>) -> Arc<ConnectionEvent> {
+        Arc::new(ConnectionEvent {
+            timestamp: 1_700_000_000.5,
+            src_addr: "192.168.0.2".parse().unwrap(),
+            src_port: 40000,
+            dst_addr: "10.0.0.1".parse().unwrap(),
+            dst_port: 80,
+            service: service.map(|s| s.to_string()),
+            namespace: service.map(|_| "test".to_string()),
+            port_name: None,
+            source: None,
+            delivery: "local".to_string(),
+            backend_addr: None,
+            backend_port: None,
+            backend_pod: None,
+            backend_node: None,
+            misrouted: false,
+            ifindex: 3,
+            ifname: ifname.map(|name| name.to_string()),
+        })
+    }
+
+    fn attributes(attributes: &[KeyValue]) -> BTreeMap<String, Value> {
+        attributes
+            .iter()
+            .map(|kv| {
+                let value = kv.value.as_ref().and_then(|v| v.value.clone()).unwrap();
+                (kv.key.clone(), value)
+            })
+            .collect()
+    }
+
+    fn resource(logs: &ResourceLogs) -> BTreeMap<String, Value> {
+        attributes(&logs.resource.as_ref().unwrap().attributes)
+    }
+
+    #[test]
+    fn test_request_resources() {
+        let events = [
+            event(Some("eth0"), Some("web")),
+            event(Some("eth0"), Some("web")),
+            event(Some("eth0"), Some("api")),
+            event(Some("eth1"), Some("web")),
+            event(Some("eth0"), None),
+            event(None, Some("web")),
+        ];
+        let req = request("node1", &events);
+        let groups: Vec<(BTreeMap<String, Value>, usize)> = req
+            .resource_logs
+            .iter()
+            .map(|logs| {
+                assert_eq!(logs.scope_logs.len(), 1);
+                let scope = &logs.scope_logs[0];
+                assert_eq!(scope.scope.as_ref().unwrap().name, SERVICE_NAME);
+                (resource(logs), scope.log_records.len())
+            })
+            .collect();
+
+        let common = [
+            ("service.name".to_string(), string(SERVICE_NAME)),
+            (
+                "service.version".to_string(),
+                string(env!("CARGO_PKG_VERSION")),
+            ),
+            ("k8s.node.name".to_string(), string("node1")),
+        ];
+        let expected = |attrs: &[(&str, Value)]| -> BTreeMap<String, Value> {
+            common
+                .iter()
+                .cloned()
+                .chain(attrs.iter().map(|(k, v)| (k.to_string(), v.clone())))
+                .collect()
+        };
+        let service = |name: &str| {
+            [
+                ("k8s.namespace.name", string("test")),
+                ("k8s.service.name", string(name)),
+            ]
+        };
+        let eth0 = ("network.interface.name", string("eth0"));
+        let eth1 = ("network.interface.name", string("eth1"));
+        let [ns, web] = service("web");
+        let [_, api] = service("api");
+        assert_eq!(
+            groups,
+            [
+                // only the index without the interface name
+                (
+                    expected(&[("network.interface.index", int(3)), ns.clone(), web.clone()]),
+                    1
+                ),
+                (expected(std::slice::from_ref(&eth0)), 1),
+                (expected(&[eth0.clone(), ns.clone(), api]), 1),
+                (expected(&[eth0, ns.clone(), web.clone()]), 2),
+                (expected(&[eth1, ns, web]), 1),
+            ]
+        );
+
+        // without the node name
+        let req = request("", &events[..1]);
+        assert!(!resource(&req.resource_logs[0]).contains_key("k8s.node.name"));
+    }
+
+    #[test]
+    fn test_log_record() {
+        let record = log_record(&event(Some("eth0"), Some("web")));
+        assert_eq!(record.time_unix_nano, 1_700_000_000_500_000_000);
+        assert_eq!(record.severity_number, SeverityNumber::Info as i32);
+        assert_eq!(record.severity_text, "INFO");
+        assert_eq!(
+            record.body.unwrap().value,
+            Some(string("Received by intermediate node"))
+        );
+        assert_eq!(
+            attributes(&record.attributes),
+            BTreeMap::from([
+                ("source.address".to_string(), string("192.168.0.2")),
+                ("source.port".to_string(), int(40000)),
+                ("destination.address".to_string(), string("10.0.0.1")),
+                ("destination.port".to_string(), int(80)),
+                ("network.transport".to_string(), string("tcp")),
+                ("network.interface.index".to_string(), int(3)),
+                ("delivery".to_string(), string("local")),
+                ("misrouted".to_string(), Value::BoolValue(false)),
+            ])
+        );
+
+        let mut event = (*event(Some("eth0"), Some("web"))).clone();
+        event.misrouted = true;
+        event.delivery = "dropped".to_string();
+        event.port_name = Some("http".to_string());
+        event.source = Some("ingress".to_string());
+        event.backend_addr = Some("10.244.1.5".parse().unwrap());
+        event.backend_port = Some(8080);
+        event.backend_pod = Some("web-0".to_string());
+        event.backend_node = Some("node2".to_string());
+        let record = log_record(&event);
+        assert_eq!(record.severity_number, SeverityNumber::Warn as i32);
+        assert_eq!(record.severity_text, "WARN");
+        assert_eq!(
+            record.body.unwrap().value,
+            Some(string("Misrouted to the node without local endpoints"))
+        );
+        let attrs = attributes(&record.attributes);
+        assert_eq!(attrs["misrouted"], Value::BoolValue(true));
+        assert_eq!(attrs["port_name"], string("http"));
+        assert_eq!(attrs["source"], string("ingress"));
+        assert_eq!(attrs["backend.address"], string("10.244.1.5"));
+        assert_eq!(attrs["backend.port"], int(8080));
+        assert_eq!(attrs["backend.pod"], string("web-0"));
+        assert_eq!(attrs["backend.node"], string("node2"));
+    }
+}
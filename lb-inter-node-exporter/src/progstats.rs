@@ -0,0 +1,171 @@
+use std::{
+    fs,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, Instant},
+};
+
+use aya::programs::ProgramFd;
+
+use crate::{error::Error, supervisor::Task, trace::AgentMetrics};
+
+// bpf(2) command to enable global BPF stats
+const BPF_ENABLE_STATS: libc::c_int = 32;
+// stats type to collect run_time_ns and run_cnt
+const BPF_STATS_RUN_TIME: u32 = 0;
+
+#[repr(C)]
+struct EnableStatsAttr {
+    stats_type: u32,
+}
+
+/// Enable BPF runtime stats in the kernel.
+///
+/// Stats stay enabled while the returned file descriptor is open.
+pub fn enable_stats() -> Result<OwnedFd, Error> {
+    let attr = EnableStatsAttr {
+        stats_type: BPF_STATS_RUN_TIME,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_ENABLE_STATS,
+            &attr as *const EnableStatsAttr,
+            std::mem::size_of::<EnableStatsAttr>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::StdIo(std::io::Error::last_os_error()));
+    }
+    // SAFETY: the kernel returned a new file descriptor owned by nobody else
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as i32) })
+}
+
+/// ProgramStats is the cumulative runtime stats of a loaded program.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramStats {
+    pub run_time_ns: u64,
+    pub run_cnt: u64,
+}
+
+impl ProgramStats {
+    /// Read stats of the program from its fdinfo.
+    pub fn read(fd: &ProgramFd) -> Result<Self, Error> {
+        let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_fd().as_raw_fd()))
+            .map_err(Error::StdIo)?;
+        Ok(parse_fdinfo(&fdinfo))
+    }
+}
+
+fn parse_fdinfo(fdinfo: &str) -> ProgramStats {
+    let mut stats = ProgramStats::default();
+    for line in fdinfo.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        match key.trim() {
+            "run_time_ns" => stats.run_time_ns = value,
+            "run_cnt" => stats.run_cnt = value,
+            _ => {}
+        }
+    }
+    stats
+}
+
+/// ProgramStatsCollector periodically exports runtime stats of the XDP program.
+pub struct ProgramStatsCollector {
+    name: String,
+    id: u32,
+    fd: ProgramFd,
+    interval: Duration,
+    metrics: AgentMetrics,
+    // keeps BPF stats enabled
+    _stats: Option<OwnedFd>,
+}
+
+impl ProgramStatsCollector {
+    pub fn new(
+        name: &str,
+        id: u32,
+        fd: ProgramFd,
+        interval: Duration,
+        metrics: AgentMetrics,
+    ) -> Self {
+        let stats = match enable_stats() {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                tracing::warn!(error=?e, "Failed to enable BPF stats, kernel.bpf_stats_enabled sysctl must be set to collect program stats");
+                None
+            }
+        };
+        ProgramStatsCollector {
+            name: name.to_string(),
+            id,
+            fd,
+            interval,
+            metrics,
+            _stats: stats,
+        }
+    }
+}
+
+impl Task for ProgramStatsCollector {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        let id = self.id.to_string();
+        let mut ticker = tokio::time::interval(self.interval);
+        let mut last: Option<(ProgramStats, Instant)> = None;
+
+        tracing::info!(program = self.name, id, "Start program stats collector");
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let stats = ProgramStats::read(&self.fd)?;
+            if let Some((prev, at)) = last {
+                let runs = stats.run_cnt.saturating_sub(prev.run_cnt);
+                let run_time = stats.run_time_ns.saturating_sub(prev.run_time_ns);
+                let elapsed = now.duration_since(at).as_secs_f64();
+                if elapsed > 0.0 {
+                    self.metrics
+                        .program_packets_per_second(&self.name, &id, runs as f64 / elapsed);
+                }
+                if runs > 0 {
+                    self.metrics.program_run_time_per_packet(
+                        &self.name,
+                        &id,
+                        run_time as f64 / runs as f64,
+                    );
+                }
+            }
+            last = Some((stats, now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fdinfo() {
+        let fdinfo = "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nino:\t2085\n\
+            prog_type:\t6\nprog_jited:\t1\nprog_tag:\t3b185187f1855c4c\n\
+            memlock:\t4096\nprog_id:\t42\nrun_time_ns:\t123456789\nrun_cnt:\t1000\n\
+            recursion_misses:\t0\nverified_insns:\t521\n";
+        assert_eq!(
+            parse_fdinfo(fdinfo),
+            ProgramStats {
+                run_time_ns: 123456789,
+                run_cnt: 1000,
+            }
+        );
+
+        // kernels without BPF stats don't have the fields
+        assert_eq!(
+            parse_fdinfo("prog_type:\t6\nrun_cnt:\tbroken\nno separator\n"),
+            ProgramStats::default()
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr},
+    pin::pin,
+    str::FromStr,
+    sync::Arc,
+};
+
+use aya::maps::{
+    lpm_trie::{Key, LpmTrie},
+    MapData, MapError,
+};
+use futures::TryStreamExt;
+use ipnet::{IpNet, Ipv4Net, Ipv4Subnets};
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind},
+    runtime::{watcher, WatchStreamExt},
+    Api, Client, ResourceExt,
+};
+use parking_lot::Mutex;
+
+use crate::{error::Error, supervisor::Task, trace::AgentMetrics};
+
+/// The owner of ranges given by --vip-range.
+pub const STATIC_POOL: &str = "static";
+
+/// VipRanges keeps VIP ranges in the LPM trie map of the eBPF program.
+///
+/// Pools may overlap, so a range stays in the map until the last pool containing it is deleted.
+/// Connections to addresses in a range are attributed to Services in userspace by the VipTable.
+pub struct VipRanges {
+    map: LpmTrie<MapData, u32, u32>,
+    owners: BTreeMap<Ipv4Net, BTreeSet<String>>,
+    metrics: AgentMetrics,
+}
+
+impl VipRanges {
+    pub fn new(map: LpmTrie<MapData, u32, u32>, metrics: AgentMetrics) -> Self {
+        VipRanges {
+            map,
+            owners: BTreeMap::new(),
+            metrics,
+        }
+    }
+
+    /// Replace the ranges owned by the pool.
+    pub fn set(&mut self, pool: &str, ranges: &[Ipv4Net]) {
+        let released: Vec<Ipv4Net> = self
+            .owners
+            .iter()
+            .filter(|(range, owners)| owners.contains(pool) && !ranges.contains(range))
+            .map(|(range, _)| *range)
+            .collect();
+        for range in released.into_iter() {
+            let owners = self.owners.get_mut(&range).unwrap();
+            owners.remove(pool);
+            if !owners.is_empty() {
+                // still owned by other pools
+                continue;
+            }
+            self.owners.remove(&range);
+            tracing::info!(pool, range=?range, "Delete the tracking VIP range");
+            if let Err(e) = self.map.remove(&range_key(&range)) {
+                tracing::error!(range=?range, error=?e, "Failed to remove the VIP range");
+                self.metrics.map_operation_failed("IPV4VIP_RANGE", "remove");
+            }
+        }
+
+        for range in ranges.iter() {
+            let owners = self.owners.entry(*range).or_default();
+            let first = owners.is_empty();
+            owners.insert(pool.to_string());
+            if !first {
+                continue;
+            }
+            tracing::info!(pool, range=?range, "Add to track VIP range");
+            if let Err(e) = self.map.insert(&range_key(range), 0, 0) {
+                tracing::error!(range=?range, error=?e, "Failed to insert the VIP range");
+                self.metrics.map_operation_failed("IPV4VIP_RANGE", "insert");
+            }
+        }
+        self.metrics.tracked_vip_ranges(self.owners.len());
+    }
+
+    /// Return true if the address is in any tracked range.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        self.owners.keys().any(|range| range.contains(&addr))
+    }
+
+    /// Return ranges in the eBPF map.
+    pub fn map_entries(&self) -> Result<Vec<(Ipv4Net, u32)>, MapError> {
+        self.map
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key_range(&key), value))
+            })
+            .collect()
+    }
+}
+
+// the eBPF program matches addresses in network byte order
+fn range_key(range: &Ipv4Net) -> Key<u32> {
+    Key::new(
+        range.prefix_len() as u32,
+        u32::from(range.network()).to_be(),
+    )
+}
+
+fn key_range(key: &Key<u32>) -> Ipv4Net {
+    let addr = Ipv4Addr::from(u32::from_be(key.data()));
+    // the prefix length in the map is always valid
+    Ipv4Net::new(addr, key.prefix_len() as u8).unwrap()
+}
+
+/// Parse ranges given as a CIDR or a `start-end` address range like MetalLB's address pools.
+/// IPv6 ranges are ignored because the eBPF program doesn't track IPv6 yet.
+pub fn parse_ranges(addresses: &[String]) -> Result<Vec<Ipv4Net>, String> {
+    let mut ranges = Vec::new();
+    for addr in addresses.iter() {
+        ranges.extend(parse_range(addr)?);
+    }
+    ranges.sort();
+    ranges.dedup();
+    Ok(ranges)
+}
+
+// Parse ranges of an IPAddressPool, which is validated by MetalLB rather than the agent.
+fn parse_pool_ranges(addresses: &[String]) -> Vec<Ipv4Net> {
+    let mut ranges = Vec::new();
+    for addr in addresses.iter() {
+        match parse_range(addr) {
+            Ok(nets) => ranges.extend(nets),
+            Err(e) => tracing::warn!(range = addr, error=?e, "Ignore the invalid VIP range"),
+        }
+    }
+    ranges.sort();
+    ranges.dedup();
+    ranges
+}
+
+fn parse_range(addr: &str) -> Result<Vec<Ipv4Net>, String> {
+    match addr.split_once('-') {
+        Some((start, end)) => {
+            match (IpAddr::from_str(start.trim()), IpAddr::from_str(end.trim())) {
+                (Ok(IpAddr::V4(start)), Ok(IpAddr::V4(end))) if start <= end => {
+                    Ok(Ipv4Subnets::new(start, end, 0).collect())
+                }
+                (Ok(IpAddr::V6(start)), Ok(IpAddr::V6(end))) if start <= end => Ok(Vec::new()),
+                _ => Err(format!(
+                    "invalid VIP range {addr}: expected start-end in order"
+                )),
+            }
+        }
+        None => match IpNet::from_str(addr.trim()) {
+            Ok(IpNet::V4(net)) => Ok(vec![net.trunc()]),
+            Ok(IpNet::V6(_)) => Ok(Vec::new()),
+            Err(e) => Err(format!("invalid VIP range {addr}: {e}")),
+        },
+    }
+}
+
+/// PoolWatcher tracks address ranges of MetalLB IPAddressPools.
+pub struct PoolWatcher {
+    client: Client,
+    ranges: Arc<Mutex<VipRanges>>,
+    metrics: AgentMetrics,
+    // pools that own ranges, kept across restarts to find deleted pools
+    pools: BTreeSet<String>,
+}
+
+impl PoolWatcher {
+    pub async fn new(ranges: Arc<Mutex<VipRanges>>, metrics: AgentMetrics) -> Result<Self, Error> {
+        let client = Client::try_default().await.map_err(Error::Kube)?;
+        Ok(PoolWatcher {
+            client,
+            ranges,
+            metrics,
+            pools: BTreeSet::new(),
+        })
+    }
+
+    fn apply(&mut self, pool: &DynamicObject) {
+        let key = pool_key(pool);
+        let ranges = parse_pool_ranges(&pool_addresses(pool));
+        self.ranges.lock().set(&key, &ranges);
+        self.pools.insert(key);
+    }
+
+    fn delete(&mut self, pool: &DynamicObject) {
+        let key = pool_key(pool);
+        self.ranges.lock().set(&key, &[]);
+        self.pools.remove(&key);
+    }
+}
+
+impl Task for PoolWatcher {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        let gvk = GroupVersionKind::gvk("metallb.io", "v1beta1", "IPAddressPool");
+        let pool_api: Api<DynamicObject> =
+            Api::all_with(self.client.clone(), &ApiResource::from_gvk(&gvk));
+        let pool_events = watcher(pool_api, watcher::Config::default()).default_backoff();
+        let mut pool_events = pin!(pool_events);
+
+        tracing::info!("Start IPAddressPool watcher");
+        while let Some(event) = pool_events.try_next().await.map_err(Error::KubeWatcher)? {
+            self.metrics.watcher_event("ipaddresspool");
+            match event {
+                watcher::Event::Applied(pool) => self.apply(&pool),
+                watcher::Event::Deleted(pool) => self.delete(&pool),
+                watcher::Event::Restarted(pools) => {
+                    self.metrics.watcher_restarted("ipaddresspool");
+                    let listed: BTreeSet<String> = pools.iter().map(pool_key).collect();
+                    let vanished: Vec<String> = self.pools.difference(&listed).cloned().collect();
+                    for key in vanished.into_iter() {
+                        self.ranges.lock().set(&key, &[]);
+                        self.pools.remove(&key);
+                    }
+                    for pool in pools.iter() {
+                        self.apply(pool);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn pool_key(pool: &DynamicObject) -> String {
+    format!(
+        "{}/{}",
+        pool.namespace().unwrap_or_default(),
+        pool.name_any()
+    )
+}
+
+fn pool_addresses(pool: &DynamicObject) -> Vec<String> {
+    pool.data
+        .get("spec")
+        .and_then(|spec| spec.get("addresses"))
+        .and_then(|addrs| addrs.as_array())
+        .map(|addrs| {
+            addrs
+                .iter()
+                .filter_map(|a| a.as_str().map(|a| a.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(addresses: &[&str]) -> Vec<String> {
+        addresses.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn ranges(addresses: &[&str]) -> Vec<String> {
+        parse_ranges(&self::addresses(addresses))
+            .unwrap()
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        // truncated to the network
+        assert_eq!(
+            ranges(&["10.0.0.5/24", " 10.0.1.0/32 "]),
+            ["10.0.0.0/24", "10.0.1.0/32"]
+        );
+        // decomposed into CIDRs
+        assert_eq!(
+            ranges(&["10.0.0.1 - 10.0.0.6"]),
+            ["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6/32"]
+        );
+        assert_eq!(ranges(&["10.0.0.0-10.0.0.255"]), ["10.0.0.0/24"]);
+        assert_eq!(ranges(&["10.0.0.1-10.0.0.1"]), ["10.0.0.1/32"]);
+        // sorted without duplicates
+        assert_eq!(
+            ranges(&["10.0.1.0/24", "10.0.0.0/24", "10.0.0.0-10.0.0.255"]),
+            ["10.0.0.0/24", "10.0.1.0/24"]
+        );
+        // IPv6 is skipped
+        assert!(ranges(&["2001:db8::/64", "2001:db8::1-2001:db8::ff"]).is_empty());
+
+        // invalid ranges given to the agent are rejected
+        for invalid in [
+            "10.0.0.6-10.0.0.1",
+            "2001:db8::ff-2001:db8::1",
+            "10.0.0.1-2001:db8::1",
+            "10.0.0.0/33",
+            "10.0.0.1-",
+            "example",
+        ] {
+            assert!(
+                parse_ranges(&addresses(&["192.168.0.0/16", invalid])).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_pool_ranges() {
+        // invalid ranges of pools are ignored
+        let ranges = parse_pool_ranges(&addresses(&[
+            "10.0.0.6-10.0.0.1",
+            "10.0.0.1-2001:db8::1",
+            "10.0.0.0/33",
+            "10.0.0.1-",
+            "example",
+            "2001:db8::/64",
+            "192.168.0.0/16",
+            "10.0.0.1-10.0.0.2",
+        ]));
+        let ranges: Vec<String> = ranges.iter().map(|r| r.to_string()).collect();
+        assert_eq!(ranges, ["10.0.0.1/32", "10.0.0.2/32", "192.168.0.0/16"]);
+    }
+
+    #[test]
+    fn test_range_key() {
+        let range = Ipv4Net::from_str("10.1.2.0/24").unwrap();
+        let key = range_key(&range);
+        assert_eq!(key.prefix_len(), 24);
+        // the first octet comes first in memory
+        assert_eq!(key.data().to_ne_bytes(), [10, 1, 2, 0]);
+        assert_eq!(key_range(&key), range);
+
+        for range in ["0.0.0.0/0", "10.0.0.1/32", "172.16.0.0/12"] {
+            let range = Ipv4Net::from_str(range).unwrap();
+            assert_eq!(key_range(&range_key(&range)), range);
+        }
+    }
+}
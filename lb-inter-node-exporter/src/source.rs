@@ -0,0 +1,340 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    error::Error,
+    health::Component,
+    kubernetes::{AddrSource, Lb, LbPort, ServiceWatcher, TrafficPolicy, VipEvent},
+    supervisor::Task,
+};
+
+/// VipSourceKind selects where the agent learns VIPs from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VipSourceKind {
+    // Services of the Kubernetes cluster
+    Kubernetes,
+    // VIPs given by --vip
+    Static,
+    // VIPs listed in --vip-file
+    File,
+}
+
+/// VipSource sends VipEvents of VIPs to track.
+pub enum VipSource {
+    Kubernetes(Box<ServiceWatcher>),
+    Static(StaticSource),
+    File(FileSource),
+}
+
+impl Task for VipSource {
+    async fn run(&mut self) -> Result<(), Error> {
+        match self {
+            VipSource::Kubernetes(watcher) => watcher.run().await,
+            VipSource::Static(source) => source.run().await,
+            VipSource::File(source) => source.run().await,
+        }
+    }
+}
+
+/// StaticVip is a VIP given without Kubernetes.
+///
+/// On the command line it is written as `[[namespace/]name=]addr:port[,port...]`,
+/// IPv6 addresses are enclosed in brackets like `[2001:db8::1]:443`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StaticVip {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub namespace: String,
+    pub addr: IpAddr,
+    pub ports: Vec<u16>,
+}
+
+impl StaticVip {
+    fn lb(&self) -> Lb {
+        Lb {
+            name: self.name.clone().unwrap_or(self.addr.to_string()),
+            namespace: self.namespace.clone(),
+            addr: Some(self.addr),
+            source: AddrSource::Static,
+            policy: TrafficPolicy::Cluster,
+            ports: self
+                .ports
+                .iter()
+                .map(|port| LbPort {
+                    name: None,
+                    port: *port,
+                    protocol: "TCP".to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for StaticVip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (service, target) = match s.split_once('=') {
+            Some((service, target)) => (Some(service), target),
+            None => (None, s),
+        };
+        let (namespace, name) = match service.map(|svc| svc.split_once('/')) {
+            Some(Some((ns, name))) => (ns.to_string(), Some(name.to_string())),
+            Some(None) => (String::new(), service.map(|svc| svc.to_string())),
+            None => (String::new(), None),
+        };
+        if name.as_deref() == Some("") {
+            return Err(format!("empty name in {s}"));
+        }
+        let (addr, ports) = target
+            .rsplit_once(':')
+            .ok_or(format!("ports are required in {s}"))?;
+        let ports = ports
+            .split(',')
+            .map(|port| u16::from_str(port).map_err(|e| format!("invalid port {port}: {e}")))
+            .collect::<Result<Vec<u16>, String>>()?;
+        // parse as a socket address to accept bracketed IPv6 addresses
+        let addr = SocketAddr::from_str(&format!("{addr}:0"))
+            .map_err(|e| format!("invalid address {addr}: {e}"))?
+            .ip();
+        Ok(StaticVip {
+            name,
+            namespace,
+            addr,
+            ports,
+        })
+    }
+}
+
+/// VipFile is the content of the file given by --vip-file in YAML or JSON.
+#[derive(Debug, Default, Deserialize)]
+pub struct VipFile {
+    #[serde(default)]
+    pub vips: Vec<StaticVip>,
+}
+
+impl VipFile {
+    pub fn parse(content: &[u8]) -> Result<Self, Error> {
+        if content.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(VipFile::default());
+        }
+        // YAML is a superset of JSON
+        serde_yaml::from_slice(content).map_err(Error::VipList)
+    }
+}
+
+/// StaticSource tracks the VIPs given at startup.
+pub struct StaticSource {
+    vips: Vec<Lb>,
+    vip_events: UnboundedSender<Vec<VipEvent>>,
+    health: Component,
+    sent: bool,
+}
+
+impl StaticSource {
+    pub fn new(
+        vips: &[StaticVip],
+        vip_events: UnboundedSender<Vec<VipEvent>>,
+        health: Component,
+    ) -> Self {
+        StaticSource {
+            vips: vips.iter().map(StaticVip::lb).collect(),
+            vip_events,
+            health,
+            sent: false,
+        }
+    }
+}
+
+impl Task for StaticSource {
+    async fn run(&mut self) -> Result<(), Error> {
+        let _alive = self.health.start();
+        if !self.sent {
+            tracing::info!(vips = self.vips.len(), "Track static VIPs");
+            self.vip_events
+                .send(self.vips.iter().cloned().map(VipEvent::Add).collect())
+                .map_err(|_| Error::ChannelClosed("VIP events".to_string()))?;
+            self.sent = true;
+        }
+        self.health.synced();
+        // the list never changes
+        std::future::pending().await
+    }
+}
+
+/// FileSource tracks VIPs listed in a file and follows changes of the file.
+///
+/// The file is polled rather than watched with inotify
+/// because a mounted ConfigMap is updated by swapping symlinks.
+pub struct FileSource {
+    path: PathBuf,
+    interval: Duration,
+    vip_events: UnboundedSender<Vec<VipEvent>>,
+    health: Component,
+    // kept across restarts to diff the reloaded file against
+    current: Vec<Lb>,
+    last_content: Option<Vec<u8>>,
+}
+
+impl FileSource {
+    pub fn new(
+        path: PathBuf,
+        interval: Duration,
+        vip_events: UnboundedSender<Vec<VipEvent>>,
+        health: Component,
+    ) -> Self {
+        FileSource {
+            path,
+            interval,
+            vip_events,
+            health,
+            current: Vec::new(),
+            last_content: None,
+        }
+    }
+
+    async fn reload(&mut self) -> Result<(), Error> {
+        let content = tokio::fs::read(&self.path).await.map_err(Error::StdIo)?;
+        if self.last_content.as_ref() == Some(&content) {
+            return Ok(());
+        }
+        let file = VipFile::parse(&content)?;
+        let desired: Vec<Lb> = file.vips.iter().map(StaticVip::lb).collect();
+        tracing::info!(path=?self.path, vips = desired.len(), "Load the VIP file");
+        self.vip_events
+            .send(VipEvent::diff(&self.current, &desired))
+            .map_err(|_| Error::ChannelClosed("VIP events".to_string()))?;
+        self.current = desired;
+        self.last_content = Some(content);
+        self.health.synced();
+        Ok(())
+    }
+}
+
+impl Task for FileSource {
+    #[tracing::instrument(skip_all)]
+    async fn run(&mut self) -> Result<(), Error> {
+        let _alive = self.health.start();
+        let mut ticker = tokio::time::interval(self.interval);
+        tracing::info!(path=?self.path, "Start VIP file watcher");
+        loop {
+            ticker.tick().await;
+            self.health.begin();
+            match self.reload().await {
+                Ok(()) => {}
+                Err(e) if e.is_fatal() => return Err(e),
+                // keep tracking the last valid list until the file is fixed
+                Err(e) => tracing::error!(path=?self.path, error=?e, "Failed to load the VIP file"),
+            }
+            self.health.done();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn test_static_vip_from_str() {
+        assert_eq!(
+            StaticVip::from_str("10.0.0.1:80").unwrap(),
+            StaticVip {
+                name: None,
+                namespace: String::new(),
+                addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                ports: vec![80],
+            }
+        );
+        assert_eq!(
+            StaticVip::from_str("web=10.0.0.1:80,443").unwrap(),
+            StaticVip {
+                name: Some("web".to_string()),
+                namespace: String::new(),
+                addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                ports: vec![80, 443],
+            }
+        );
+        assert_eq!(
+            StaticVip::from_str("test/web=[2001:db8::1]:443").unwrap(),
+            StaticVip {
+                name: Some("web".to_string()),
+                namespace: "test".to_string(),
+                addr: IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                ports: vec![443],
+            }
+        );
+
+        for invalid in [
+            "=10.0.0.1:80",
+            "test/=10.0.0.1:80",
+            "10.0.0.1",
+            "10.0.0.1:",
+            "10.0.0.1:http",
+            "10.0.0.1:65536",
+            "10.0.0.1:80,",
+            "2001:db8::1:443",
+            "[2001:db8::1]",
+            "example.com:80",
+        ] {
+            assert!(StaticVip::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_vip_file_parse() {
+        assert!(VipFile::parse(b"").unwrap().vips.is_empty());
+        assert!(VipFile::parse(b" \n\t").unwrap().vips.is_empty());
+        assert!(VipFile::parse(b"vips: []").unwrap().vips.is_empty());
+
+        let yaml = b"
+vips:
+- name: web
+  namespace: test
+  addr: 10.0.0.1
+  ports: [80, 443]
+- addr: 2001:db8::1
+  ports: [443]
+";
+        let json = br#"{"vips": [
+            {"name": "web", "namespace": "test", "addr": "10.0.0.1", "ports": [80, 443]},
+            {"addr": "2001:db8::1", "ports": [443]}
+        ]}"#;
+        let expected = vec![
+            StaticVip {
+                name: Some("web".to_string()),
+                namespace: "test".to_string(),
+                addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                ports: vec![80, 443],
+            },
+            StaticVip {
+                name: None,
+                namespace: String::new(),
+                addr: IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                ports: vec![443],
+            },
+        ];
+        assert_eq!(VipFile::parse(yaml).unwrap().vips, expected);
+        assert_eq!(VipFile::parse(json).unwrap().vips, expected);
+
+        for invalid in [
+            &b"vips: [{addr: 10.0.0.1}]"[..],
+            b"vips: [{addr: 10.0.0.1, ports: [65536]}]",
+            b"vips: [{addr: example.com, ports: [80]}]",
+            b"vips: 10.0.0.1",
+        ] {
+            assert!(VipFile::parse(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    any::Any,
+    future::Future,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{error::Error, trace::AgentMetrics};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// a task running longer than this is considered recovered and restarts without delay growth
+const RESET_BACKOFF_AFTER: Duration = Duration::from_secs(60);
+
+/// Task is a long running component of the agent.
+///
+/// `run` is called again when it returns or panics, so the state kept in the task survives restarts.
+/// State shared between tasks is guarded by parking_lot locks, which are not poisoned by a panic.
+pub trait Task: Send + 'static {
+    fn run(&mut self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Supervisor runs tasks and restarts them with backoff on failure.
+///
+/// Fatal errors are not retried and are sent to the receiver returned by `new`.
+#[derive(Clone)]
+pub struct Supervisor {
+    metrics: AgentMetrics,
+    fatal: UnboundedSender<(&'static str, Error)>,
+}
+
+impl Supervisor {
+    pub fn new(metrics: AgentMetrics) -> (Self, UnboundedReceiver<(&'static str, Error)>) {
+        let (fatal, fatal_recv) = unbounded_channel();
+        (Supervisor { metrics, fatal }, fatal_recv)
+    }
+
+    pub fn spawn<T: Task>(&self, name: &'static str, mut task: T) {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let reason = match AssertUnwindSafe(task.run()).catch_unwind().await {
+                    Ok(Ok(())) => {
+                        tracing::warn!(task = name, "Task exited");
+                        "exited"
+                    }
+                    Ok(Err(e)) if e.is_fatal() => {
+                        tracing::error!(task = name, error=?e, "Task failed with a fatal error");
+                        let _ = supervisor.fatal.send((name, e));
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        tracing::error!(task = name, error=?e, "Task failed");
+                        e.reason()
+                    }
+                    Err(panic) => {
+                        tracing::error!(
+                            task = name,
+                            panic = panic_message(&panic),
+                            "Task panicked"
+                        );
+                        "panic"
+                    }
+                };
+                if started.elapsed() >= RESET_BACKOFF_AFTER {
+                    backoff = INITIAL_BACKOFF;
+                }
+                supervisor.metrics.task_restarted(name, reason);
+                tracing::info!(task = name, backoff=?backoff, "Restart the task");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown"
+    }
+}
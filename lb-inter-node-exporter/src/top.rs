@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    task::JoinHandle,
+};
+
+use crate::{
+    api::MapEntry,
+    cli::{follow, get, ClientArgs, StreamMessage},
+    feed::ConnectionEvent,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const INPUT_INTERVAL: Duration = Duration::from_millis(100);
+// rows shown in the client prefix table
+const TOP_PREFIXES: usize = 20;
+
+#[derive(Debug, Default, Clone)]
+struct VipStats {
+    service: String,
+    rate: f64,
+    total: u64,
+    dropped: u64,
+    misrouted: u64,
+}
+
+/// TopView aggregates events into per-second rates.
+#[derive(Debug, Default)]
+struct TopView {
+    prefix_v4: u8,
+    prefix_v6: u8,
+    vips: BTreeMap<IpAddr, VipStats>,
+    // counts in the current window
+    vip_window: HashMap<IpAddr, u64>,
+    prefix_window: HashMap<IpNet, u64>,
+    iface_window: HashMap<String, u64>,
+    // rates of the last window
+    total_rate: f64,
+    prefix_rates: Vec<(IpNet, f64)>,
+    iface_rates: Vec<(String, f64)>,
+    lagged: u64,
+    kernel_drops: Option<u64>,
+    kernel_drop_rate: f64,
+}
+
+impl TopView {
+    fn receive(&mut self, message: StreamMessage) {
+        match message {
+            StreamMessage::Event(event) => self.record(&event),
+            StreamMessage::Lagged(n) => self.lagged += n,
+        }
+    }
+
+    fn record(&mut self, event: &ConnectionEvent) {
+        let stats = self.vips.entry(event.dst_addr).or_default();
+        if let (Some(ns), Some(name)) = (event.namespace.as_deref(), event.service.as_deref()) {
+            stats.service = format!("{ns}/{name}");
+        }
+        stats.total += 1;
+        if event.delivery == "dropped" {
+            stats.dropped += 1;
+        }
+        if event.misrouted {
+            stats.misrouted += 1;
+        }
+        *self.vip_window.entry(event.dst_addr).or_default() += 1;
+
+        let prefix = match event.src_addr {
+            IpAddr::V4(_) => self.prefix_v4,
+            IpAddr::V6(_) => self.prefix_v6,
+        };
+        if let Ok(net) = IpNet::new(event.src_addr, prefix) {
+            *self.prefix_window.entry(net.trunc()).or_default() += 1;
+        }
+        let iface = event
+            .ifname
+            .clone()
+            .unwrap_or_else(|| event.ifindex.to_string());
+        *self.iface_window.entry(iface).or_default() += 1;
+    }
+
+    // turn counts of the window into rates and start a new window
+    fn refresh(&mut self, elapsed: Duration, kernel_drops: Option<u64>) {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        for (addr, stats) in self.vips.iter_mut() {
+            stats.rate = self.vip_window.get(addr).copied().unwrap_or_default() as f64 / secs;
+        }
+        self.total_rate = self.vip_window.values().sum::<u64>() as f64 / secs;
+        self.vip_window.clear();
+
+        self.prefix_rates = self
+            .prefix_window
+            .drain()
+            .map(|(net, n)| (net, n as f64 / secs))
+            .collect();
+        self.prefix_rates
+            .sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        self.prefix_rates.truncate(TOP_PREFIXES);
+
+        self.iface_rates = self
+            .iface_window
+            .drain()
+            .map(|(iface, n)| (iface, n as f64 / secs))
+            .collect();
+        self.iface_rates.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.kernel_drop_rate = match (self.kernel_drops, kernel_drops) {
+            (Some(prev), Some(now)) => now.saturating_sub(prev) as f64 / secs,
+            _ => 0.0,
+        };
+        self.kernel_drops = kernel_drops;
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, body] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(body);
+        let [prefixes, ifaces] =
+            Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(right);
+
+        let kernel_drops = self
+            .kernel_drops
+            .map(|d| format!("{d} ({:.1}/s)", self.kernel_drop_rate))
+            .unwrap_or("n/a".to_string());
+        frame.render_widget(
+            Paragraph::new(format!(
+                "conn/s: {:.1}  kernel drops: {}  stream lagged: {}  (q to quit)",
+                self.total_rate, kernel_drops, self.lagged
+            ))
+            .block(Block::bordered().title("lb-inter-node-exporter top")),
+            header,
+        );
+
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let mut vips: Vec<(&IpAddr, &VipStats)> = self.vips.iter().collect();
+        vips.sort_by(|a, b| b.1.rate.total_cmp(&a.1.rate).then(a.0.cmp(b.0)));
+        let rows = vips.into_iter().map(|(addr, s)| {
+            Row::new(vec![
+                addr.to_string(),
+                s.service.clone(),
+                format!("{:.1}", s.rate),
+                s.total.to_string(),
+                s.dropped.to_string(),
+                s.misrouted.to_string(),
+            ])
+        });
+        frame.render_widget(
+            Table::new(
+                rows,
+                [
+                    Constraint::Length(16),
+                    Constraint::Min(20),
+                    Constraint::Length(8),
+                    Constraint::Length(8),
+                    Constraint::Length(8),
+                    Constraint::Length(9),
+                ],
+            )
+            .header(
+                Row::new(vec![
+                    "VIP",
+                    "SERVICE",
+                    "CONN/S",
+                    "TOTAL",
+                    "DROPPED",
+                    "MISROUTED",
+                ])
+                .style(bold),
+            )
+            .block(Block::bordered().title("VIPs")),
+            left,
+        );
+
+        let rows = self
+            .prefix_rates
+            .iter()
+            .map(|(net, rate)| Row::new(vec![net.to_string(), format!("{rate:.1}")]));
+        frame.render_widget(
+            Table::new(rows, [Constraint::Min(20), Constraint::Length(8)])
+                .header(Row::new(vec!["CLIENT PREFIX", "CONN/S"]).style(bold))
+                .block(Block::bordered().title("Top clients")),
+            prefixes,
+        );
+
+        let rows = self.iface_rates.iter().map(|(iface, rate)| {
+            let share = if self.total_rate > 0.0 {
+                rate / self.total_rate * 100.0
+            } else {
+                0.0
+            };
+            Row::new(vec![
+                iface.clone(),
+                format!("{rate:.1}"),
+                format!("{share:.0}%"),
+            ])
+        });
+        frame.render_widget(
+            Table::new(
+                rows,
+                [
+                    Constraint::Min(12),
+                    Constraint::Length(8),
+                    Constraint::Length(6),
+                ],
+            )
+            .header(Row::new(vec!["INTERFACE", "CONN/S", "SHARE"]).style(bold))
+            .block(Block::bordered().title("Interfaces")),
+            ifaces,
+        );
+    }
+}
+
+/// Run the top view of the agent until the user quits.
+pub async fn run(
+    client: ClientArgs,
+    query: Vec<(&'static str, String)>,
+    prefix_v4: u8,
+    prefix_v6: u8,
+) -> Result<(), anyhow::Error> {
+    let (messages, message_recv) = unbounded_channel();
+    let stream_client = client.clone();
+    let mut stream =
+        tokio::spawn(
+            async move { follow(&stream_client, &query, |m| messages.send(m).is_ok()).await },
+        );
+
+    let mut terminal = ratatui::init();
+    let view = TopView {
+        prefix_v4,
+        prefix_v6,
+        ..Default::default()
+    };
+    let result = run_view(&mut terminal, view, &client, message_recv, &mut stream).await;
+    ratatui::restore();
+    stream.abort();
+    result
+}
+
+async fn run_view(
+    terminal: &mut DefaultTerminal,
+    mut view: TopView,
+    client: &ClientArgs,
+    mut messages: UnboundedReceiver<StreamMessage>,
+    stream: &mut JoinHandle<Result<(), anyhow::Error>>,
+) -> Result<(), anyhow::Error> {
+    let mut input = tokio::time::interval(INPUT_INTERVAL);
+    let mut last_refresh = Instant::now();
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => view.receive(message),
+                // the stream task dropped the sender, report why it ended
+                None => {
+                    stream.await??;
+                    anyhow::bail!("the event stream of the agent is closed");
+                }
+            },
+            _ = input.tick() => {
+                while event::poll(Duration::ZERO)? {
+                    if let Event::Key(key) = event::read()? {
+                        let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL)
+                            && key.code == KeyCode::Char('c');
+                        if key.kind == KeyEventKind::Press
+                            && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || ctrl_c)
+                        {
+                            return Ok(());
+                        }
+                    }
+                }
+                if last_refresh.elapsed() >= REFRESH_INTERVAL {
+                    view.refresh(last_refresh.elapsed(), kernel_drops(client).await);
+                    last_refresh = Instant::now();
+                    terminal.draw(|frame| view.draw(frame))?;
+                }
+            },
+        }
+    }
+}
+
+async fn kernel_drops(client: &ClientArgs) -> Option<u64> {
+    let maps: BTreeMap<String, Vec<MapEntry>> = get(client, "/api/v1/maps", &[]).await.ok()?;
+    Some(maps.get("EVENT_DROPS")?.iter().map(|e| e.value).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(src: &str, dst: &str, ifname: Option<&str>) -> ConnectionEvent {
+        ConnectionEvent {
+            timestamp: 0.0,
+            src_addr: src.parse().unwrap(),
+            src_port: 40000,
+            dst_addr: dst.parse().unwrap(),
+            dst_port: 80,
+            service: Some("web".to_string()),
+            namespace: Some("test".to_string()),
+            port_name: None,
+            source: None,
+            delivery: "local".to_string(),
+            backend_addr: None,
+            backend_port: None,
+            backend_pod: None,
+            backend_node: None,
+            misrouted: false,
+            ifindex: 3,
+            ifname: ifname.map(|name| name.to_string()),
+        }
+    }
+
+    fn view() -> TopView {
+        TopView {
+            prefix_v4: 24,
+            prefix_v6: 64,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vip_rates() {
+        let mut view = view();
+        for _ in 0..4 {
+            view.record(&event("192.168.0.2", "10.0.0.1", Some("eth0")));
+        }
+        let mut dropped = event("192.168.0.3", "10.0.0.2", Some("eth0"));
+        dropped.service = None;
+        dropped.delivery = "dropped".to_string();
+        dropped.misrouted = true;
+        view.record(&dropped);
+        view.refresh(Duration::from_secs(2), None);
+
+        assert_eq!(view.total_rate, 2.5);
+        let vip = &view.vips[&"10.0.0.1".parse::<IpAddr>().unwrap()];
+        assert_eq!(vip.service, "test/web");
+        assert_eq!(
+            (vip.rate, vip.total, vip.dropped, vip.misrouted),
+            (2.0, 4, 0, 0)
+        );
+        let vip = &view.vips[&"10.0.0.2".parse::<IpAddr>().unwrap()];
+        assert_eq!(vip.service, "");
+        assert_eq!(
+            (vip.rate, vip.total, vip.dropped, vip.misrouted),
+            (0.5, 1, 1, 1)
+        );
+
+        // a new window starts on refresh, totals are kept
+        view.record(&event("192.168.0.2", "10.0.0.2", Some("eth0")));
+        view.refresh(Duration::from_secs(1), None);
+        assert_eq!(view.total_rate, 1.0);
+        let vip = &view.vips[&"10.0.0.1".parse::<IpAddr>().unwrap()];
+        assert_eq!((vip.rate, vip.total), (0.0, 4));
+        let vip = &view.vips[&"10.0.0.2".parse::<IpAddr>().unwrap()];
+        assert_eq!((vip.rate, vip.total), (1.0, 2));
+        assert_eq!(vip.service, "test/web");
+    }
+
+    #[test]
+    fn test_prefix_rates() {
+        let mut view = view();
+        for src in [
+            "192.168.0.2",
+            "192.168.0.3",
+            "192.168.1.2",
+            "fd00::1",
+            "fd00::2",
+            "fd00:0:0:1::1",
+        ] {
+            view.record(&event(src, "10.0.0.1", None));
+        }
+        view.record(&event("192.168.0.4", "10.0.0.1", None));
+        view.refresh(Duration::from_secs(1), None);
+
+        let rates: Vec<(String, f64)> = view
+            .prefix_rates
+            .iter()
+            .map(|(net, rate)| (net.to_string(), *rate))
+            .collect();
+        // sorted by rate, then by prefix
+        assert_eq!(
+            rates,
+            [
+                ("192.168.0.0/24".to_string(), 3.0),
+                ("fd00::/64".to_string(), 2.0),
+                ("192.168.1.0/24".to_string(), 1.0),
+                ("fd00:0:0:1::/64".to_string(), 1.0),
+            ]
+        );
+
+        let mut view = self::view();
+        for i in 0..TOP_PREFIXES + 5 {
+            view.record(&event(&format!("10.{i}.0.1"), "10.0.0.1", None));
+        }
+        view.refresh(Duration::from_secs(1), None);
+        assert_eq!(view.prefix_rates.len(), TOP_PREFIXES);
+    }
+
+    #[test]
+    fn test_iface_rates() {
+        let mut view = view();
+        view.record(&event("192.168.0.2", "10.0.0.1", Some("eth1")));
+        view.record(&event("192.168.0.2", "10.0.0.1", Some("eth0")));
+        view.record(&event("192.168.0.2", "10.0.0.1", Some("eth0")));
+        // the interface index without the name
+        view.record(&event("192.168.0.2", "10.0.0.1", None));
+        view.refresh(Duration::from_millis(500), None);
+        assert_eq!(
+            view.iface_rates,
+            [
+                ("3".to_string(), 2.0),
+                ("eth0".to_string(), 4.0),
+                ("eth1".to_string(), 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut view = view();
+        view.receive(StreamMessage::Event(Box::new(event(
+            "192.168.0.2",
+            "10.0.0.1",
+            None,
+        ))));
+        view.receive(StreamMessage::Lagged(3));
+        view.receive(StreamMessage::Lagged(2));
+        assert_eq!(view.lagged, 5);
+        assert_eq!(view.vips.len(), 1);
+        // lagged events are counted through refreshes
+        view.refresh(Duration::from_secs(1), None);
+        assert_eq!(view.lagged, 5);
+        assert_eq!(view.total_rate, 1.0);
+    }
+
+    #[test]
+    fn test_kernel_drops() {
+        let mut view = view();
+        view.refresh(Duration::from_secs(1), Some(10));
+        assert_eq!((view.kernel_drops, view.kernel_drop_rate), (Some(10), 0.0));
+        view.refresh(Duration::from_secs(2), Some(16));
+        assert_eq!((view.kernel_drops, view.kernel_drop_rate), (Some(16), 3.0));
+        // the agent is unreachable
+        view.refresh(Duration::from_secs(1), None);
+        assert_eq!((view.kernel_drops, view.kernel_drop_rate), (None, 0.0));
+        // the counter is reset on restarting the agent
+        view.refresh(Duration::from_secs(1), Some(20));
+        view.refresh(Duration::from_secs(1), Some(5));
+        assert_eq!((view.kernel_drops, view.kernel_drop_rate), (Some(5), 0.0));
+    }
+}
@@ -1,37 +1,170 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, RandomState},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use opentelemetry::trace::SpanBuilder;
-use opentelemetry_otlp::WithExportConfig;
-use prometheus::{opts, IntCounterVec};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Registry};
+use opentelemetry_sdk::trace::Tracer;
+use parking_lot::Mutex;
+use prometheus::{
+    core::Collector, opts, process_collector::ProcessCollector, GaugeVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
 
-pub fn prepare_tracing(level: &str, metrics_endpoint: &str) {
-    let span = SpanBuilder::default().with_kind(opentelemetry::trace::SpanKind::Internal);
+use crate::{error::Error, supervisor::Task, vip::ServiceRef};
 
-    let a = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(metrics_endpoint),
-        )
-        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
-            opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new(
-                "service.name",
-                "lb-inter-node-exporter",
-            )]),
-        ))
-        .install_simple()
-        .unwrap();
+/// LogLevelHandle changes the log level of the running agent.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
 
+/// Validate the log level given by --log-level.
+pub fn parse_log_level(level: &str) -> Result<String, String> {
+    LevelFilter::from_str(level)
+        .map(|_| level.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Install the subscriber logging JSON to stdout and exporting spans with the tracer if any.
+pub fn prepare_tracing(level: &str, tracer: Option<Tracer>) -> LogLevelHandle {
+    let (level_filter, handle) = reload::Layer::new(LevelFilter::from_str(level).unwrap());
     Registry::default()
+        .with(level_filter)
         .with(tracing_subscriber::fmt::Layer::new().with_ansi(true).json())
-        .with(tracing_subscriber::filter::LevelFilter::from_str(level).unwrap())
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
         .init();
+    handle
+}
+
+/// EventLabels is the label set of metrics about a received connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct EventLabels {
+    pub src: String,
+    pub dst: String,
+    pub service: String,
+    pub namespace: String,
+    pub port_name: String,
+    pub source: String,
+    pub delivery: String,
+    pub backend_pod: String,
+    pub backend_node: String,
 }
 
+const PICKED_LABELS: [&str; 9] = [
+    "src",
+    "dst",
+    "service",
+    "namespace",
+    "port_name",
+    "source",
+    "delivery",
+    "backend_pod",
+    "backend_node",
+];
+
+const MISROUTED_LABELS: [&str; 6] = ["src", "dst", "service", "namespace", "port_name", "source"];
+
+// number of locks the series last seen times are split across
+const SERIES_SHARDS: usize = 16;
+
+impl EventLabels {
+    fn picked(&self) -> [&str; 9] {
+        [
+            &self.src,
+            &self.dst,
+            &self.service,
+            &self.namespace,
+            &self.port_name,
+            &self.source,
+            &self.delivery,
+            &self.backend_pod,
+            &self.backend_node,
+        ]
+    }
+
+    fn misrouted(&self) -> [&str; 6] {
+        [
+            &self.src,
+            &self.dst,
+            &self.service,
+            &self.namespace,
+            &self.port_name,
+            &self.source,
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Metrics {
     picked_total: IntCounterVec,
+    misrouted_total: IntCounterVec,
+    expected_local_ratio: GaugeVec,
+    picked_series: Arc<Series>,
+    misrouted_series: Arc<Series>,
+}
+
+/// CounterSample is the value of a counter series.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CounterSample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+}
+
+// label sets with their last seen time keyed by their hash
+type SeriesShard = HashMap<u64, Vec<(EventLabels, Instant)>>;
+
+// Label sets emitted with their last seen time.
+//
+// Label sets are kept by their hash in shards, so that an event clones labels only for a new series
+// and removing series holds one shard at a time.
+#[derive(Debug)]
+struct Series {
+    hasher: RandomState,
+    shards: Vec<Mutex<SeriesShard>>,
+}
+
+impl Series {
+    fn new() -> Self {
+        Series {
+            hasher: RandomState::new(),
+            shards: (0..SERIES_SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn touch(&self, labels: &EventLabels, now: Instant) {
+        let hash = self.hasher.hash_one(labels);
+        let mut shard = self.shards[hash as usize % SERIES_SHARDS].lock();
+        // label sets with the same hash share the entry
+        let entry = shard.entry(hash).or_default();
+        match entry.iter_mut().find(|(l, _)| l == labels) {
+            Some((_, last_seen)) => *last_seen = now,
+            None => entry.push((labels.clone(), now)),
+        }
+    }
+
+    // Forget label sets matching f and return them.
+    fn remove(&self, f: &impl Fn(&EventLabels, Instant) -> bool) -> Vec<EventLabels> {
+        let mut removed = Vec::new();
+        for shard in self.shards.iter() {
+            shard.lock().retain(|_, entry| {
+                entry.retain(|(labels, last_seen)| {
+                    if !f(labels, *last_seen) {
+                        return true;
+                    }
+                    removed.push(labels.clone());
+                    false
+                });
+                !entry.is_empty()
+            });
+        }
+        removed
+    }
 }
 
 impl Default for Metrics {
@@ -41,22 +174,529 @@ impl Default for Metrics {
                 "lb_inter_node_exporter_picked_total",
                 "The count of picked as the intermediate node"
             ),
-            &["src", "dst"],
+            &PICKED_LABELS,
+        )
+        .unwrap();
+        let misrouted_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_misrouted_total",
+                "The count of externalTrafficPolicy=Local connections received by the node without ready local endpoints"
+            ),
+            &MISROUTED_LABELS,
         )
         .unwrap();
 
-        Self { picked_total }
+        let expected_local_ratio = GaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_expected_local_ratio",
+                "The expected ratio of connections served by local endpoints on this node"
+            ),
+            &["namespace", "service"],
+        )
+        .unwrap();
+
+        Self {
+            picked_total,
+            misrouted_total,
+            expected_local_ratio,
+            picked_series: Arc::new(Series::new()),
+            misrouted_series: Arc::new(Series::new()),
+        }
     }
 }
 
 impl Metrics {
     pub fn register(self, registry: &prometheus::Registry) -> Result<Self, prometheus::Error> {
         registry.register(Box::new(self.picked_total.clone()))?;
+        registry.register(Box::new(self.misrouted_total.clone()))?;
+        registry.register(Box::new(self.expected_local_ratio.clone()))?;
         Ok(self)
     }
-    pub fn picked_total(&self, src: IpAddr, dst: IpAddr) {
-        self.picked_total
-            .with_label_values(&[src.to_string().as_str(), dst.to_string().as_str()])
+
+    /// Return current values of the picked and misrouted counters.
+    pub fn counters(&self) -> Vec<CounterSample> {
+        let mut samples = Vec::new();
+        for family in self
+            .picked_total
+            .collect()
+            .into_iter()
+            .chain(self.misrouted_total.collect())
+        {
+            for metric in family.get_metric().iter() {
+                samples.push(CounterSample {
+                    name: family.get_name().to_string(),
+                    labels: metric
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                        .collect(),
+                    value: metric.get_counter().get_value() as u64,
+                });
+            }
+        }
+        samples
+    }
+
+    pub fn picked_total(&self, labels: &EventLabels) {
+        self.picked_total.with_label_values(&labels.picked()).inc();
+        self.picked_series.touch(labels, Instant::now());
+    }
+
+    pub fn misrouted_total(&self, labels: &EventLabels) {
+        self.misrouted_total
+            .with_label_values(&labels.misrouted())
             .inc();
+        self.misrouted_series.touch(labels, Instant::now());
+    }
+
+    /// Remove all series labelled with the client.
+    pub fn remove_src(&self, src: &str) {
+        self.remove_series(|labels, _| labels.src == src);
+    }
+
+    /// Remove all series of the Service on the VIP.
+    pub fn remove_service(&self, dst: &str, svc: &ServiceRef) {
+        self.remove_series(|labels, _| {
+            labels.dst == dst && labels.service == svc.name && labels.namespace == svc.namespace
+        });
+    }
+
+    pub fn remove_expected_local_ratio(&self, svc: &ServiceRef) {
+        let _ = self
+            .expected_local_ratio
+            .remove_label_values(&[svc.namespace.as_str(), svc.name.as_str()]);
+    }
+
+    /// Remove series not seen for the ttl.
+    pub fn gc(&self, ttl: Duration) {
+        let now = Instant::now();
+        self.remove_series(|_, last_seen| now.duration_since(last_seen) >= ttl);
+    }
+
+    fn remove_series(&self, f: impl Fn(&EventLabels, Instant) -> bool) {
+        for labels in self.picked_series.remove(&f).iter() {
+            let _ = self.picked_total.remove_label_values(&labels.picked());
+        }
+        for labels in self.misrouted_series.remove(&f).iter() {
+            let _ = self
+                .misrouted_total
+                .remove_label_values(&labels.misrouted());
+        }
+    }
+
+    pub fn expected_local_ratio(&self, svc: &ServiceRef, ratio: f64) {
+        self.expected_local_ratio
+            .with_label_values(&[svc.namespace.as_str(), svc.name.as_str()])
+            .set(ratio);
+    }
+}
+
+/// SeriesGc removes metric series not seen for the ttl.
+pub struct SeriesGc {
+    metrics: Metrics,
+    ttl: Duration,
+}
+
+impl SeriesGc {
+    pub fn new(metrics: Metrics, ttl: Duration) -> Self {
+        SeriesGc { metrics, ttl }
+    }
+}
+
+impl Task for SeriesGc {
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut ticker = tokio::time::interval(self.ttl.min(Duration::from_secs(60)));
+        loop {
+            ticker.tick().await;
+            self.metrics.gc(self.ttl);
+        }
+    }
+}
+
+/// AgentMetrics is about the agent itself.
+#[derive(Clone)]
+pub struct AgentMetrics {
+    tracked_vips: IntGauge,
+    tracked_vip_ranges: IntGauge,
+    attached_interface: IntGaugeVec,
+    watcher_restarts_total: IntCounterVec,
+    task_restarts_total: IntCounterVec,
+    watcher_last_event_timestamp: GaugeVec,
+    map_operation_failures_total: IntCounterVec,
+    events_processed_total: IntCounter,
+    events_sampled_out_total: IntCounter,
+    config_reloads_total: IntCounterVec,
+    config_restart_required: IntGauge,
+    event_queue_length: IntGauge,
+    event_queue_dropped_events_total: IntCounter,
+    ringbuf_drained_records: IntGauge,
+    ringbuf_dropped_events_total: IntCounter,
+    otlp_log_records_exported_total: IntCounter,
+    otlp_log_records_dropped_total: IntCounterVec,
+    ipfix_records_exported_total: IntCounter,
+    ipfix_records_dropped_total: IntCounterVec,
+    build_info: IntGaugeVec,
+    program_packets_per_second: GaugeVec,
+    program_run_time_per_packet: GaugeVec,
+    program_verified_instructions: IntGaugeVec,
+}
+
+impl Default for AgentMetrics {
+    fn default() -> Self {
+        let tracked_vips = IntGauge::with_opts(opts!(
+            "lb_inter_node_exporter_tracked_vips",
+            "The number of VIPs tracked in the eBPF map"
+        ))
+        .unwrap();
+        let tracked_vip_ranges = IntGauge::with_opts(opts!(
+            "lb_inter_node_exporter_tracked_vip_ranges",
+            "The number of VIP ranges tracked in the eBPF map"
+        ))
+        .unwrap();
+        let attached_interface = IntGaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_attached_interface",
+                "The interface the XDP program is attached to"
+            ),
+            &["ifname", "ifindex", "mode"],
+        )
+        .unwrap();
+        let watcher_restarts_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_watcher_restarts_total",
+                "The count of (re)starts of the Kubernetes watchers"
+            ),
+            &["watcher"],
+        )
+        .unwrap();
+        let task_restarts_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_task_restarts_total",
+                "The count of restarts of the background tasks"
+            ),
+            &["task", "reason"],
+        )
+        .unwrap();
+        let watcher_last_event_timestamp = GaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_watcher_last_event_timestamp_seconds",
+                "The unix time the Kubernetes watcher received the last event"
+            ),
+            &["watcher"],
+        )
+        .unwrap();
+        let map_operation_failures_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_map_operation_failures_total",
+                "The count of failed operations on eBPF maps"
+            ),
+            &["map", "op"],
+        )
+        .unwrap();
+        let events_processed_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_events_processed_total",
+            "The count of events from the eBPF program processed"
+        ))
+        .unwrap();
+        let events_sampled_out_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_events_sampled_out_total",
+            "The count of events from the eBPF program skipped by sampling"
+        ))
+        .unwrap();
+        let config_reloads_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_config_reloads_total",
+                "The count of reloads of the config file by result (success, failure or invalid)"
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let config_restart_required = IntGauge::with_opts(opts!(
+            "lb_inter_node_exporter_config_restart_required",
+            "Whether the config file has changes applied only after a restart"
+        ))
+        .unwrap();
+        let event_queue_length = IntGauge::with_opts(opts!(
+            "lb_inter_node_exporter_event_queue_length",
+            "The number of events waiting to be processed"
+        ))
+        .unwrap();
+        let event_queue_dropped_events_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_event_queue_dropped_events_total",
+            "The count of events dropped because the queue of events waiting to be processed was full"
+        ))
+        .unwrap();
+        let ringbuf_drained_records = IntGauge::with_opts(opts!(
+            "lb_inter_node_exporter_ringbuf_drained_records",
+            "The count of events read from the ring buffer on the last wakeup of the agent, a batch close to the capacity of the ring buffer means the agent falls behind"
+        ))
+        .unwrap();
+        let otlp_log_records_exported_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_otlp_log_records_exported_total",
+            "The count of events exported as OTLP log records"
+        ))
+        .unwrap();
+        let otlp_log_records_dropped_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_otlp_log_records_dropped_total",
+                "The count of events not exported as OTLP log records"
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let ipfix_records_exported_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_ipfix_records_exported_total",
+            "The count of events exported as IPFIX data records"
+        ))
+        .unwrap();
+        let ipfix_records_dropped_total = IntCounterVec::new(
+            opts!(
+                "lb_inter_node_exporter_ipfix_records_dropped_total",
+                "The count of events not exported as IPFIX data records"
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let ringbuf_dropped_events_total = IntCounter::with_opts(opts!(
+            "lb_inter_node_exporter_ringbuf_dropped_events_total",
+            "The count of events the eBPF program dropped because the ring buffer was full"
+        ))
+        .unwrap();
+        let build_info = IntGaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_build_info",
+                "The build information of the agent"
+            ),
+            &["version", "ebpf_object_sha256"],
+        )
+        .unwrap();
+        let program_packets_per_second = GaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_program_packets_per_second",
+                "The rate of packets processed by the eBPF program"
+            ),
+            &["program", "id"],
+        )
+        .unwrap();
+        let program_run_time_per_packet = GaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_program_run_time_per_packet_nanoseconds",
+                "The average run time of the eBPF program per packet"
+            ),
+            &["program", "id"],
+        )
+        .unwrap();
+        let program_verified_instructions = IntGaugeVec::new(
+            opts!(
+                "lb_inter_node_exporter_program_verified_instructions",
+                "The number of instructions of the eBPF program verified at load time"
+            ),
+            &["program", "id"],
+        )
+        .unwrap();
+
+        Self {
+            tracked_vips,
+            tracked_vip_ranges,
+            attached_interface,
+            watcher_restarts_total,
+            task_restarts_total,
+            watcher_last_event_timestamp,
+            map_operation_failures_total,
+            events_processed_total,
+            events_sampled_out_total,
+            config_reloads_total,
+            config_restart_required,
+            event_queue_length,
+            event_queue_dropped_events_total,
+            ringbuf_drained_records,
+            ringbuf_dropped_events_total,
+            otlp_log_records_exported_total,
+            otlp_log_records_dropped_total,
+            ipfix_records_exported_total,
+            ipfix_records_dropped_total,
+            build_info,
+            program_packets_per_second,
+            program_run_time_per_packet,
+            program_verified_instructions,
+        }
+    }
+}
+
+impl AgentMetrics {
+    pub fn register(self, registry: &prometheus::Registry) -> Result<Self, prometheus::Error> {
+        registry.register(Box::new(self.tracked_vips.clone()))?;
+        registry.register(Box::new(self.tracked_vip_ranges.clone()))?;
+        registry.register(Box::new(self.attached_interface.clone()))?;
+        registry.register(Box::new(self.watcher_restarts_total.clone()))?;
+        registry.register(Box::new(self.task_restarts_total.clone()))?;
+        registry.register(Box::new(self.watcher_last_event_timestamp.clone()))?;
+        registry.register(Box::new(self.map_operation_failures_total.clone()))?;
+        registry.register(Box::new(self.events_processed_total.clone()))?;
+        registry.register(Box::new(self.events_sampled_out_total.clone()))?;
+        registry.register(Box::new(self.config_reloads_total.clone()))?;
+        registry.register(Box::new(self.config_restart_required.clone()))?;
+        registry.register(Box::new(self.event_queue_length.clone()))?;
+        registry.register(Box::new(self.event_queue_dropped_events_total.clone()))?;
+        registry.register(Box::new(self.ringbuf_drained_records.clone()))?;
+        registry.register(Box::new(self.ringbuf_dropped_events_total.clone()))?;
+        registry.register(Box::new(self.otlp_log_records_exported_total.clone()))?;
+        registry.register(Box::new(self.otlp_log_records_dropped_total.clone()))?;
+        registry.register(Box::new(self.ipfix_records_exported_total.clone()))?;
+        registry.register(Box::new(self.ipfix_records_dropped_total.clone()))?;
+        registry.register(Box::new(self.build_info.clone()))?;
+        registry.register(Box::new(self.program_packets_per_second.clone()))?;
+        registry.register(Box::new(self.program_run_time_per_packet.clone()))?;
+        registry.register(Box::new(self.program_verified_instructions.clone()))?;
+        registry.register(Box::new(ProcessCollector::for_self()))?;
+        Ok(self)
+    }
+
+    pub fn tracked_vips(&self, n: usize) {
+        self.tracked_vips.set(n as i64);
+    }
+
+    pub fn tracked_vip_ranges(&self, n: usize) {
+        self.tracked_vip_ranges.set(n as i64);
+    }
+
+    pub fn attached_interface(&self, ifname: &str, ifindex: u32, mode: &str) {
+        self.attached_interface
+            .with_label_values(&[ifname, ifindex.to_string().as_str(), mode])
+            .set(1);
+    }
+
+    pub fn watcher_restarted(&self, watcher: &str) {
+        self.watcher_restarts_total
+            .with_label_values(&[watcher])
+            .inc();
+    }
+
+    pub fn task_restarted(&self, task: &str, reason: &str) {
+        self.task_restarts_total
+            .with_label_values(&[task, reason])
+            .inc();
+    }
+
+    pub fn watcher_event(&self, watcher: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.watcher_last_event_timestamp
+            .with_label_values(&[watcher])
+            .set(now.as_secs_f64());
+    }
+
+    pub fn map_operation_failed(&self, map: &str, op: &str) {
+        self.map_operation_failures_total
+            .with_label_values(&[map, op])
+            .inc();
+    }
+
+    pub fn event_processed(&self) {
+        self.events_processed_total.inc();
+    }
+
+    pub fn event_sampled_out(&self) {
+        self.events_sampled_out_total.inc();
+    }
+
+    pub fn config_reloaded(&self, result: &str) {
+        self.config_reloads_total.with_label_values(&[result]).inc();
+    }
+
+    pub fn config_restart_required(&self, required: bool) {
+        self.config_restart_required.set(required as i64);
+    }
+
+    pub fn event_queue_length(&self, n: usize) {
+        self.event_queue_length.set(n as i64);
+    }
+
+    pub fn event_queue_dropped(&self) {
+        self.event_queue_dropped_events_total.inc();
+    }
+
+    pub fn ringbuf_drained(&self, records: usize) {
+        self.ringbuf_drained_records.set(records as i64);
+    }
+
+    pub fn ringbuf_dropped_events(&self, n: u64) {
+        self.ringbuf_dropped_events_total.inc_by(n);
+    }
+
+    pub fn otlp_log_records_exported(&self, n: usize) {
+        self.otlp_log_records_exported_total.inc_by(n as u64);
+    }
+
+    pub fn otlp_log_records_dropped(&self, reason: &str, n: u64) {
+        self.otlp_log_records_dropped_total
+            .with_label_values(&[reason])
+            .inc_by(n);
+    }
+
+    pub fn ipfix_records_exported(&self, n: u32) {
+        self.ipfix_records_exported_total.inc_by(n as u64);
+    }
+
+    pub fn ipfix_records_dropped(&self, reason: &str, n: u64) {
+        self.ipfix_records_dropped_total
+            .with_label_values(&[reason])
+            .inc_by(n);
+    }
+
+    pub fn build_info(&self, ebpf_object: &[u8]) {
+        let hash = Sha256::digest(ebpf_object)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        self.build_info
+            .with_label_values(&[env!("CARGO_PKG_VERSION"), hash.as_str()])
+            .set(1);
+    }
+
+    pub fn program_packets_per_second(&self, program: &str, id: &str, rate: f64) {
+        self.program_packets_per_second
+            .with_label_values(&[program, id])
+            .set(rate);
+    }
+
+    pub fn program_run_time_per_packet(&self, program: &str, id: &str, ns: f64) {
+        self.program_run_time_per_packet
+            .with_label_values(&[program, id])
+            .set(ns);
+    }
+
+    pub fn program_verified_instructions(&self, program: &str, id: &str, n: u32) {
+        self.program_verified_instructions
+            .with_label_values(&[program, id])
+            .set(n as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series() {
+        let series = Series::new();
+        let start = Instant::now();
+        let a = EventLabels {
+            src: "10.0.0.1".to_string(),
+            ..Default::default()
+        };
+        let b = EventLabels {
+            src: "10.0.0.2".to_string(),
+            ..Default::default()
+        };
+        series.touch(&a, start);
+        series.touch(&b, start);
+        series.touch(&a, start + Duration::from_secs(10));
+
+        let removed = series.remove(&|_, last_seen| last_seen < start + Duration::from_secs(5));
+        assert_eq!(removed, vec![b.clone()]);
+        assert!(series.remove(&|labels, _| *labels == b).is_empty());
+        assert_eq!(series.remove(&|_, _| true), vec![a]);
     }
 }
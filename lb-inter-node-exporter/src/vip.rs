@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use aya::maps::{self, MapData};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    error::Error,
+    health::Component,
+    kubernetes::{AddrSource, Lb, TrafficPolicy, VipEvent},
+    supervisor::Task,
+    trace::{AgentMetrics, Metrics},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServiceRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServicePortRef {
+    pub service: ServiceRef,
+    pub port_name: Option<String>,
+    pub source: AddrSource,
+    pub policy: TrafficPolicy,
+}
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_SCTP: u8 = 132;
+
+/// VipTable keeps which Services own each VIP.
+///
+/// Several Services can share one VIP on different ports(e.g. MetalLB's allow-shared-ip),
+/// so a VIP stays tracked until the last Service owning it is deleted.
+#[derive(Debug, Default)]
+pub struct VipTable {
+    owners: HashMap<IpAddr, BTreeSet<ServiceRef>>,
+    // keyed by (VIP, IP protocol number, port) so that TCP and UDP Services on the same port don't collide
+    ports: HashMap<(IpAddr, u8, u16), BTreeSet<ServicePortRef>>,
+}
+
+impl VipTable {
+    /// Add the Service as an owner of its VIP.
+    /// This returns true when the VIP was not tracked before and must be inserted to the eBPF map.
+    pub fn add(&mut self, lb: &Lb) -> bool {
+        let Some(addr) = lb.addr else {
+            return false;
+        };
+        let svc = service_ref(lb);
+        for port in lb.ports.iter() {
+            self.ports
+                .entry((addr, protocol_number(&port.protocol), port.port))
+                .or_default()
+                .insert(ServicePortRef {
+                    service: svc.clone(),
+                    port_name: port.name.clone(),
+                    source: lb.source,
+                    policy: lb.policy,
+                });
+        }
+        let owners = self.owners.entry(addr).or_default();
+        let first = owners.is_empty();
+        owners.insert(svc);
+        first
+    }
+
+    /// Remove the Service from owners of its VIP.
+    /// This returns true when no Service owns the VIP anymore and it must be removed from the eBPF map.
+    pub fn delete(&mut self, lb: &Lb) -> bool {
+        let Some(addr) = lb.addr else {
+            return false;
+        };
+        let svc = service_ref(lb);
+        self.ports.retain(|(a, _, _), refs| {
+            if *a == addr {
+                refs.retain(|r| r.service != svc);
+            }
+            !refs.is_empty()
+        });
+        let Some(owners) = self.owners.get_mut(&addr) else {
+            return false;
+        };
+        if !owners.remove(&svc) {
+            return false;
+        }
+        if owners.is_empty() {
+            self.owners.remove(&addr);
+            return true;
+        }
+        false
+    }
+
+    /// Return tracked VIPs with Services serving each port, ordered by address and port.
+    pub fn entries(&self) -> Vec<(IpAddr, Vec<(u16, ServicePortRef)>)> {
+        let mut entries: Vec<(IpAddr, Vec<(u16, ServicePortRef)>)> = self
+            .owners
+            .keys()
+            .map(|addr| {
+                let mut ports: Vec<(u16, ServicePortRef)> = self
+                    .ports
+                    .iter()
+                    .filter(|((a, _, _), _)| a == addr)
+                    .flat_map(|((_, _, port), refs)| refs.iter().map(|r| (*port, r.clone())))
+                    .collect();
+                ports.sort();
+                (*addr, ports)
+            })
+            .collect();
+        entries.sort_by_key(|(addr, _)| *addr);
+        entries
+    }
+
+    /// The number of tracked IPv4 VIPs, which are in the eBPF map.
+    pub fn ipv4_len(&self) -> usize {
+        self.owners.keys().filter(|addr| addr.is_ipv4()).count()
+    }
+
+    /// Return true if the VIP is tracked.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.owners.contains_key(addr)
+    }
+
+    /// Return true if the Service owns any VIP.
+    pub fn has_service(&self, svc: &ServiceRef) -> bool {
+        self.owners.values().any(|owners| owners.contains(svc))
+    }
+
+    /// Find the Service that serves the given VIP and TCP port.
+    pub fn lookup(&self, addr: IpAddr, port: u16) -> Option<&ServicePortRef> {
+        self.ports
+            .get(&(addr, IPPROTO_TCP, port))
+            .and_then(|refs| refs.first())
+    }
+}
+
+/// VipSync applies VIP events to the VIP table and the eBPF map.
+///
+/// Events are received in batches of a diff. Metric series of a Service deleted and
+/// added again in the same batch, e.g. on a port change, are kept.
+pub struct VipSync {
+    pub events: UnboundedReceiver<Vec<VipEvent>>,
+    pub vips: Arc<RwLock<VipTable>>,
+    pub ipv4_vips: Arc<Mutex<maps::HashMap<MapData, u32, u32>>>,
+    pub metrics: Metrics,
+    pub agent_metrics: AgentMetrics,
+    pub health: Component,
+}
+
+impl Task for VipSync {
+    async fn run(&mut self) -> Result<(), Error> {
+        let _alive = self.health.start();
+        while let Some(events) = self.events.recv().await {
+            self.health.begin();
+            let readded: HashSet<(Option<IpAddr>, ServiceRef)> = events
+                .iter()
+                .filter_map(|event| match event {
+                    VipEvent::Add(lb) => Some((lb.addr, service_ref(lb))),
+                    VipEvent::Delete(_) => None,
+                })
+                .collect();
+            for event in events.into_iter() {
+                match event {
+                    VipEvent::Add(lb) => self.add(lb),
+                    VipEvent::Delete(lb) => {
+                        let keep_series = readded.contains(&(lb.addr, service_ref(&lb)));
+                        self.delete(lb, keep_series)
+                    }
+                }
+            }
+            self.health.done();
+        }
+        Err(Error::ChannelClosed("VIP events".to_string()))
+    }
+}
+
+impl VipSync {
+    fn add(&mut self, lb: Lb) {
+        tracing::info!(
+            name = lb.name,
+            namespace = lb.namespace,
+            vip =? lb.addr,
+            source = lb.source.as_str(),
+            "Add to track VIP"
+        );
+        let mut vips = self.vips.write();
+        let added = vips.add(&lb);
+        self.agent_metrics.tracked_vips(vips.ipv4_len());
+        drop(vips);
+        if !added {
+            // this VIP is already tracked by another Service
+            return;
+        }
+        match lb.addr {
+            Some(IpAddr::V4(addr)) => {
+                let addr_num: u32 = u32::from(addr);
+                if let Err(e) = self.ipv4_vips.lock().insert(addr_num, 0, 0) {
+                    tracing::error!(vip=?addr, error=?e, "Failed to insert the VIP");
+                    self.agent_metrics.map_operation_failed("IPV4VIP", "insert");
+                }
+            }
+            Some(IpAddr::V6(_addr)) => {
+                // not implemented
+            }
+            None => {}
+        }
+    }
+
+    fn delete(&mut self, lb: Lb, keep_series: bool) {
+        tracing::info!(
+            name = lb.name,
+            namespace = lb.namespace,
+            vip =? lb.addr,
+            source = lb.source.as_str(),
+            "Delete the tracking VIP"
+        );
+        let svc = service_ref(&lb);
+        if let Some(addr) = lb.addr.filter(|_| !keep_series) {
+            self.metrics.remove_service(&addr.to_string(), &svc);
+        }
+        let mut vips = self.vips.write();
+        let removed = vips.delete(&lb);
+        if !vips.has_service(&svc) && !keep_series {
+            self.metrics.remove_expected_local_ratio(&svc);
+        }
+        self.agent_metrics.tracked_vips(vips.ipv4_len());
+        drop(vips);
+        if !removed {
+            // this VIP is still owned by other Services
+            return;
+        }
+        match lb.addr {
+            Some(IpAddr::V4(addr)) => {
+                let addr_num: u32 = u32::from(addr);
+                if let Err(e) = self.ipv4_vips.lock().remove(&addr_num) {
+                    tracing::error!(vip=?addr, error=?e, "Failed to remove the VIP");
+                    self.agent_metrics.map_operation_failed("IPV4VIP", "remove");
+                }
+            }
+            Some(IpAddr::V6(_addr)) => {
+                // not implemented
+            }
+            None => {}
+        }
+    }
+}
+
+// Service ports default to TCP when the protocol is not set.
+fn protocol_number(protocol: &str) -> u8 {
+    match protocol {
+        "UDP" => IPPROTO_UDP,
+        "SCTP" => IPPROTO_SCTP,
+        _ => IPPROTO_TCP,
+    }
+}
+
+pub fn service_ref(lb: &Lb) -> ServiceRef {
+    ServiceRef {
+        namespace: lb.namespace.clone(),
+        name: lb.name.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kubernetes::LbPort;
+
+    use super::*;
+
+    fn lb(name: &str, addr: &str, port: u16, protocol: &str) -> Lb {
+        Lb {
+            name: name.to_string(),
+            namespace: "test".to_string(),
+            addr: Some(addr.parse().unwrap()),
+            source: AddrSource::Ingress,
+            policy: TrafficPolicy::Cluster,
+            ports: vec![LbPort {
+                name: Some(name.to_string()),
+                port,
+                protocol: protocol.to_string(),
+            }],
+        }
+    }
+
+    fn lookup_name(vips: &VipTable, addr: &str, port: u16) -> Option<String> {
+        vips.lookup(addr.parse().unwrap(), port)
+            .map(|r| r.service.name.clone())
+    }
+
+    #[test]
+    fn test_vip_table_shared_vip() {
+        let mut vips = VipTable::default();
+        let http = lb("http", "10.0.10.0", 80, "TCP");
+        let https = lb("https", "10.0.10.0", 443, "TCP");
+
+        assert!(vips.add(&http));
+        assert!(!vips.add(&https));
+        assert_eq!(vips.entries().len(), 1);
+        assert_eq!(
+            lookup_name(&vips, "10.0.10.0", 80),
+            Some("http".to_string())
+        );
+        assert_eq!(
+            lookup_name(&vips, "10.0.10.0", 443),
+            Some("https".to_string())
+        );
+
+        // the VIP stays tracked while another Service owns it
+        assert!(!vips.delete(&http));
+        assert_eq!(vips.entries().len(), 1);
+        assert_eq!(lookup_name(&vips, "10.0.10.0", 80), None);
+        assert_eq!(
+            lookup_name(&vips, "10.0.10.0", 443),
+            Some("https".to_string())
+        );
+        assert!(!vips.delete(&http));
+
+        assert!(vips.delete(&https));
+        assert_eq!(vips.entries().len(), 0);
+        assert_eq!(lookup_name(&vips, "10.0.10.0", 443), None);
+
+        // re-adding makes the VIP tracked again
+        assert!(vips.add(&https));
+        assert_eq!(vips.entries().len(), 1);
+        assert_eq!(
+            lookup_name(&vips, "10.0.10.0", 443),
+            Some("https".to_string())
+        );
+    }
+
+    #[test]
+    fn test_vip_table_protocol() {
+        let mut vips = VipTable::default();
+        let dns_udp = lb("dns-udp", "10.0.10.0", 53, "UDP");
+        let dns_tcp = lb("dns-tcp", "10.0.10.0", 53, "TCP");
+
+        assert!(vips.add(&dns_udp));
+        assert_eq!(lookup_name(&vips, "10.0.10.0", 53), None);
+        assert!(!vips.add(&dns_tcp));
+        assert_eq!(
+            lookup_name(&vips, "10.0.10.0", 53),
+            Some("dns-tcp".to_string())
+        );
+        assert_eq!(vips.entries()[0].1.len(), 2);
+
+        assert!(!vips.delete(&dns_tcp));
+        assert_eq!(lookup_name(&vips, "10.0.10.0", 53), None);
+    }
+}